@@ -3,7 +3,14 @@ use std::{fs::File, path::PathBuf, str::FromStr, sync::Arc};
 use rustyline::{DefaultEditor, error::ReadlineError};
 use zip::ZipArchive;
 
-use crate::{logger, plugin::loader::PluginLoader, server::Server};
+use crate::{
+    logger,
+    plugin::loader::PluginLoader,
+    requests::{channel, member, overwrite, role},
+    server::Server,
+    types::data::{ChannelKind, Overwrite, OverwriteTarget},
+    utils::{auth, permissions::Permissions},
+};
 
 logger!(LOGGER "CLI");
 
@@ -79,7 +86,64 @@ pub fn require_args(args: &[String], required: &[&str]) -> bool {
     true
 }
 
+/// Logs the valid names when `arg` isn't a permission list
+pub fn parse_permissions(arg: &str) -> Option<Permissions> {
+    let permissions = Permissions::parse(arg);
+    if permissions.is_none() {
+        let names: Vec<&str> = Permissions::NAMES.iter().map(|(n, _)| *n).collect();
+        LOGGER.error(format!(
+            "Permissions are a comma separated list of: none, {}",
+            names.join(", ")
+        ));
+    }
+
+    permissions
+}
+
+/// Parses the `type` of a channel kind, `iframe:<url>` for iframes
+pub fn parse_kind(arg: &str) -> Option<ChannelKind> {
+    let kind = match arg.split_once(':') {
+        Some(("iframe", url)) => Some(ChannelKind::IFrame(url.to_string())),
+        _ => ChannelKind::from_name(arg, None),
+    };
+    if kind.is_none() {
+        LOGGER.error(
+            "Channel kinds are text, voice, category, announcement, forum, stage or iframe:<url>",
+        );
+    }
+
+    kind
+}
+
+/// Parses `role:<name>` or `user:<user-id>`
+pub fn parse_target(server: &Arc<Server>, arg: &str) -> Option<OverwriteTarget> {
+    let target = match arg.split_once(':') {
+        Some(("role", name)) => role::by_name(server, name).map(|r| OverwriteTarget::Role(r.id)),
+        Some(("user", user_id)) => Ok(OverwriteTarget::User(user_id.to_string())),
+        _ => Err(anyhow::anyhow!("Targets are role:<name> or user:<user-id>")),
+    };
+
+    LOGGER.extract(target, "Invalid target")
+}
+
+/// Asks for a password without echoing it, or reads the next line when input isn't a terminal.
+/// Passwords aren't taken as arguments so they stay out of the history
+fn read_password(rl: &mut DefaultEditor) -> Option<String> {
+    let password = match rpassword::prompt_password("Password: ") {
+        Ok(password) => password,
+        Err(_) => rl.readline("Password: ").ok()?,
+    };
+    if password.is_empty() {
+        LOGGER.error("Password can't be empty");
+        return None;
+    }
+
+    Some(password)
+}
+
+/// Runs on its own thread since reading the terminal blocks, async work goes through `runtime`
 pub fn start_cli(server: Arc<Server>, plugin_loader: PluginLoader) {
+    let runtime = tokio::runtime::Handle::current();
     std::thread::spawn(move || {
         let mut rl = DefaultEditor::new().unwrap();
 
@@ -123,10 +187,11 @@ pub fn start_cli(server: Arc<Server>, plugin_loader: PluginLoader) {
                 }
                 "load" "loads a plugin that has been installed" => {
                     if require_args(&args, &["<plugin-id>"]) {
-                        let mut p = plugin_loader.load(&server.root.join("plugins").join(&args[1]));
-                        let s = server.clone();
-                        server.plugins.lock().unwrap().push(p.clone());
-                        std::thread::spawn(move || p.run(&s));
+                        let path = server.root.join("plugins").join(&args[1]);
+                        LOGGER.extract(
+                            runtime.block_on(plugin_loader.start(&server, &path)),
+                            "Couldn't load plugin",
+                        );
                     }
                 }
                 "stop" "stops a plugin that has been installed" => {
@@ -171,10 +236,11 @@ pub fn start_cli(server: Arc<Server>, plugin_loader: PluginLoader) {
                             server.plugins.lock().unwrap().remove(i);
                         }
 
-                        let mut p = plugin_loader.load(&server.root.join("plugins").join(&args[1]));
-                        let s = server.clone();
-                        server.plugins.lock().unwrap().push(p.clone());
-                        std::thread::spawn(move || p.run(&s));
+                        let path = server.root.join("plugins").join(&args[1]);
+                        LOGGER.extract(
+                            runtime.block_on(plugin_loader.start(&server, &path)),
+                            "Couldn't load plugin",
+                        );
                     }
                 }
                 "reload-all" "reloads all of the plugins" => {
@@ -189,10 +255,228 @@ pub fn start_cli(server: Arc<Server>, plugin_loader: PluginLoader) {
                     server.plugins.lock().unwrap().clear();
                     plugin_loader.clear();
 
-                    plugin_loader.load_all(&server);
+                    runtime.block_on(plugin_loader.load_all(&server));
+                }
+                "user-add" "Creates a local account, asking for its password" => {
+                    if require_args(&args, &["<username>"]) && let Some(password) = read_password(&mut rl) {
+                        match auth::create_user(&server.db, &args[1], &password) {
+                            Ok(user_id) => LOGGER.info(format!("Created user '{}' ({user_id})", args[1])),
+                            Err(e) => LOGGER.error(e.context("Couldn't create user")),
+                        }
+                    }
+                }
+                "user-passwd" "Asks for a new password for a local account and logs it out everywhere" => {
+                    if require_args(&args, &["<username>"]) && let Some(password) = read_password(&mut rl) {
+                        match auth::set_password(&server.db, &args[1], &password) {
+                            Ok(()) => LOGGER.info(format!("Changed password of '{}'", args[1])),
+                            Err(e) => LOGGER.error(e.context("Couldn't change password")),
+                        }
+                    }
+                }
+                "user-remove" "Deletes a local account" => {
+                    if require_args(&args, &["<username>"]) {
+                        match auth::delete_user(&server.db, &args[1]) {
+                            Ok(()) => LOGGER.info(format!("Deleted user '{}'", args[1])),
+                            Err(e) => LOGGER.error(e.context("Couldn't delete user")),
+                        }
+                    }
+                }
+                "tls-reload" "Reloads the TLS certificate and key from disk" => {
+                    match &server.tls {
+                        Some(tls) => match tls.reload() {
+                            Ok(()) => LOGGER.info("Reloaded certificate"),
+                            Err(e) => LOGGER.error(e.context("Couldn't reload certificate")),
+                        },
+                        None => LOGGER.error("TLS is not enabled"),
+                    }
+                }
+                "member-remove" "Removes a member and disconnects them until they rejoin" => {
+                    if require_args(&args, &["<user-id>"]) {
+                        match member::remove(&server, &args[1]) {
+                            Ok(()) => LOGGER.info(format!("Removed member {}", args[1])),
+                            Err(e) => LOGGER.error(e.context("Couldn't remove member")),
+                        }
+                    }
+                }
+                "member-ban" "Removes a member and keeps them from connecting again" => {
+                    if require_args(&args, &["<user-id>"])
+                        && let Err(e) = member::ban(&server, None, &args[1])
+                    {
+                        LOGGER.error(e.context("Couldn't ban user"));
+                    }
+                }
+                "member-unban" "Lets a banned user connect again" => {
+                    if require_args(&args, &["<user-id>"])
+                        && let Err(e) = member::unban(&server, &args[1])
+                    {
+                        LOGGER.error(e.context("Couldn't unban user"));
+                    }
+                }
+                "roles" "Lists every role and its permissions" => {
+                    match server.db.get_roles() {
+                        Ok(roles) => {
+                            for r in roles {
+                                println!("{} ({}): {}", r.name, r.id, r.permissions);
+                            }
+                        }
+                        Err(e) => LOGGER.error(format!("Couldn't list roles: {e}")),
+                    }
+                }
+                "role-create" "Creates a role with a comma separated list of permissions" => {
+                    if require_args(&args, &["<name>", "<permissions>"])
+                        && let Some(permissions) = parse_permissions(&args[2])
+                    {
+                        match role::create(&server, &args[1], permissions) {
+                            Ok(r) => LOGGER.info(format!("Created role '{}' ({})", r.name, r.id)),
+                            Err(e) => LOGGER.error(e.context("Couldn't create role")),
+                        }
+                    }
+                }
+                "role-set" "Replaces the permissions of a role" => {
+                    if require_args(&args, &["<name>", "<permissions>"])
+                        && let Some(permissions) = parse_permissions(&args[2])
+                    {
+                        match role::set_permissions(&server, &args[1], permissions) {
+                            Ok(()) => LOGGER.info(format!("Set permissions of '{}' to {permissions}", args[1])),
+                            Err(e) => LOGGER.error(e.context("Couldn't set permissions")),
+                        }
+                    }
+                }
+                "role-delete" "Deletes a role and unassigns it from everyone" => {
+                    if require_args(&args, &["<name>"]) {
+                        match role::delete(&server, &args[1]) {
+                            Ok(()) => LOGGER.info(format!("Deleted role '{}'", args[1])),
+                            Err(e) => LOGGER.error(e.context("Couldn't delete role")),
+                        }
+                    }
+                }
+                "role-assign" "Gives a user or plugin a role" => {
+                    if require_args(&args, &["<user-id>", "<role>"]) {
+                        let assigned = role::by_name(&server, &args[2])
+                            .and_then(|r| role::assign(&server, None, &args[1], r.id, true));
+                        if let Err(e) = assigned {
+                            LOGGER.error(e.context("Couldn't assign role"));
+                        }
+                    }
+                }
+                "role-unassign" "Takes a role from a user or plugin" => {
+                    if require_args(&args, &["<user-id>", "<role>"]) {
+                        let unassigned = role::by_name(&server, &args[2])
+                            .and_then(|r| role::assign(&server, None, &args[1], r.id, false));
+                        if let Err(e) = unassigned {
+                            LOGGER.error(e.context("Couldn't unassign role"));
+                        }
+                    }
+                }
+                "channels" "Lists every channel in order" => {
+                    for c in server.channels.lock().unwrap().iter() {
+                        println!(
+                            "{} ({}): {:?}, parent {:?}, topic {:?}, nsfw {}, slow mode {}s",
+                            c.name, c.id, c.kind, c.parent, c.topic, c.nsfw, c.slow_mode_secs
+                        );
+                    }
+                }
+                "channel-create" "Creates a channel, kinds are text, voice, category, announcement, forum, stage or iframe:<url>" => {
+                    if require_args(&args, &["<name>", "<kind>"])
+                        && let Some(kind) = parse_kind(&args[2])
+                        && let Err(e) = channel::create(&server, &args[1], kind)
+                    {
+                        LOGGER.error(e.context("Couldn't create channel"));
+                    }
+                }
+                "channel-rename" "Renames a channel" => {
+                    if require_args(&args, &["<channel-id>", "<name>"])
+                        && let Err(e) = channel::rename(&server, &args[1], &args[2])
+                    {
+                        LOGGER.error(e.context("Couldn't rename channel"));
+                    }
+                }
+                "channel-move" "Moves a channel to a position in the list, starting at 0" => {
+                    if require_args(&args, &["<channel-id>", "<position>"]) {
+                        match args[2].parse() {
+                            Ok(position) => {
+                                if let Err(e) = channel::move_to(&server, &args[1], position) {
+                                    LOGGER.error(e.context("Couldn't move channel"));
+                                }
+                            }
+                            Err(_) => LOGGER.error("Position must be a number"),
+                        }
+                    }
+                }
+                "channel-topic" "Sets the topic of a channel, clears it when left out" => {
+                    if require_args(&args, &["<channel-id>"])
+                        && let Err(e) = channel::set_topic(&server, &args[1], args.get(2).cloned())
+                    {
+                        LOGGER.error(e.context("Couldn't set topic"));
+                    }
+                }
+                "channel-parent" "Puts a channel in a category, takes it out of its category when left out" => {
+                    if require_args(&args, &["<channel-id>"])
+                        && let Err(e) = channel::set_parent(&server, &args[1], args.get(2).cloned())
+                    {
+                        LOGGER.error(e.context("Couldn't set category"));
+                    }
+                }
+                "channel-nsfw" "Marks a channel as NSFW or not" => {
+                    if require_args(&args, &["<channel-id>", "<true|false>"]) {
+                        match args[2].parse() {
+                            Ok(nsfw) => {
+                                if let Err(e) = channel::set_nsfw(&server, &args[1], nsfw) {
+                                    LOGGER.error(e.context("Couldn't set NSFW"));
+                                }
+                            }
+                            Err(_) => LOGGER.error("Expected true or false"),
+                        }
+                    }
+                }
+                "channel-slowmode" "Sets the seconds members wait between messages in a channel, 0 turns it off" => {
+                    if require_args(&args, &["<channel-id>", "<seconds>"]) {
+                        match args[2].parse() {
+                            Ok(seconds) => {
+                                if let Err(e) = channel::set_slow_mode(&server, &args[1], seconds) {
+                                    LOGGER.error(e.context("Couldn't set slow mode"));
+                                }
+                            }
+                            Err(_) => LOGGER.error("Seconds must be a number"),
+                        }
+                    }
+                }
+                "channel-delete" "Deletes a channel and all of its messages" => {
+                    if require_args(&args, &["<channel-id>"])
+                        && let Err(e) = channel::delete(&server, &args[1])
+                    {
+                        LOGGER.error(e.context("Couldn't delete channel"));
+                    }
+                }
+                "overwrites" "Lists the permission overwrites of a channel" => {
+                    if require_args(&args, &["<channel-id>"]) {
+                        for o in overwrite::of(&server, &args[1]) {
+                            println!("{:?}: allow {}, deny {}", o.target, o.allow, o.deny);
+                        }
+                    }
+                }
+                "overwrite-set" "Allows and denies permissions for a role:<name> or user:<user-id> in a channel" => {
+                    if require_args(&args, &["<channel-id>", "<target>", "<allow>", "<deny>"])
+                        && let Some(target) = parse_target(&server, &args[2])
+                        && let Some(allow) = parse_permissions(&args[3])
+                        && let Some(deny) = parse_permissions(&args[4])
+                    {
+                        let overwrite = Overwrite { target, allow, deny };
+                        if let Err(e) = overwrite::set(&server, None, &args[1], overwrite) {
+                            LOGGER.error(e.context("Couldn't set overwrite"));
+                        }
+                    }
+                }
+                "overwrite-remove" "Removes the overwrite of a role:<name> or user:<user-id> in a channel" => {
+                    if require_args(&args, &["<channel-id>", "<target>"])
+                        && let Some(target) = parse_target(&server, &args[2])
+                        && let Err(e) = overwrite::delete(&server, None, &args[1], &target)
+                    {
+                        LOGGER.error(e.context("Couldn't remove overwrite"));
+                    }
                 }
                 "shutdown" "Softly shuts the server down, may not fully shut everything down" => {
-                    server.shutdown();
+                    runtime.block_on(server.shutdown());
                     break;
                 }
 
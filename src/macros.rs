@@ -2,13 +2,13 @@
 macro_rules! logger {
     (const $i:ident $name:expr) => {
         #[allow(unused)]
-        pub const $i: $crate::once_cell::sync::Lazy<$crate::utils::logger::Logger> =
+        pub static $i: $crate::once_cell::sync::Lazy<$crate::utils::logger::Logger> =
             $crate::once_cell::sync::Lazy::new(|| $crate::utils::logger::Logger::new($name));
     };
 
     ($i:ident $name:expr) => {
         #[allow(unused)]
-        pub const $i: $crate::once_cell::sync::Lazy<$crate::utils::logger::Logger> =
+        pub static $i: $crate::once_cell::sync::Lazy<$crate::utils::logger::Logger> =
             $crate::once_cell::sync::Lazy::new(|| $crate::utils::logger::Logger::new($name));
     };
 
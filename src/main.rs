@@ -16,7 +16,8 @@ pub use anyhow::Context as ErrorContext;
 pub use anyhow::Result;
 pub use once_cell;
 
-fn main() -> Result<()> {
+#[tokio::main]
+async fn main() -> Result<()> {
     let root = PathBuf::from("");
     let config: ServerConfig = if let Ok(env_config) = std::env::var("VX_CONFIG") {
         ServerConfig::from_str(&env_config)?
@@ -24,16 +25,17 @@ fn main() -> Result<()> {
         vfs::read_config(&root.join("config.json"))?
     };
 
-    if let Ok(a) = std::env::var("AXIOM_NODE") {
-        if a == "true" {
-            config
-                .build_req(&root, crate::server::Server::call_node_request)
-                .run()?;
+    if let Ok(a) = std::env::var("AXIOM_NODE")
+        && a == "true"
+    {
+        config
+            .build_req(&root, crate::server::Server::call_node_request)
+            .run()
+            .await?;
 
-            return Ok(());
-        }
+        return Ok(());
     }
 
-    config.build(&root).run()?;
+    config.build(&root).run().await?;
     Ok(())
 }
@@ -1,4 +1,8 @@
-use crate::{server::Server, types::message::ServerMessage, utils::client::Client};
+use crate::{
+    server::Server,
+    types::message::ServerMessage,
+    utils::{client::Client, database::Page},
+};
 use std::sync::Arc;
 
 crate::logger!(LOGGER "Chunk Loader");
@@ -9,10 +13,25 @@ pub fn load_chunk(
     channel_id: &str,
     chunk_id: usize,
 ) -> crate::Result<()> {
-    let mut chunk = server
+    let chunk = server
         .db
         .get_chunk_node(&client.get_uuid()?, channel_id, chunk_id)?;
-    chunk.reverse();
     client.send(ServerMessage::Chunk(chunk))?;
     Ok(())
 }
+
+pub fn load_messages(
+    server: &Arc<Server>,
+    client: &Client,
+    channel_id: &str,
+    page: Page,
+    limit: usize,
+) -> crate::Result<()> {
+    let messages = server
+        .db
+        .get_messages_node(&client.get_uuid()?, channel_id, page, limit)?;
+    client.send(ServerMessage::Messages {
+        channel_id: channel_id.to_string(),
+        messages,
+    })
+}
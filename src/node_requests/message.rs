@@ -1,8 +1,11 @@
 use std::sync::Arc;
 
-use anyhow::anyhow;
-
-use crate::{server::Server, types, utils::client::Client};
+use crate::{
+    requests::{error::RequestError, upload},
+    server::Server,
+    types,
+    utils::client::Client,
+};
 
 crate::logger!(LOGGER "Message Manager");
 
@@ -11,30 +14,54 @@ pub fn send(
     client: &Client,
     channel_id: &str,
     contents: &str,
-) -> crate::Result<()> {
+    reply_to: Option<i64>,
+    attachments: &[i64],
+) -> crate::Result<types::data::Message> {
     LOGGER.info(format!("SendMessage to {channel_id}: {contents}"));
 
-    if contents.is_empty() {
-        client.send(types::message::ResponseError::InvalidRequest(format!(
-            "Invalid message: empty message"
-        )))?;
+    if contents.is_empty() && attachments.is_empty() {
+        return Err(
+            RequestError::InvalidRequest("Invalid message: empty message".to_string()).into(),
+        );
+    }
 
-        return Ok(());
+    let user_id = client.get_uuid()?;
+    if let Some(reply_to) = reply_to {
+        let Some(replied) = server.db.get_message_by_id(reply_to)? else {
+            return Err(
+                RequestError::NotFound("Replied message does not exist".to_string()).into(),
+            );
+        };
+        let between = [replied.channel_id.as_str(), replied.from.as_str()];
+        if !between.contains(&channel_id) || !between.contains(&user_id.as_str()) {
+            return Err(RequestError::InvalidRequest(
+                "Replies have to be in the same conversation".to_string(),
+            )
+            .into());
+        }
     }
 
-    let msg = server.db.insert_message(
-        &channel_id,
-        &client.get_uuid()?,
-        &contents,
+    let attachments = upload::attachments(server, &user_id, attachments)?;
+
+    let mut msg = server.db.insert_message(
+        channel_id,
+        &user_id,
+        contents,
         chrono::Utc::now().timestamp(),
+        reply_to,
+        None,
     )?;
+    for attachment in &attachments {
+        server.db.attach(msg.id, attachment.id)?;
+    }
+    msg.attachments = attachments;
 
     server.broadcast_to(
         &[&msg.channel_id, &msg.from],
         types::message::ServerMessage::MessageCreate(msg.clone()),
     )?;
 
-    Ok(())
+    Ok(msg)
 }
 
 pub fn edit(
@@ -45,11 +72,14 @@ pub fn edit(
 ) -> crate::Result<()> {
     LOGGER.info(format!("EditMessage {message_id}: {new_contents}"));
     let Some(msg) = server.db.get_message_by_id(message_id)? else {
-        return Err(anyhow!("Message does not exist"));
+        return Err(RequestError::NotFound("Message does not exist".to_string()).into());
     };
 
     if msg.from != client.get_uuid()? {
-        return Err(anyhow!("You are not the author of this message"));
+        return Err(RequestError::Unauthorized(
+            "You are not the author of this message".to_string(),
+        )
+        .into());
     }
 
     server.db.edit_message(message_id, new_contents)?;
@@ -65,18 +95,25 @@ pub fn edit(
 pub fn delete(server: &Arc<Server>, client: &Client, message_id: i64) -> crate::Result<()> {
     LOGGER.info(format!("DeleteMessage {message_id}"));
     let Some(msg) = server.db.get_message_by_id(message_id)? else {
-        return Err(anyhow!("Message does not exist"));
+        return Err(RequestError::NotFound("Message does not exist".to_string()).into());
     };
 
     if msg.from != client.get_uuid()? {
-        return Err(anyhow!("You are not the author of this message"));
+        return Err(RequestError::Unauthorized(
+            "You are not the author of this message".to_string(),
+        )
+        .into());
     }
 
-    server.db.delete_message(message_id)?;
+    let hashes = server.db.delete_message(message_id)?;
+    upload::remove_unused(server, &hashes);
 
     server.broadcast_to(
         &[&msg.channel_id, &msg.from],
-        types::message::ServerMessage::MessageDelete { message_id },
+        types::message::ServerMessage::MessageDelete {
+            message_id,
+            channel_id: msg.channel_id.clone(),
+        },
     )?;
 
     Ok(())
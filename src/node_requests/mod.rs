@@ -1,14 +1,17 @@
 pub mod chunk;
 pub mod indicator;
 pub mod message;
+pub mod reaction;
+pub mod search;
 pub mod voice;
 
 use std::sync::Arc;
 
 use crate::{
+    requests::{error::RequestError, role, upload},
     server::Server,
-    types::message::{ClientMessage, ServerMessage, WsMessage},
-    utils::client::Client,
+    types::message::{ClientMessage, Event, ServerMessage, WsMessage},
+    utils::{client::Client, database::SearchFilter, encoding::Encoded},
 };
 
 impl Server {
@@ -16,14 +19,28 @@ impl Server {
         self: &Arc<Self>,
         req: &WsMessage<ClientMessage>,
         client: &Client,
-    ) -> crate::Result<()> {
+    ) -> crate::Result<Option<i64>> {
+        let (required, channel_id) = role::required(req);
+        self.require(client, channel_id, required)?;
+
         match req {
             WsMessage::Message(req) => match req {
                 ClientMessage::SendMessage {
                     channel_id,
                     contents,
+                    reply_to,
+                    thread_id,
+                    attachments,
                 } => {
-                    message::send(self, client, channel_id, contents)?;
+                    if thread_id.is_some() {
+                        return Err(RequestError::InvalidRequest(
+                            "Direct messages have no threads".to_string(),
+                        )
+                        .into());
+                    }
+                    let msg =
+                        message::send(self, client, channel_id, contents, *reply_to, attachments)?;
+                    return Ok(Some(msg.id));
                 }
 
                 ClientMessage::EditMessage {
@@ -39,6 +56,51 @@ impl Server {
                     chunk_id,
                     channel_id,
                 } => chunk::load_chunk(self, client, channel_id, *chunk_id)?,
+                ClientMessage::LoadMessages {
+                    channel_id,
+                    before,
+                    after,
+                    around,
+                    limit,
+                } => {
+                    let page = crate::requests::chunk::page(*before, *after, *around, *limit)?;
+                    chunk::load_messages(self, client, channel_id, page, *limit)?
+                }
+
+                ClientMessage::CreateThread { .. }
+                | ClientMessage::LoadThreadChunk { .. }
+                | ClientMessage::ListThreads { .. } => {
+                    return Err(RequestError::InvalidRequest(
+                        "Direct messages have no threads".to_string(),
+                    )
+                    .into());
+                }
+
+                ClientMessage::Search {
+                    query,
+                    channel_id,
+                    from,
+                    since,
+                    until,
+                    before,
+                    limit,
+                } => {
+                    let filter = SearchFilter {
+                        from: from.as_deref(),
+                        since: *since,
+                        until: *until,
+                        before: *before,
+                        limit: *limit,
+                    };
+                    search::search(self, client, query, channel_id.as_deref(), &filter)?
+                }
+
+                ClientMessage::AddReaction { message_id, emoji } => {
+                    reaction::react(self, client, *message_id, emoji, true)?
+                }
+                ClientMessage::RemoveReaction { message_id, emoji } => {
+                    reaction::react(self, client, *message_id, emoji, false)?
+                }
 
                 ClientMessage::Typing { channel_id } => {
                     indicator::start_typing(self, client, channel_id)?
@@ -46,19 +108,23 @@ impl Server {
 
                 ClientMessage::JoinVoice { channel_id } => voice::join(self, client, channel_id)?,
                 ClientMessage::LeaveVoice { channel_id } => voice::leave(self, client, channel_id)?,
+
+                other => self.call_shared_request(other, client)?,
             },
 
-            WsMessage::Binary(data) => {
-                // Self::LOGGER.info(format!("Binary message: {data:?}"));
-                voice::voice(self, client, data)?;
-            }
+            WsMessage::Binary(data) => match upload::json_chunk(self, client, data) {
+                Some(chunk) => upload::chunk(self, client, chunk)?,
+                None => voice::voice(self, client, data)?,
+            },
+
+            WsMessage::Upload(chunk) => upload::chunk(self, client, chunk)?,
 
-            WsMessage::String(s) => {
-                Self::LOGGER.info(format!("String message: {s}"));
+            WsMessage::Malformed(reason) => {
+                return Err(RequestError::InvalidRequest(reason.clone()).into());
             }
         }
 
-        Ok(())
+        Ok(None)
     }
 
     pub fn broadcast_to(
@@ -66,19 +132,28 @@ impl Server {
         targets: &[&String],
         message: ServerMessage,
     ) -> crate::Result<()> {
-        for c in self.clients.lock().unwrap().iter() {
-            if !targets.contains(&&c.get_uuid()?) {
-                continue;
-            }
+        let message = Arc::new(message);
+        let (seq, clients) = self.sessions.record(
+            &message,
+            |user_id| targets.iter().any(|t| *t == user_id),
+            || {
+                self.clients
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|c| c.get_uuid().is_ok_and(|uuid| targets.contains(&&uuid)))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            },
+        );
+        let event = Event {
+            message: &message,
+            seq,
+        };
+        let encoded = Encoded::new(&event);
 
-            let c = c.clone();
-            let server = self.clone();
-            let message = message.clone();
-            std::thread::spawn(move || {
-                server
-                    .wrap_err(&c, c.send(&message))
-                    .expect("Failed to broadcast");
-            });
+        for c in clients {
+            let _ = self.wrap_err(&c, c.send_encoded(&encoded));
         }
 
         Ok(())
@@ -0,0 +1,27 @@
+use std::sync::Arc;
+
+use crate::{
+    requests::{error::RequestError, reaction},
+    server::Server,
+    utils::client::Client,
+};
+
+/// React to a direct message, only the two users of the conversation can
+pub fn react(
+    server: &Arc<Server>,
+    client: &Client,
+    message_id: i64,
+    emoji: &str,
+    added: bool,
+) -> crate::Result<()> {
+    let msg = reaction::find(server, message_id)?;
+    let user_id = client.get_uuid()?;
+    if msg.from != user_id && msg.channel_id != user_id {
+        return Err(RequestError::NotFound("Message does not exist".to_string()).into());
+    }
+
+    let Some(reaction) = reaction::change(server, &msg, emoji, &user_id, added)? else {
+        return Ok(());
+    };
+    server.broadcast_to(&[&msg.channel_id, &msg.from], reaction)
+}
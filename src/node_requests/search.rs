@@ -0,0 +1,29 @@
+use std::sync::Arc;
+
+use crate::{
+    requests::search::{fts_query, send},
+    server::Server,
+    utils::{
+        client::Client,
+        database::{SearchFilter, SearchScope},
+    },
+};
+
+/// Search the user's direct messages, or only the ones with `channel_id`
+pub fn search(
+    server: &Arc<Server>,
+    client: &Client,
+    query: &str,
+    channel_id: Option<&str>,
+    filter: &SearchFilter,
+) -> crate::Result<()> {
+    let query = fts_query(query, filter.limit)?;
+    let user_id = client.get_uuid()?;
+    let scope = SearchScope::Direct {
+        user_id: &user_id,
+        with: channel_id,
+    };
+
+    let hits = server.db.search(&query, &scope, filter)?;
+    send(client, hits, filter.limit)
+}
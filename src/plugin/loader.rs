@@ -6,90 +6,109 @@ use crate::{
     },
     server::Server,
 };
+use anyhow::anyhow;
 use std::{
     collections::HashMap,
     fs,
-    io::{BufRead, BufReader},
-    net::{TcpListener, TcpStream},
     path::Path,
-    process::Command,
     sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::{TcpListener, TcpStream},
+    process::Command,
+    sync::Notify,
 };
 
 logger!(LOGGER "Plugin Loader");
 
+/// How long a spawned plugin has to connect back to the loader
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone)]
 pub struct PluginLoader {
     plugin_clients: Arc<Mutex<HashMap<String, TcpStream>>>,
+    connected: Arc<Notify>,
 }
 
 impl PluginLoader {
     pub fn new() -> Self {
         Self {
             plugin_clients: Arc::new(Mutex::new(HashMap::new())),
+            connected: Arc::new(Notify::new()),
         }
     }
 
-    pub fn load(&self, path: &Path) -> Plugin {
-        let json_string = fs::read_to_string(path.join("plugin.json")).unwrap();
-        let plugin_json: PluginJson = serde_json::from_str(&json_string).unwrap();
+    pub async fn load(&self, path: &Path) -> crate::Result<Plugin> {
+        let json_string = fs::read_to_string(path.join("plugin.json"))?;
+        let plugin_json: PluginJson = serde_json::from_str(&json_string)?;
         LOGGER.info(format!("Loading {}", plugin_json.id));
 
-        let child = Arc::new(Mutex::new(
-            Command::new(plugin_json.file)
-                .args(plugin_json.args)
-                .current_dir(path)
-                .spawn()
-                .unwrap(),
-        ));
+        let child = Command::new(&plugin_json.file)
+            .args(&plugin_json.args)
+            .current_dir(path)
+            .spawn()?;
 
-        while self
-            .plugin_clients
-            .lock()
-            .unwrap()
-            .get(&plugin_json.id)
-            .is_none()
-        {}
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, self.wait_for(&plugin_json.id))
+            .await
+            .map_err(|_| anyhow!("Plugin '{}' never connected", plugin_json.id))?;
         LOGGER.info(format!("Loaded {}", plugin_json.id));
-        let a = self
-            .plugin_clients
-            .lock()
-            .unwrap()
-            .get(&plugin_json.id)
-            .unwrap()
-            .try_clone()
-            .unwrap();
-        Plugin {
-            stream: a.try_clone().unwrap(),
-            reader: BufReader::new(a),
-            id: plugin_json.id,
-            child,
+
+        Ok(Plugin::new(plugin_json.id, stream, child))
+    }
+
+    /// Wait for the plugin to connect and introduce itself
+    async fn wait_for(&self, plugin_id: &str) -> TcpStream {
+        loop {
+            // Register interest before checking so a connection in between isn't missed
+            let connected = self.connected.notified();
+            if let Some(stream) = self.plugin_clients.lock().unwrap().remove(plugin_id) {
+                return stream;
+            }
+            connected.await;
         }
     }
 
-    pub fn start_server(&self) {
-        let plugin_clients = self.plugin_clients.clone();
-        let listener = TcpListener::bind("0.0.0.0:7243").unwrap();
-
-        let handle_plugin = move |stream: Result<TcpStream, std::io::Error>| -> crate::Result<()> {
-            let stream = stream?;
-            let mut reader = BufReader::new(&stream);
-            let mut s = String::new();
-            reader.read_line(&mut s)?;
-            let plugin_handshake: PluginHandshake = serde_json::from_str(&s)?;
-            plugin_clients
-                .lock()
-                .unwrap()
-                .insert(plugin_handshake.id, stream);
-            Ok(())
-        };
-
-        std::thread::spawn(move || {
-            for stream in listener.incoming() {
-                if let Err(e) = handle_plugin(stream) {
-                    LOGGER.error(format!("Plugin error: {e}"));
-                }
+    pub async fn start_server(&self) -> crate::Result<()> {
+        let loader = self.clone();
+        let listener = TcpListener::bind("0.0.0.0:7243").await?;
+
+        tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        LOGGER.error(format!("Plugin error: {e}"));
+                        continue;
+                    }
+                };
+
+                let loader = loader.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = loader.handle_plugin(stream).await {
+                        LOGGER.error(format!("Plugin error: {e}"));
+                    }
+                });
             }
         });
+
+        Ok(())
+    }
+
+    async fn handle_plugin(&self, mut stream: TcpStream) -> crate::Result<()> {
+        // Read the handshake byte by byte so nothing after it gets buffered away
+        let mut s = String::new();
+        BufReader::with_capacity(1, &mut stream)
+            .read_line(&mut s)
+            .await?;
+        let plugin_handshake: PluginHandshake = serde_json::from_str(&s)?;
+        self.plugin_clients
+            .lock()
+            .unwrap()
+            .insert(plugin_handshake.id, stream);
+        self.connected.notify_waiters();
+        Ok(())
     }
 
     pub fn remove(&self, plugin_id: &str) {
@@ -100,7 +119,19 @@ impl PluginLoader {
         self.plugin_clients.lock().unwrap().clear();
     }
 
-    pub fn load_all(&self, server: &Arc<Server>) {
+    /// Load a plugin and start handling its messages
+    pub async fn start(&self, server: &Arc<Server>, path: &Path) -> crate::Result<()> {
+        let p = self.load(path).await?;
+        server.plugins.lock().unwrap().push(p.clone());
+
+        let s = server.clone();
+        tokio::spawn(async move {
+            LOGGER.extract(p.run(&s).await, format!("Plugin '{}' failed", p.get_id()));
+        });
+        Ok(())
+    }
+
+    pub async fn load_all(&self, server: &Arc<Server>) {
         let path = server.root.join("plugins");
         if !path.exists() {
             fs::create_dir(&path).unwrap();
@@ -113,23 +144,12 @@ impl PluginLoader {
             let path = entry.path();
 
             if path.is_dir() {
-                let mut p = self.load(&path);
-                let s = server.clone();
-                server.plugins.lock().unwrap().push(p.clone());
-                std::thread::spawn(move || p.run(&s));
+                LOGGER.extract(
+                    self.start(server, &path).await,
+                    format!("Couldn't load {path:?}"),
+                );
             }
         }
         LOGGER.info("Plugins loaded");
     }
 }
-
-impl Clone for Plugin {
-    fn clone(&self) -> Self {
-        Plugin {
-            stream: self.stream.try_clone().unwrap(),
-            reader: BufReader::new(self.stream.try_clone().unwrap()),
-            id: self.id.clone(),
-            child: self.child.clone(),
-        }
-    }
-}
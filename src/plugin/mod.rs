@@ -1,72 +1,125 @@
 pub mod loader;
 pub mod types;
 
-use std::{
-    io::{BufRead, BufReader, Write},
-    net::TcpStream,
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{
+        TcpStream,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
     process::Child,
-    sync::{Arc, Mutex},
+    sync::{Mutex as AsyncMutex, mpsc},
 };
 
 use crate::{
     plugin::types::{LoaderMessage, PluginMessage},
+    requests::channel,
     server::Server,
     types::message::ServerMessage,
+    utils::{self, permissions::Permissions},
 };
 
+crate::logger!(LOGGER "Plugin");
+
+enum Outbound {
+    Message(String),
+    Close,
+}
+
+#[derive(Clone)]
 pub struct Plugin {
-    stream: TcpStream,
-    reader: BufReader<TcpStream>,
+    outbound: mpsc::UnboundedSender<Outbound>,
+    reader: Arc<AsyncMutex<BufReader<OwnedReadHalf>>>,
     id: String,
     child: Arc<Mutex<Child>>,
 }
 
 impl Plugin {
-    pub fn send(&mut self, m: &LoaderMessage) -> crate::Result<()> {
-        self.stream
-            .write(serde_json::to_string(&m).unwrap().as_bytes())?;
-        Ok(())
+    pub fn new(id: String, stream: TcpStream, child: Child) -> Self {
+        let (reader, writer) = stream.into_split();
+        let (outbound, rx) = mpsc::unbounded_channel();
+        tokio::spawn(Self::write_loop(writer, rx));
+
+        Plugin {
+            outbound,
+            reader: Arc::new(AsyncMutex::new(BufReader::new(reader))),
+            id,
+            child: Arc::new(Mutex::new(child)),
+        }
+    }
+
+    async fn write_loop(mut writer: OwnedWriteHalf, mut rx: mpsc::UnboundedReceiver<Outbound>) {
+        while let Some(Outbound::Message(m)) = rx.recv().await {
+            if writer.write_all(m.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+        let _ = writer.shutdown().await;
     }
 
-    pub fn read(&mut self) -> crate::Result<PluginMessage> {
+    pub fn send(&self, m: &LoaderMessage) -> crate::Result<()> {
+        self.outbound
+            .send(Outbound::Message(serde_json::to_string(&m)?))
+            .map_err(|_| anyhow!("Plugin '{}' disconnected", self.id))
+    }
+
+    /// Returns `None` once the plugin closed the connection
+    pub async fn read(&self) -> crate::Result<Option<PluginMessage>> {
         let mut buf = String::new();
-        self.reader.read_line(&mut buf)?;
-        Ok(serde_json::from_str(&buf)?)
+        if self.reader.lock().await.read_line(&mut buf).await? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_str(&buf)?))
     }
 
-    pub fn run(&mut self, server: &Arc<Server>) -> crate::Result<()> {
-        loop {
-            match self.read()? {
+    pub async fn run(&self, server: &Arc<Server>) -> crate::Result<()> {
+        while let Some(m) = self.read().await? {
+            match m {
                 PluginMessage::SendMessage {
                     channel_id,
                     contents,
                 } => {
-                    let msg = server.db.insert_message(
-                        &channel_id,
-                        &self.id,
-                        &contents,
-                        chrono::Utc::now().timestamp(),
-                    )?;
-
-                    for c in server.clients.lock().unwrap().iter() {
-                        let c = c.clone();
-                        let server = server.clone();
-                        let msg = msg.clone();
-                        std::thread::spawn(move || {
-                            server
-                                .wrap_err(&c, c.send(ServerMessage::MessageCreate(msg)))
-                                .expect("Failed to broadcast");
-                        });
+                    // Plugins are given roles by their id like users
+                    let permissions = utils::blocking(|| server.permissions(&self.id))?;
+                    if !permissions.contains(Permissions::SEND_MESSAGES) {
+                        LOGGER.warn(format!(
+                            "Plugin '{}' is missing permission: send_messages",
+                            self.id
+                        ));
+                        continue;
                     }
+                    if let Err(e) = channel::find(server, &channel_id).and_then(|c| {
+                        channel::check_send(&c, server.channel_permissions(&self.id, &c.id)?, false)
+                    }) {
+                        LOGGER.warn(format!("Plugin '{}' can't send a message: {e}", self.id));
+                        continue;
+                    }
+
+                    let msg = utils::blocking(|| {
+                        server.db.insert_message(
+                            &channel_id,
+                            &self.id,
+                            &contents,
+                            chrono::Utc::now().timestamp(),
+                            None,
+                            None,
+                        )
+                    })?;
+
+                    server.broadcast(ServerMessage::MessageCreate(msg));
                 }
             }
         }
+        Ok(())
     }
 
     pub fn stop(&mut self) -> crate::Result<()> {
         self.send(&LoaderMessage::Shutdown)?;
-        self.stream.shutdown(std::net::Shutdown::Both)?;
-        self.child.lock().unwrap().kill()?;
+        let _ = self.outbound.send(Outbound::Close);
+        self.child.lock().unwrap().start_kill()?;
         Ok(())
     }
 
@@ -0,0 +1,334 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    requests::{error::RequestError, upload},
+    server::Server,
+    types::{
+        data::{Channel, ChannelKind},
+        message::ServerMessage,
+    },
+    utils::permissions::Permissions,
+};
+
+crate::logger!(LOGGER "Channels");
+
+/// Longest channel name
+pub const MAX_NAME_LEN: usize = 100;
+/// Longest channel topic
+pub const MAX_TOPIC_LEN: usize = 1024;
+/// Longest slow mode interval, 6 hours
+pub const MAX_SLOW_MODE_SECS: u32 = 6 * 60 * 60;
+
+/// Get a channel by its id
+pub fn get(server: &Arc<Server>, channel_id: &str) -> Option<Channel> {
+    server
+        .channels
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|c| c.id == channel_id)
+        .cloned()
+}
+
+/// Like [`get`] but a missing channel is a `NotFound` error
+pub fn find(server: &Arc<Server>, channel_id: &str) -> crate::Result<Channel> {
+    get(server, channel_id)
+        .ok_or_else(|| RequestError::NotFound("Channel does not exist".to_string()).into())
+}
+
+/// Fails unless a user with `permissions` in the channel can send messages to it, or to one of
+/// its threads if `in_thread`
+pub fn check_send(
+    channel: &Channel,
+    permissions: Permissions,
+    in_thread: bool,
+) -> crate::Result<()> {
+    match channel.kind {
+        ChannelKind::Announcement if !permissions.contains(Permissions::POST_ANNOUNCEMENTS) => Err(
+            RequestError::Unauthorized("Missing permission: post_announcements".to_string()).into(),
+        ),
+        ChannelKind::Forum if in_thread => Ok(()),
+        ChannelKind::Forum => Err(RequestError::InvalidRequest(
+            "Messages in forum channels have to be in a thread".to_string(),
+        )
+        .into()),
+        ref kind if !kind.is_text() => Err(RequestError::InvalidRequest(
+            "Messages can only be sent to text channels".to_string(),
+        )
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// Like [`find`] but the channel must be a voice or stage channel
+pub fn find_voice(server: &Arc<Server>, channel_id: &str) -> crate::Result<Channel> {
+    let channel = find(server, channel_id)?;
+    if !channel.kind.is_voice() {
+        return Err(
+            RequestError::InvalidRequest("Only voice channels can be joined".to_string()).into(),
+        );
+    }
+    Ok(channel)
+}
+
+pub fn create(server: &Arc<Server>, name: &str, kind: ChannelKind) -> crate::Result<Channel> {
+    check_name(name)?;
+
+    let channel = {
+        let mut channels = server.channels.lock().unwrap();
+        let channel = Channel {
+            id: format!("{:016x}", rand::random::<u64>()),
+            name: name.to_string(),
+            kind,
+            position: channels.last().map_or(0, |c| c.position + 1),
+            topic: None,
+            parent: None,
+            nsfw: false,
+            slow_mode_secs: 0,
+        };
+        server.db.insert_channel(&channel)?;
+        channels.push(channel.clone());
+        channel
+    };
+
+    LOGGER.info(format!(
+        "Created channel '{}' ({})",
+        channel.name, channel.id
+    ));
+    server.broadcast(ServerMessage::ChannelCreate(channel.clone()));
+    Ok(channel)
+}
+
+pub fn rename(server: &Arc<Server>, channel_id: &str, name: &str) -> crate::Result<()> {
+    check_name(name)?;
+    update(server, channel_id, |c| c.name = name.to_string())
+}
+
+pub fn set_topic(
+    server: &Arc<Server>,
+    channel_id: &str,
+    topic: Option<String>,
+) -> crate::Result<()> {
+    if topic
+        .as_ref()
+        .is_some_and(|t| t.chars().count() > MAX_TOPIC_LEN)
+    {
+        return Err(RequestError::InvalidRequest(format!(
+            "Topic can't be longer than {MAX_TOPIC_LEN} characters"
+        ))
+        .into());
+    }
+
+    let topic = topic.filter(|t| !t.is_empty());
+    update(server, channel_id, |c| c.topic = topic)
+}
+
+/// Put a channel in a category, `None` takes it out of the one it's in
+pub fn set_parent(
+    server: &Arc<Server>,
+    channel_id: &str,
+    parent: Option<String>,
+) -> crate::Result<()> {
+    if let Some(parent) = &parent {
+        let kind = find(server, parent)?.kind;
+        let is_category = |kind: &ChannelKind| matches!(kind, ChannelKind::Category);
+        if !is_category(&kind) || is_category(&find(server, channel_id)?.kind) {
+            return Err(RequestError::InvalidRequest(
+                "Only channels can be put in a category".to_string(),
+            )
+            .into());
+        }
+    }
+
+    update(server, channel_id, |c| c.parent = parent)
+}
+
+pub fn set_nsfw(server: &Arc<Server>, channel_id: &str, nsfw: bool) -> crate::Result<()> {
+    update(server, channel_id, |c| c.nsfw = nsfw)
+}
+
+pub fn set_slow_mode(server: &Arc<Server>, channel_id: &str, seconds: u32) -> crate::Result<()> {
+    if seconds > MAX_SLOW_MODE_SECS {
+        return Err(RequestError::InvalidRequest(format!(
+            "Slow mode can't be longer than {MAX_SLOW_MODE_SECS} seconds"
+        ))
+        .into());
+    }
+
+    update(server, channel_id, |c| c.slow_mode_secs = seconds)
+}
+
+/// Takes the user's turn in the channel's slow mode, fails with how long to wait if they sent
+/// a message in it less than its interval ago. Returns when the turn was taken so it can be
+/// given back with [`release_slow_mode`] if the message isn't stored
+pub fn reserve_slow_mode(
+    server: &Arc<Server>,
+    channel: &Channel,
+    user_id: &str,
+    permissions: Permissions,
+) -> crate::Result<Option<Instant>> {
+    // Moderators aren't slowed down
+    if channel.slow_mode_secs == 0
+        || permissions.contains(Permissions::MANAGE_MESSAGES)
+        || permissions.contains(Permissions::MANAGE_CHANNELS)
+    {
+        return Ok(None);
+    }
+
+    let interval = Duration::from_secs(channel.slow_mode_secs.into());
+    let key = (user_id.to_string(), channel.id.clone());
+    // Checked and taken under one lock so concurrent sends can't both get through
+    let mut sent = server.slow_mode.lock().unwrap();
+    if let Some(last) = sent.get(&key)
+        && last.elapsed() < interval
+    {
+        let wait = (interval - last.elapsed()).as_millis().div_ceil(1000);
+        return Err(RequestError::InvalidRequest(format!(
+            "Slow mode is on, wait {wait} more seconds before sending another message"
+        ))
+        .into());
+    }
+
+    // Nothing older than the longest interval can hold anyone back
+    let max = Duration::from_secs(MAX_SLOW_MODE_SECS.into());
+    sent.retain(|_, last| last.elapsed() < max);
+    let now = Instant::now();
+    sent.insert(key, now);
+    Ok(Some(now))
+}
+
+/// Give back a turn taken by [`reserve_slow_mode`] at `reserved`
+pub fn release_slow_mode(server: &Arc<Server>, channel_id: &str, user_id: &str, reserved: Instant) {
+    let key = (user_id.to_string(), channel_id.to_string());
+    let mut sent = server.slow_mode.lock().unwrap();
+    if sent.get(&key) == Some(&reserved) {
+        sent.remove(&key);
+    }
+}
+
+/// Move a channel to `position`, every channel whose position changed is broadcast
+pub fn move_to(server: &Arc<Server>, channel_id: &str, position: u32) -> crate::Result<()> {
+    let moved = {
+        let mut channels = server.channels.lock().unwrap();
+        let Some(from) = channels.iter().position(|c| c.id == channel_id) else {
+            return Err(RequestError::NotFound("Channel does not exist".to_string()).into());
+        };
+        // Can't underflow, the channel being moved was just found
+        let to = (position as usize).min(channels.len() - 1);
+
+        // Nothing changes in memory until the new order is saved
+        let mut reordered = channels.clone();
+        let channel = reordered.remove(from);
+        reordered.insert(to, channel);
+
+        let mut moved = Vec::new();
+        for (position, channel) in reordered.iter_mut().enumerate() {
+            if channel.position != position as u32 {
+                channel.position = position as u32;
+                moved.push(channel.clone());
+            }
+        }
+        server.db.set_channel_positions(&moved)?;
+        *channels = reordered;
+        moved
+    };
+
+    for channel in moved {
+        server.broadcast(ServerMessage::ChannelUpdate(channel));
+    }
+    Ok(())
+}
+
+/// Delete a channel with its messages, anyone in it if it's a voice channel is disconnected
+pub fn delete(server: &Arc<Server>, channel_id: &str) -> crate::Result<()> {
+    let hashes = {
+        let mut channels = server.channels.lock().unwrap();
+        let Some(index) = channels.iter().position(|c| c.id == channel_id) else {
+            return Err(RequestError::NotFound("Channel does not exist".to_string()).into());
+        };
+
+        let hashes = server.db.delete_channel(channel_id)?;
+        let channel = channels.remove(index);
+        LOGGER.info(format!(
+            "Deleted channel '{}' ({})",
+            channel.name, channel.id
+        ));
+        hashes
+    };
+    upload::remove_unused(server, &hashes);
+
+    // The channels of a deleted category stay, outside of any category
+    let children: Vec<String> = server
+        .channels
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|c| c.parent.as_deref() == Some(channel_id))
+        .map(|c| c.id.clone())
+        .collect();
+    for child in children {
+        update(server, &child, |c| c.parent = None)?;
+    }
+
+    let in_voice: Vec<String> = server
+        .voice
+        .lock()
+        .unwrap()
+        .get(channel_id)
+        .into_iter()
+        .cloned()
+        .collect();
+    for user_id in in_voice {
+        let Some(voice_id) = server.voice.lock().unwrap().remove(channel_id, &user_id) else {
+            continue;
+        };
+        server.broadcast(ServerMessage::VoiceLeave {
+            user_id,
+            channel_id: channel_id.to_string(),
+            voice_id,
+        });
+    }
+
+    // Sent while the overwrites still decide who knew about the channel
+    server.broadcast(ServerMessage::ChannelDelete {
+        channel_id: channel_id.to_string(),
+    });
+    server.overwrites.lock().unwrap().remove(channel_id);
+    Ok(())
+}
+
+fn update(
+    server: &Arc<Server>,
+    channel_id: &str,
+    change: impl FnOnce(&mut Channel),
+) -> crate::Result<()> {
+    let channel = {
+        let mut channels = server.channels.lock().unwrap();
+        let Some(channel) = channels.iter_mut().find(|c| c.id == channel_id) else {
+            return Err(RequestError::NotFound("Channel does not exist".to_string()).into());
+        };
+
+        let mut updated = channel.clone();
+        change(&mut updated);
+        server.db.update_channel(&updated)?;
+        *channel = updated;
+        channel.clone()
+    };
+
+    server.broadcast(ServerMessage::ChannelUpdate(channel));
+    Ok(())
+}
+
+fn check_name(name: &str) -> crate::Result<()> {
+    let len = name.trim().chars().count();
+    if len == 0 || len > MAX_NAME_LEN {
+        return Err(RequestError::InvalidRequest(format!(
+            "Channel names must be 1 to {MAX_NAME_LEN} characters"
+        ))
+        .into());
+    }
+    Ok(())
+}
@@ -1,16 +1,63 @@
-use crate::{server::Server, types::message::ServerMessage, utils::client::Client};
+use crate::{
+    requests::error::RequestError,
+    server::Server,
+    types::message::ServerMessage,
+    utils::{client::Client, database::Page},
+};
 use std::sync::Arc;
 
 crate::logger!(LOGGER "Chunk Loader");
 
+/// Most messages in a single page
+pub const MAX_PAGE_SIZE: usize = 100;
+
 pub fn load_chunk(
     server: &Arc<Server>,
     client: &Client,
     channel_id: &str,
     chunk_id: usize,
 ) -> crate::Result<()> {
-    let mut chunk = server.db.get_chunk(channel_id, chunk_id)?;
-    chunk.reverse();
+    let chunk = server.db.get_chunk(channel_id, chunk_id)?;
     client.send(ServerMessage::Chunk(chunk))?;
     Ok(())
 }
+
+pub fn load_messages(
+    server: &Arc<Server>,
+    client: &Client,
+    channel_id: &str,
+    page: Page,
+    limit: usize,
+) -> crate::Result<()> {
+    let messages = server.db.get_messages(channel_id, page, limit)?;
+    client.send(ServerMessage::Messages {
+        channel_id: channel_id.to_string(),
+        messages,
+    })
+}
+
+/// Where a page starts, at most one message id can be given
+pub fn page(
+    before: Option<i64>,
+    after: Option<i64>,
+    around: Option<i64>,
+    limit: usize,
+) -> crate::Result<Page> {
+    if limit == 0 || limit > MAX_PAGE_SIZE {
+        return Err(RequestError::InvalidRequest(format!(
+            "limit must be between 1 and {MAX_PAGE_SIZE}"
+        ))
+        .into());
+    }
+
+    match (before, after, around) {
+        (None, None, None) => Ok(Page::Latest),
+        (Some(id), None, None) => Ok(Page::Before(id)),
+        (None, Some(id), None) => Ok(Page::After(id)),
+        (None, None, Some(id)) => Ok(Page::Around(id)),
+        _ => Err(RequestError::InvalidRequest(
+            "Only one of before, after and around can be set".to_string(),
+        )
+        .into()),
+    }
+}
@@ -0,0 +1,31 @@
+use crate::types::message::ResponseError;
+
+/// A request failed because of the request itself, the client is told why and stays connected.
+///
+/// Any other error a handler returns is fatal and ends the connection.
+#[derive(Debug)]
+pub enum RequestError {
+    NotFound(String),
+    Unauthorized(String),
+    InvalidRequest(String),
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound(m) | Self::Unauthorized(m) | Self::InvalidRequest(m) => f.write_str(m),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+impl From<RequestError> for ResponseError {
+    fn from(e: RequestError) -> Self {
+        match e {
+            RequestError::NotFound(m) => Self::NotFound(m),
+            RequestError::Unauthorized(m) => Self::Unauthorized(m),
+            RequestError::InvalidRequest(m) => Self::InvalidRequest(m),
+        }
+    }
+}
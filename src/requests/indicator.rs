@@ -1,7 +1,7 @@
 use crate::{server::Server, types::message::ServerMessage, utils::client::Client};
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", content = "params", rename_all = "snake_case")]
@@ -16,20 +16,17 @@ pub struct IndicatorContext {
 }
 
 impl Server {
-    pub fn spawn_indicator_thread(self: &Arc<Self>) {
+    /// Counts down the indicators once a second and drops the expired ones
+    pub fn spawn_indicator_task(self: &Arc<Self>) {
         let server = self.clone();
-        std::thread::spawn(move || {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
             loop {
-                let mut remove = Vec::new();
-                for (i, indicator) in server.indicators.lock().unwrap().iter_mut().enumerate() {
-                    indicator.expires -= 1;
-                    if indicator.expires == 0 {
-                        remove.push(i);
-                    }
-                }
-                for r in remove {
-                    server.indicators.lock().unwrap().remove(r);
-                }
+                interval.tick().await;
+                server.indicators.lock().unwrap().retain_mut(|indicator| {
+                    indicator.expires = indicator.expires.saturating_sub(1);
+                    indicator.expires > 0
+                });
             }
         });
     }
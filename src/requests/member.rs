@@ -0,0 +1,197 @@
+use std::sync::Arc;
+
+use crate::{
+    requests::error::RequestError,
+    server::Server,
+    types::{
+        data::{Member, Status, UserPresence},
+        message::ServerMessage,
+    },
+    utils::{client::Client, permissions::Permissions},
+};
+
+crate::logger!(LOGGER "Members");
+
+/// Most members in a single page
+pub const MAX_PAGE_SIZE: usize = 100;
+
+/// Called on every authentication, the first one makes the user a member
+pub fn join(server: &Arc<Server>, user_id: &str) -> crate::Result<()> {
+    let joined = chrono::Utc::now().timestamp();
+    if !server.db.insert_member(user_id, joined)? {
+        return Ok(());
+    }
+
+    LOGGER.info(format!("New member: {user_id}"));
+    server.broadcast(ServerMessage::MemberJoin(Member {
+        user_id: user_id.to_string(),
+        joined,
+        presence: presence_of(server, user_id),
+    }));
+    Ok(())
+}
+
+/// Remove a member and close their connections, they join again when they next connect
+pub fn remove(server: &Arc<Server>, user_id: &str) -> crate::Result<()> {
+    if !server.db.delete_member(user_id)? {
+        return Err(RequestError::NotFound(format!("{user_id} is not a member")).into());
+    }
+
+    let clients: Vec<Client> = server
+        .clients
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|c| c.get_uuid().is_ok_and(|uuid| uuid == user_id))
+        .cloned()
+        .collect();
+    for c in clients {
+        let _ = c.send_close(1008, "Removed from the server");
+    }
+
+    server.broadcast(ServerMessage::MemberLeave {
+        user_id: user_id.to_string(),
+    });
+    Ok(())
+}
+
+/// Disconnect a member, `by` is `None` when it's done from the CLI
+pub fn kick(server: &Arc<Server>, by: Option<&Client>, user_id: &str) -> crate::Result<()> {
+    check_target(server, by, user_id)?;
+    remove(server, user_id)
+}
+
+/// Remove a member and keep them from connecting again
+pub fn ban(server: &Arc<Server>, by: Option<&Client>, user_id: &str) -> crate::Result<()> {
+    check_target(server, by, user_id)?;
+    if !server
+        .db
+        .insert_ban(user_id, chrono::Utc::now().timestamp())?
+    {
+        return Err(RequestError::InvalidRequest(format!("{user_id} is already banned")).into());
+    }
+
+    LOGGER.info(format!("Banned {user_id}"));
+    if server.db.get_member(user_id)?.is_some() {
+        remove(server, user_id)?;
+    }
+    Ok(())
+}
+
+pub fn unban(server: &Arc<Server>, user_id: &str) -> crate::Result<()> {
+    if !server.db.delete_ban(user_id)? {
+        return Err(RequestError::NotFound(format!("{user_id} is not banned")).into());
+    }
+
+    LOGGER.info(format!("Unbanned {user_id}"));
+    Ok(())
+}
+
+/// Only administrators can kick or ban administrators
+fn check_target(server: &Arc<Server>, by: Option<&Client>, user_id: &str) -> crate::Result<()> {
+    let Some(client) = by else {
+        return Ok(());
+    };
+
+    let admin = |user_id: &str| {
+        server
+            .permissions(user_id)
+            .map(|p| p.contains(Permissions::ADMINISTRATOR))
+    };
+    if admin(user_id)? && !admin(&client.get_uuid()?)? {
+        return Err(RequestError::Unauthorized(
+            "Only administrators can kick or ban administrators".to_string(),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+pub fn list(
+    server: &Arc<Server>,
+    client: &Client,
+    online: Option<bool>,
+    after: Option<&str>,
+    limit: usize,
+) -> crate::Result<()> {
+    if limit == 0 || limit > MAX_PAGE_SIZE {
+        return Err(RequestError::InvalidRequest(format!(
+            "limit must be between 1 and {MAX_PAGE_SIZE}"
+        ))
+        .into());
+    }
+
+    let after = after.unwrap_or_default();
+    let presence = server.presence.lock().unwrap().snapshot();
+    let mut members = Vec::new();
+
+    match online {
+        // Everyone online is connected, so they can be paged from memory
+        Some(true) => {
+            let mut online: Vec<&String> =
+                presence.keys().filter(|id| id.as_str() > after).collect();
+            online.sort();
+            for user_id in online {
+                if members.len() == limit {
+                    break;
+                }
+                // Connected but removed in the meantime, the page is filled from the next ones
+                let Some(joined) = server.db.get_member(user_id)? else {
+                    continue;
+                };
+                members.push(Member {
+                    user_id: user_id.clone(),
+                    joined,
+                    presence: presence[user_id].clone(),
+                });
+            }
+        }
+        _ => {
+            let mut cursor = after.to_string();
+            while members.len() < limit {
+                let page = server.db.get_members(&cursor, limit)?;
+                let Some((last, _)) = page.last() else {
+                    break;
+                };
+                cursor = last.clone();
+
+                let exhausted = page.len() < limit;
+                members.extend(
+                    page.into_iter()
+                        .filter(|(user_id, _)| online.is_none() || !presence.contains_key(user_id))
+                        .map(|(user_id, joined)| Member {
+                            presence: presence.get(&user_id).cloned().unwrap_or(OFFLINE),
+                            user_id,
+                            joined,
+                        }),
+                );
+                if exhausted {
+                    break;
+                }
+            }
+            members.truncate(limit);
+        }
+    }
+
+    // A full page means there may be more
+    let next = (members.len() == limit)
+        .then(|| members.last().map(|m| m.user_id.clone()))
+        .flatten();
+    client.send(ServerMessage::Members { members, next })?;
+    Ok(())
+}
+
+const OFFLINE: UserPresence = UserPresence {
+    status: Status::Offline,
+    text: None,
+};
+
+fn presence_of(server: &Arc<Server>, user_id: &str) -> UserPresence {
+    server
+        .presence
+        .lock()
+        .unwrap()
+        .snapshot()
+        .remove(user_id)
+        .unwrap_or(OFFLINE)
+}
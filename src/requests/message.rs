@@ -1,8 +1,12 @@
 use std::sync::Arc;
 
-use anyhow::anyhow;
-
-use crate::{plugin::types::LoaderMessage, server::Server, types, utils::client::Client};
+use crate::{
+    plugin::types::LoaderMessage,
+    requests::{channel, error::RequestError, thread, upload},
+    server::Server,
+    types,
+    utils::{client::Client, permissions::Permissions},
+};
 
 crate::logger!(LOGGER "Message Manager");
 
@@ -11,32 +15,75 @@ pub fn send(
     client: &Client,
     channel_id: &str,
     contents: &str,
-) -> crate::Result<()> {
+    reply_to: Option<i64>,
+    thread_id: Option<i64>,
+    attachments: &[i64],
+) -> crate::Result<types::data::Message> {
     LOGGER.info(format!("SendMessage to {channel_id}: {contents}"));
 
-    if contents.is_empty() {
-        client.send(types::message::ResponseError::InvalidRequest(format!(
-            "Invalid message: empty message"
-        )))?;
-
-        return Ok(());
+    if contents.is_empty() && attachments.is_empty() {
+        return Err(
+            RequestError::InvalidRequest("Invalid message: empty message".to_string()).into(),
+        );
     }
 
-    let msg = server.db.insert_message(
-        &channel_id,
-        &client.get_uuid()?,
-        &contents,
-        chrono::Utc::now().timestamp(),
-    )?;
+    let channel = channel::find(server, channel_id)?;
+    let user_id = client.get_uuid()?;
+    let permissions = server.channel_permissions(&user_id, channel_id)?;
+    channel::check_send(&channel, permissions, thread_id.is_some())?;
+    if let Some(thread_id) = thread_id
+        && thread::find(server, thread_id)?.channel_id != channel_id
+    {
+        return Err(
+            RequestError::InvalidRequest("The thread is in another channel".to_string()).into(),
+        );
+    }
+    if let Some(reply_to) = reply_to {
+        let Some(replied) = server.db.get_message_by_id(reply_to)? else {
+            return Err(
+                RequestError::NotFound("Replied message does not exist".to_string()).into(),
+            );
+        };
+        // The message a thread started at belongs to the thread as well
+        let same_thread = replied.thread_id == thread_id || Some(replied.id) == thread_id;
+        if replied.channel_id != channel_id || !same_thread {
+            return Err(RequestError::InvalidRequest(
+                "Replies have to be in the same channel and thread".to_string(),
+            )
+            .into());
+        }
+    }
+    let attachments = upload::attachments(server, &user_id, attachments)?;
+    let reserved = channel::reserve_slow_mode(server, &channel, &user_id, permissions)?;
+
+    let mut msg = server
+        .db
+        .insert_message(
+            channel_id,
+            &user_id,
+            contents,
+            chrono::Utc::now().timestamp(),
+            reply_to,
+            thread_id,
+        )
+        .inspect_err(|_| {
+            if let Some(reserved) = reserved {
+                channel::release_slow_mode(server, channel_id, &user_id, reserved);
+            }
+        })?;
+    for attachment in &attachments {
+        server.db.attach(msg.id, attachment.id)?;
+    }
+    msg.attachments = attachments;
 
     server.broadcast(types::message::ServerMessage::MessageCreate(msg.clone()));
 
     server.send_plugin_message(&LoaderMessage::MessageSent {
         user_id: client.get_uuid().unwrap_or_default(),
-        msg: msg,
+        msg: msg.clone(),
     })?;
 
-    Ok(())
+    Ok(msg)
 }
 
 pub fn edit(
@@ -47,17 +94,22 @@ pub fn edit(
 ) -> crate::Result<()> {
     LOGGER.info(format!("EditMessage {message_id}: {new_contents}"));
     let Some(msg) = server.db.get_message_by_id(message_id)? else {
-        return Err(anyhow!("Message does not exist"));
+        return Err(RequestError::NotFound("Message does not exist".to_string()).into());
     };
 
     if msg.from != client.get_uuid()? {
-        return Err(anyhow!("You are not the author of this message"));
+        return Err(RequestError::Unauthorized(
+            "You are not the author of this message".to_string(),
+        )
+        .into());
     }
+    server.require(client, Some(&msg.channel_id), Permissions::SEND_MESSAGES)?;
 
     server.db.edit_message(message_id, new_contents)?;
 
     server.broadcast(types::message::ServerMessage::MessageUpdate {
         message_id,
+        channel_id: msg.channel_id,
         contents: new_contents.to_string(),
     });
 
@@ -67,16 +119,43 @@ pub fn edit(
 pub fn delete(server: &Arc<Server>, client: &Client, message_id: i64) -> crate::Result<()> {
     LOGGER.info(format!("DeleteMessage {message_id}"));
     let Some(msg) = server.db.get_message_by_id(message_id)? else {
-        return Err(anyhow!("Message does not exist"));
+        return Err(RequestError::NotFound("Message does not exist".to_string()).into());
     };
 
-    if msg.from != client.get_uuid()? {
-        return Err(anyhow!("You are not the author of this message"));
+    let user_id = client.get_uuid()?;
+    if msg.from != user_id
+        && !server
+            .channel_permissions(&user_id, &msg.channel_id)?
+            .contains(Permissions::MANAGE_MESSAGES)
+    {
+        return Err(RequestError::Unauthorized(
+            "You are not the author of this message".to_string(),
+        )
+        .into());
     }
 
-    server.db.delete_message(message_id)?;
+    let hashes = server.db.delete_message(message_id)?;
+    upload::remove_unused(server, &hashes);
+
+    server.broadcast(types::message::ServerMessage::MessageDelete {
+        message_id,
+        channel_id: msg.channel_id.clone(),
+    });
 
-    server.broadcast(types::message::ServerMessage::MessageDelete { message_id });
+    // A thread started at the message goes with it, along with everything sent to it
+    if let Some(deleted) = server.db.delete_thread(message_id)? {
+        upload::remove_unused(server, &deleted.hashes);
+        for id in deleted.messages {
+            server.broadcast(types::message::ServerMessage::MessageDelete {
+                message_id: id,
+                channel_id: msg.channel_id.clone(),
+            });
+        }
+        server.broadcast(types::message::ServerMessage::ThreadDelete {
+            thread_id: message_id,
+            channel_id: msg.channel_id,
+        });
+    }
 
     Ok(())
 }
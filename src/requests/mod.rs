@@ -1,14 +1,33 @@
+pub mod channel;
 pub mod chunk;
+pub mod error;
 pub mod indicator;
+pub mod member;
 pub mod message;
+pub mod overwrite;
+pub mod presence;
+pub mod reaction;
+pub mod role;
+pub mod search;
+pub mod session;
+pub mod thread;
+pub mod upload;
 pub mod voice;
 
 use std::sync::Arc;
 
 use crate::{
+    requests::error::RequestError,
     server::Server,
-    types::message::{ClientMessage, ServerMessage, WsMessage},
-    utils::client::Client,
+    types::{
+        data::Overwrite,
+        message::{ClientMessage, Event, ServerMessage, WsMessage},
+    },
+    utils::{
+        client::Client,
+        database::SearchFilter,
+        encoding::{Encoded, Encoding},
+    },
 };
 
 impl Server {
@@ -16,14 +35,29 @@ impl Server {
         self: &Arc<Self>,
         req: &WsMessage<ClientMessage>,
         client: &Client,
-    ) -> crate::Result<()> {
+    ) -> crate::Result<Option<i64>> {
+        let (required, channel_id) = role::required(req);
+        self.require(client, channel_id, required)?;
+
         match req {
             WsMessage::Message(req) => match req {
                 ClientMessage::SendMessage {
                     channel_id,
                     contents,
+                    reply_to,
+                    thread_id,
+                    attachments,
                 } => {
-                    message::send(self, client, channel_id, contents)?;
+                    let msg = message::send(
+                        self,
+                        client,
+                        channel_id,
+                        contents,
+                        *reply_to,
+                        *thread_id,
+                        attachments,
+                    )?;
+                    return Ok(Some(msg.id));
                 }
 
                 ClientMessage::EditMessage {
@@ -39,6 +73,66 @@ impl Server {
                     chunk_id,
                     channel_id,
                 } => chunk::load_chunk(self, client, channel_id, *chunk_id)?,
+                ClientMessage::LoadMessages {
+                    channel_id,
+                    before,
+                    after,
+                    around,
+                    limit,
+                } => {
+                    let page = chunk::page(*before, *after, *around, *limit)?;
+                    chunk::load_messages(self, client, channel_id, page, *limit)?
+                }
+
+                ClientMessage::CreateThread {
+                    channel_id,
+                    name,
+                    message_id,
+                    contents,
+                } => {
+                    let thread = thread::create(
+                        self,
+                        client,
+                        channel_id,
+                        name,
+                        *message_id,
+                        contents.as_deref(),
+                    )?;
+                    return Ok(Some(thread.id));
+                }
+                ClientMessage::LoadThreadChunk {
+                    thread_id,
+                    chunk_id,
+                } => thread::load_chunk(self, client, *thread_id, *chunk_id)?,
+                ClientMessage::ListThreads { channel_id } => {
+                    thread::list(self, client, channel_id)?
+                }
+
+                ClientMessage::Search {
+                    query,
+                    channel_id,
+                    from,
+                    since,
+                    until,
+                    before,
+                    limit,
+                } => {
+                    let filter = SearchFilter {
+                        from: from.as_deref(),
+                        since: *since,
+                        until: *until,
+                        before: *before,
+                        limit: *limit,
+                    };
+                    search::search(self, client, query, channel_id.as_deref(), &filter)?
+                }
+
+                ClientMessage::AddReaction { message_id, emoji } => {
+                    reaction::react(self, client, *message_id, emoji, true)?
+                }
+                ClientMessage::RemoveReaction { message_id, emoji } => {
+                    reaction::react(self, client, *message_id, emoji, false)?
+                }
 
                 ClientMessage::Typing { channel_id } => {
                     indicator::start_typing(self, client, channel_id)?
@@ -46,31 +140,145 @@ impl Server {
 
                 ClientMessage::JoinVoice { channel_id } => voice::join(self, client, channel_id)?,
                 ClientMessage::LeaveVoice { channel_id } => voice::leave(self, client, channel_id)?,
+
+                other => self.call_shared_request(other, client)?,
             },
 
-            WsMessage::Binary(data) => {
-                // Self::LOGGER.info(format!("Binary message: {data:?}"));
-                voice::voice(self, client, data)?;
+            WsMessage::Binary(data) => match upload::json_chunk(self, client, data) {
+                Some(chunk) => upload::chunk(self, client, chunk)?,
+                None => voice::voice(self, client, data)?,
+            },
+
+            WsMessage::Upload(chunk) => upload::chunk(self, client, chunk)?,
+
+            WsMessage::Malformed(reason) => {
+                return Err(RequestError::InvalidRequest(reason.clone()).into());
             }
+        }
+
+        Ok(None)
+    }
 
-            WsMessage::String(s) => {
-                Self::LOGGER.info(format!("String message: {s}"));
+    /// The requests handled the same way by servers and nodes
+    pub(crate) fn call_shared_request(
+        self: &Arc<Self>,
+        req: &ClientMessage,
+        client: &Client,
+    ) -> crate::Result<()> {
+        match req {
+            ClientMessage::BeginUpload {
+                name,
+                size,
+                content_type,
+            } => upload::begin(self, client, name, *size, content_type.clone())?,
+            ClientMessage::FinishUpload { upload_id } => upload::finish(self, client, *upload_id)?,
+            ClientMessage::CancelUpload { upload_id } => upload::cancel(self, client, *upload_id)?,
+
+            ClientMessage::SetStatus { status, text } => {
+                presence::set_status(self, client, *status, text.clone())?
+            }
+
+            ClientMessage::ListMembers {
+                online,
+                after,
+                limit,
+            } => member::list(self, client, *online, after.as_deref(), *limit)?,
+
+            ClientMessage::Resume {
+                session_id,
+                last_seq,
+            } => session::resume(self, client, session_id, *last_seq)?,
+
+            ClientMessage::ListRoles => role::list(self, client)?,
+            ClientMessage::AssignRole { user_id, role_id } => {
+                role::assign(self, Some(client), user_id, *role_id, true)?
+            }
+            ClientMessage::UnassignRole { user_id, role_id } => {
+                role::assign(self, Some(client), user_id, *role_id, false)?
+            }
+
+            ClientMessage::KickMember { user_id } => member::kick(self, Some(client), user_id)?,
+            ClientMessage::BanMember { user_id } => member::ban(self, Some(client), user_id)?,
+            ClientMessage::UnbanMember { user_id } => member::unban(self, user_id)?,
+
+            ClientMessage::CreateChannel { name, kind } => {
+                channel::create(self, name, kind.clone()).map(|_| ())?
+            }
+            ClientMessage::RenameChannel { channel_id, name } => {
+                channel::rename(self, channel_id, name)?
+            }
+            ClientMessage::MoveChannel {
+                channel_id,
+                position,
+            } => channel::move_to(self, channel_id, *position)?,
+            ClientMessage::SetChannelTopic { channel_id, topic } => {
+                channel::set_topic(self, channel_id, topic.clone())?
+            }
+            ClientMessage::SetChannelParent { channel_id, parent } => {
+                channel::set_parent(self, channel_id, parent.clone())?
+            }
+            ClientMessage::SetChannelNsfw { channel_id, nsfw } => {
+                channel::set_nsfw(self, channel_id, *nsfw)?
+            }
+            ClientMessage::SetSlowMode {
+                channel_id,
+                seconds,
+            } => channel::set_slow_mode(self, channel_id, *seconds)?,
+            ClientMessage::DeleteChannel { channel_id } => channel::delete(self, channel_id)?,
+
+            ClientMessage::ListOverwrites { channel_id } => {
+                overwrite::list(self, client, channel_id)?
+            }
+            ClientMessage::SetOverwrite {
+                channel_id,
+                target,
+                allow,
+                deny,
+            } => {
+                let overwrite = Overwrite {
+                    target: target.clone(),
+                    allow: *allow,
+                    deny: *deny,
+                };
+                overwrite::set(self, Some(client), channel_id, overwrite)?
+            }
+            ClientMessage::DeleteOverwrite { channel_id, target } => {
+                overwrite::delete(self, Some(client), channel_id, target)?
+            }
+
+            _ => {
+                return Err(RequestError::InvalidRequest(
+                    "Request isn't supported here".to_string(),
+                )
+                .into());
             }
         }
 
         Ok(())
     }
 
+    /// Send an event to everyone, events in a channel only to those who can see it
     pub fn broadcast(self: &Arc<Self>, msg: ServerMessage) {
-        for c in self.clients.lock().unwrap().iter() {
-            let c = c.clone();
-            let server = self.clone();
-            let msg = msg.clone();
-            std::thread::spawn(move || {
-                server
-                    .wrap_err(&c, c.send(msg))
-                    .expect("Failed to broadcast");
-            });
+        let msg = Arc::new(msg);
+        let channel_id = msg.channel_id();
+        let visible = |user_id: &str| channel_id.is_none_or(|c| self.can_view(user_id, c));
+
+        let (seq, clients) = self.sessions.record(&msg, visible, || {
+            self.clients
+                .lock()
+                .unwrap()
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>()
+        });
+        let event = Event { message: &msg, seq };
+        let encoded = Encoded::new(&event);
+
+        for c in clients
+            .into_iter()
+            .filter(|c| c.get_uuid().is_ok_and(|uuid| visible(&uuid)))
+        {
+            let _ = self.wrap_err(&c, c.send_encoded(&encoded));
         }
     }
 
@@ -79,19 +287,19 @@ impl Server {
         targets: &[&String],
         bytes: Vec<u8>,
     ) -> crate::Result<()> {
-        for c in self.clients.lock().unwrap().iter() {
-            if !targets.contains(&&c.get_uuid()?) {
-                continue;
-            }
+        let frames = Encoding::ALL.map(|e| e.encode_voice(&bytes));
 
-            let c = c.clone();
-            let server = self.clone();
-            let bytes = bytes.clone();
-            std::thread::spawn(move || {
-                server
-                    .wrap_err(&c, c.send_bin(&bytes))
-                    .expect("Failed to broadcast");
-            });
+        let clients: Vec<Client> = self
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| c.get_uuid().is_ok_and(|uuid| targets.contains(&&uuid)))
+            .cloned()
+            .collect();
+        for c in clients {
+            let frame = frames[c.encoding() as usize].clone();
+            let _ = self.wrap_err(&c, c.send_voice(frame));
         }
 
         Ok(())
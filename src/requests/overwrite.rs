@@ -0,0 +1,119 @@
+use std::sync::Arc;
+
+use crate::{
+    requests::{channel, error::RequestError},
+    server::Server,
+    types::{
+        data::{Overwrite, OverwriteTarget},
+        message::ServerMessage,
+    },
+    utils::{client::Client, permissions::Permissions},
+};
+
+crate::logger!(LOGGER "Overwrites");
+
+pub fn list(server: &Arc<Server>, client: &Client, channel_id: &str) -> crate::Result<()> {
+    check_channel(server, channel_id)?;
+    client.send(ServerMessage::Overwrites {
+        channel_id: channel_id.to_string(),
+        overwrites: of(server, channel_id),
+    })
+}
+
+/// Create or replace an overwrite, `by` is `None` when it's done from the CLI
+pub fn set(
+    server: &Arc<Server>,
+    by: Option<&Client>,
+    channel_id: &str,
+    overwrite: Overwrite,
+) -> crate::Result<()> {
+    check_channel(server, channel_id)?;
+    check_target(server, &overwrite.target)?;
+    check_grantable(server, by, channel_id, overwrite.allow | overwrite.deny)?;
+
+    server.db.set_overwrite(channel_id, &overwrite)?;
+    {
+        let mut overwrites = server.overwrites.lock().unwrap();
+        let overwrites = overwrites.entry(channel_id.to_string()).or_default();
+        overwrites.retain(|o| o.target != overwrite.target);
+        overwrites.push(overwrite);
+    }
+
+    changed(server, channel_id);
+    Ok(())
+}
+
+pub fn delete(
+    server: &Arc<Server>,
+    by: Option<&Client>,
+    channel_id: &str,
+    target: &OverwriteTarget,
+) -> crate::Result<()> {
+    check_channel(server, channel_id)?;
+    if let Some(o) = of(server, channel_id).iter().find(|o| o.target == *target) {
+        check_grantable(server, by, channel_id, o.allow | o.deny)?;
+    }
+
+    if !server.db.delete_overwrite(channel_id, target)? {
+        return Err(RequestError::NotFound("Overwrite does not exist".to_string()).into());
+    }
+    if let Some(overwrites) = server.overwrites.lock().unwrap().get_mut(channel_id) {
+        overwrites.retain(|o| o.target != *target);
+    }
+
+    changed(server, channel_id);
+    Ok(())
+}
+
+/// The overwrites of a channel
+pub fn of(server: &Arc<Server>, channel_id: &str) -> Vec<Overwrite> {
+    server
+        .overwrites
+        .lock()
+        .unwrap()
+        .get(channel_id)
+        .cloned()
+        .unwrap_or_default()
+}
+
+fn changed(server: &Arc<Server>, channel_id: &str) {
+    LOGGER.info(format!("Overwrites of {channel_id} changed"));
+    server.permissions_changed();
+    server.broadcast(ServerMessage::Overwrites {
+        channel_id: channel_id.to_string(),
+        overwrites: of(server, channel_id),
+    });
+}
+
+fn check_channel(server: &Arc<Server>, channel_id: &str) -> crate::Result<()> {
+    channel::find(server, channel_id).map(|_| ())
+}
+
+fn check_target(server: &Arc<Server>, target: &OverwriteTarget) -> crate::Result<()> {
+    if let OverwriteTarget::Role(role_id) = target
+        && server.db.get_role(*role_id)?.is_none()
+    {
+        return Err(RequestError::NotFound("Role does not exist".to_string()).into());
+    }
+    Ok(())
+}
+
+/// Otherwise `manage_channels` would be enough to give oneself any permission in the channel
+fn check_grantable(
+    server: &Arc<Server>,
+    by: Option<&Client>,
+    channel_id: &str,
+    permissions: Permissions,
+) -> crate::Result<()> {
+    if let Some(client) = by
+        && !server
+            .channel_permissions(&client.get_uuid()?, channel_id)?
+            .contains(permissions)
+    {
+        return Err(RequestError::Unauthorized(
+            "You can't manage an overwrite with permissions you don't have".to_string(),
+        )
+        .into());
+    }
+    Ok(())
+}
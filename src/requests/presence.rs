@@ -0,0 +1,44 @@
+use std::sync::Arc;
+
+use crate::{
+    requests::error::RequestError,
+    server::Server,
+    types::{
+        data::{Status, UserPresence},
+        message::ServerMessage,
+    },
+    utils::{client::Client, presence::MAX_TEXT_LEN},
+};
+
+pub fn set_status(
+    server: &Arc<Server>,
+    client: &Client,
+    status: Status,
+    text: Option<String>,
+) -> crate::Result<()> {
+    let user_id = client.get_uuid()?;
+
+    if text
+        .as_ref()
+        .is_some_and(|t| t.chars().count() > MAX_TEXT_LEN)
+    {
+        return Err(RequestError::InvalidRequest(format!(
+            "Status text can't be longer than {MAX_TEXT_LEN} characters"
+        ))
+        .into());
+    }
+
+    // Appearing offline hides the text too
+    let text = text.filter(|t| !t.is_empty() && status != Status::Offline);
+    let update = server
+        .presence
+        .lock()
+        .unwrap()
+        .set(&user_id, UserPresence { status, text });
+
+    if let Some(presence) = update {
+        server.broadcast(ServerMessage::PresenceUpdate { user_id, presence });
+    }
+
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use std::sync::Arc;
+
+use crate::{
+    requests::error::RequestError,
+    server::Server,
+    types::{data::Message, message::ServerMessage},
+    utils::{client::Client, permissions::Permissions},
+};
+
+/// Longest emoji, long enough for custom `:name:` ones
+pub const MAX_EMOJI_LEN: usize = 64;
+/// Most different emoji on a single message
+pub const MAX_REACTIONS: usize = 20;
+
+/// React with `emoji` (`added`) or take the reaction back
+pub fn react(
+    server: &Arc<Server>,
+    client: &Client,
+    message_id: i64,
+    emoji: &str,
+    added: bool,
+) -> crate::Result<()> {
+    let msg = find(server, message_id)?;
+    server.require(client, Some(&msg.channel_id), Permissions::VIEW_CHANNELS)?;
+
+    let user_id = client.get_uuid()?;
+    let Some(reaction) = change(server, &msg, emoji, &user_id, added)? else {
+        return Ok(());
+    };
+    server.broadcast(reaction);
+    Ok(())
+}
+
+pub fn find(server: &Arc<Server>, message_id: i64) -> crate::Result<Message> {
+    server
+        .db
+        .get_message_by_id(message_id)?
+        .ok_or_else(|| RequestError::NotFound("Message does not exist".to_string()).into())
+}
+
+/// Store the reaction, returns the event to broadcast if anything changed
+pub fn change(
+    server: &Arc<Server>,
+    msg: &Message,
+    emoji: &str,
+    user_id: &str,
+    added: bool,
+) -> crate::Result<Option<ServerMessage>> {
+    if !added {
+        if !server.db.delete_reaction(msg.id, emoji, user_id)? {
+            return Ok(None);
+        }
+        return Ok(Some(ServerMessage::ReactionRemove {
+            message_id: msg.id,
+            channel_id: msg.channel_id.clone(),
+            user_id: user_id.to_string(),
+            emoji: emoji.to_string(),
+        }));
+    }
+
+    let len = emoji.chars().count();
+    if len == 0 || len > MAX_EMOJI_LEN || emoji.chars().any(char::is_whitespace) {
+        return Err(RequestError::InvalidRequest(format!(
+            "Emoji must be 1 to {MAX_EMOJI_LEN} characters without spaces"
+        ))
+        .into());
+    }
+
+    let reactions = server.db.get_reactions(msg.id)?;
+    if reactions.len() >= MAX_REACTIONS && !reactions.iter().any(|r| r.emoji == emoji) {
+        return Err(RequestError::InvalidRequest(format!(
+            "Messages can't have more than {MAX_REACTIONS} different reactions"
+        ))
+        .into());
+    }
+
+    if !server.db.insert_reaction(msg.id, emoji, user_id)? {
+        return Ok(None);
+    }
+    Ok(Some(ServerMessage::ReactionAdd {
+        message_id: msg.id,
+        channel_id: msg.channel_id.clone(),
+        user_id: user_id.to_string(),
+        emoji: emoji.to_string(),
+    }))
+}
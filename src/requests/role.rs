@@ -0,0 +1,308 @@
+use std::sync::Arc;
+
+use crate::{
+    requests::error::RequestError,
+    server::Server,
+    types::{
+        data::{Channel, OverwriteTarget, Role},
+        message::{ClientMessage, ServerMessage, WsMessage},
+    },
+    utils::{
+        client::Client,
+        database::EVERYONE_ROLE,
+        permissions::{Grants, Permissions},
+    },
+};
+
+crate::logger!(LOGGER "Roles");
+
+/// What a request needs before it's handled, and the channel it's needed in.
+///
+/// Editing and deleting messages depend on the message's channel and author, so they're
+/// checked in `message::edit` and `message::delete`, loading a thread in `thread::load_chunk`
+/// and reactions in `reaction::react`. Searching every channel only looks through the ones the
+/// user can see.
+pub fn required(req: &WsMessage<ClientMessage>) -> (Permissions, Option<&str>) {
+    let WsMessage::Message(req) = req else {
+        // Voice without `speak` is dropped in the voice handler instead
+        return (Permissions::NONE, None);
+    };
+
+    match req {
+        ClientMessage::SendMessage { channel_id, .. }
+        | ClientMessage::Typing { channel_id }
+        | ClientMessage::CreateThread { channel_id, .. } => {
+            (Permissions::SEND_MESSAGES, Some(channel_id))
+        }
+        ClientMessage::LoadChunk { channel_id, .. }
+        | ClientMessage::LoadMessages { channel_id, .. }
+        | ClientMessage::ListThreads { channel_id }
+        | ClientMessage::ListOverwrites { channel_id } => {
+            (Permissions::VIEW_CHANNELS, Some(channel_id))
+        }
+        ClientMessage::Search {
+            channel_id: Some(channel_id),
+            ..
+        } => (Permissions::VIEW_CHANNELS, Some(channel_id)),
+        ClientMessage::JoinVoice { channel_id } => (Permissions::JOIN_VOICE, Some(channel_id)),
+        ClientMessage::SetOverwrite { channel_id, .. }
+        | ClientMessage::DeleteOverwrite { channel_id, .. }
+        | ClientMessage::RenameChannel { channel_id, .. }
+        | ClientMessage::MoveChannel { channel_id, .. }
+        | ClientMessage::SetChannelTopic { channel_id, .. }
+        | ClientMessage::SetChannelParent { channel_id, .. }
+        | ClientMessage::SetChannelNsfw { channel_id, .. }
+        | ClientMessage::SetSlowMode { channel_id, .. }
+        | ClientMessage::DeleteChannel { channel_id } => {
+            (Permissions::MANAGE_CHANNELS, Some(channel_id))
+        }
+        ClientMessage::CreateChannel { .. } => (Permissions::MANAGE_CHANNELS, None),
+        ClientMessage::AssignRole { .. } | ClientMessage::UnassignRole { .. } => {
+            (Permissions::MANAGE_ROLES, None)
+        }
+        ClientMessage::KickMember { .. } => (Permissions::KICK_MEMBERS, None),
+        ClientMessage::BanMember { .. } | ClientMessage::UnbanMember { .. } => {
+            (Permissions::BAN_MEMBERS, None)
+        }
+        ClientMessage::EditMessage { .. }
+        | ClientMessage::DeleteMessage { .. }
+        | ClientMessage::Search { .. }
+        | ClientMessage::LoadThreadChunk { .. }
+        | ClientMessage::AddReaction { .. }
+        | ClientMessage::RemoveReaction { .. }
+        | ClientMessage::BeginUpload { .. }
+        | ClientMessage::FinishUpload { .. }
+        | ClientMessage::CancelUpload { .. }
+        | ClientMessage::LeaveVoice { .. }
+        | ClientMessage::SetStatus { .. }
+        | ClientMessage::ListMembers { .. }
+        | ClientMessage::Resume { .. }
+        | ClientMessage::ListRoles => (Permissions::NONE, None),
+    }
+}
+
+impl Server {
+    /// The roles and server wide permissions of a user, cached until a role changes
+    pub fn grants(&self, user_id: &str) -> crate::Result<Grants> {
+        // Held across the queries so an invalidation can't be overwritten by a stale result
+        let mut cache = self.permission_cache.lock().unwrap();
+        if let Some(grants) = cache.get(user_id) {
+            return Ok(grants.clone());
+        }
+
+        let grants = Grants {
+            roles: self.db.get_user_roles(user_id)?,
+            permissions: self.db.get_user_permissions(user_id)?,
+        };
+        cache.insert(user_id.to_string(), grants.clone());
+        Ok(grants)
+    }
+
+    pub fn permissions(&self, user_id: &str) -> crate::Result<Permissions> {
+        Ok(self.grants(user_id)?.permissions)
+    }
+
+    /// The permissions of a user in a channel, after its overwrites
+    pub fn channel_permissions(
+        &self,
+        user_id: &str,
+        channel_id: &str,
+    ) -> crate::Result<Permissions> {
+        let grants = self.grants(user_id)?;
+        Ok(self.apply_overwrites(&grants, user_id, channel_id))
+    }
+
+    fn apply_overwrites(&self, grants: &Grants, user_id: &str, channel_id: &str) -> Permissions {
+        let overwrites = self.overwrites.lock().unwrap();
+        let overwrites = overwrites.get(channel_id).map(Vec::as_slice);
+        grants.in_channel(user_id, overwrites.unwrap_or_default())
+    }
+
+    /// Whether a user can see a channel, `false` if that couldn't be checked
+    pub fn can_view(&self, user_id: &str, channel_id: &str) -> bool {
+        self.channel_permissions(user_id, channel_id)
+            .is_ok_and(|p| p.contains(Permissions::VIEW_CHANNELS))
+    }
+
+    /// The channels a user can see
+    pub fn visible_channels(&self, user_id: &str) -> Vec<Channel> {
+        self.channels
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| self.can_view(user_id, &c.id))
+            .cloned()
+            .collect()
+    }
+
+    /// The channels the `everyone` role can see, for connections that haven't authenticated yet
+    pub fn public_channels(&self) -> crate::Result<Vec<Channel>> {
+        let Some(everyone) = self.db.get_role(EVERYONE_ROLE)? else {
+            return Ok(Vec::new());
+        };
+        let grants = Grants {
+            roles: Vec::new(),
+            permissions: everyone.permissions,
+        };
+
+        Ok(self
+            .channels
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| {
+                self.apply_overwrites(&grants, "", &c.id)
+                    .contains(Permissions::VIEW_CHANNELS)
+            })
+            .cloned()
+            .collect())
+    }
+
+    /// Fails with `Unauthorized` unless the client has every permission in `required`, in
+    /// `channel_id` if it's set
+    pub fn require(
+        &self,
+        client: &Client,
+        channel_id: Option<&str>,
+        required: Permissions,
+    ) -> crate::Result<()> {
+        if required == Permissions::NONE {
+            return Ok(());
+        }
+
+        let user_id = client.get_uuid()?;
+        let granted = match channel_id {
+            Some(channel_id) => self.channel_permissions(&user_id, channel_id)?,
+            None => self.permissions(&user_id)?,
+        };
+        if granted.contains(required) {
+            return Ok(());
+        }
+
+        Err(RequestError::Unauthorized(format!("Missing permission: {required}")).into())
+    }
+
+    /// Forget cached permissions after a role or overwrite changed, and tell every connection
+    /// which channels it can see now
+    pub fn permissions_changed(self: &Arc<Self>) {
+        self.permission_cache.lock().unwrap().clear();
+
+        let clients: Vec<Client> = self.clients.lock().unwrap().iter().cloned().collect();
+        for c in clients {
+            let Ok(user_id) = c.get_uuid() else {
+                continue;
+            };
+            let channels = self.visible_channels(&user_id);
+            let _ = self.wrap_err(&c, c.send(ServerMessage::Channels(channels)));
+        }
+    }
+}
+
+pub fn list(server: &Arc<Server>, client: &Client) -> crate::Result<()> {
+    client.send(ServerMessage::Roles(server.db.get_roles()?))
+}
+
+/// Give (`assigned`) or take a role, `by` is `None` when it's done from the CLI
+pub fn assign(
+    server: &Arc<Server>,
+    by: Option<&Client>,
+    user_id: &str,
+    role_id: i64,
+    assigned: bool,
+) -> crate::Result<()> {
+    if role_id == EVERYONE_ROLE {
+        return Err(
+            RequestError::InvalidRequest("Everyone has the everyone role".to_string()).into(),
+        );
+    }
+
+    let Some(role) = server.db.get_role(role_id)? else {
+        return Err(RequestError::NotFound("Role does not exist".to_string()).into());
+    };
+
+    // Otherwise `manage_roles` would be enough to become an administrator
+    if let Some(client) = by
+        && !server
+            .permissions(&client.get_uuid()?)?
+            .contains(role.permissions)
+    {
+        return Err(RequestError::Unauthorized(
+            "You can't manage a role with permissions you don't have".to_string(),
+        )
+        .into());
+    }
+
+    let changed = match assigned {
+        true => server.db.assign_role(user_id, role_id)?,
+        false => server.db.unassign_role(user_id, role_id)?,
+    };
+    if !changed {
+        return Ok(());
+    }
+
+    LOGGER.info(format!(
+        "{} role '{}' {} {user_id}",
+        if assigned { "Assigned" } else { "Unassigned" },
+        role.name,
+        if assigned { "to" } else { "from" },
+    ));
+    server.permissions_changed();
+    server.broadcast(ServerMessage::MemberRoles {
+        user_id: user_id.to_string(),
+        roles: server.db.get_user_roles(user_id)?,
+    });
+    Ok(())
+}
+
+pub fn create(server: &Arc<Server>, name: &str, permissions: Permissions) -> crate::Result<Role> {
+    if server.db.get_role_by_name(name)?.is_some() {
+        return Err(RequestError::InvalidRequest(format!("Role '{name}' already exists")).into());
+    }
+
+    let role = Role {
+        id: server.db.insert_role(name, permissions)?,
+        name: name.to_string(),
+        permissions,
+    };
+    server.broadcast(ServerMessage::RoleUpdate(role.clone()));
+    Ok(role)
+}
+
+pub fn set_permissions(
+    server: &Arc<Server>,
+    name: &str,
+    permissions: Permissions,
+) -> crate::Result<()> {
+    let mut role = by_name(server, name)?;
+    server.db.set_role_permissions(role.id, permissions)?;
+    server.permissions_changed();
+
+    role.permissions = permissions;
+    server.broadcast(ServerMessage::RoleUpdate(role));
+    Ok(())
+}
+
+pub fn delete(server: &Arc<Server>, name: &str) -> crate::Result<()> {
+    let role = by_name(server, name)?;
+    if role.id == EVERYONE_ROLE {
+        return Err(
+            RequestError::InvalidRequest("The everyone role can't be deleted".to_string()).into(),
+        );
+    }
+
+    server.db.delete_role(role.id)?;
+    let target = OverwriteTarget::Role(role.id);
+    for overwrites in server.overwrites.lock().unwrap().values_mut() {
+        overwrites.retain(|o| o.target != target);
+    }
+    server.permissions_changed();
+    server.broadcast(ServerMessage::RoleDelete { role_id: role.id });
+    Ok(())
+}
+
+pub fn by_name(server: &Arc<Server>, name: &str) -> crate::Result<Role> {
+    server
+        .db
+        .get_role_by_name(name)?
+        .ok_or_else(|| RequestError::NotFound(format!("Role '{name}' does not exist")).into())
+}
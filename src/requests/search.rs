@@ -0,0 +1,84 @@
+use std::sync::Arc;
+
+use crate::{
+    requests::{channel, error::RequestError},
+    server::Server,
+    types::{data::SearchHit, message::ServerMessage},
+    utils::{
+        client::Client,
+        database::{SearchFilter, SearchScope},
+    },
+};
+
+/// Longest search query
+pub const MAX_QUERY_LEN: usize = 256;
+/// Most hits in a single page
+pub const MAX_PAGE_SIZE: usize = 50;
+
+/// Search the channels the user can see, or only `channel_id`
+pub fn search(
+    server: &Arc<Server>,
+    client: &Client,
+    query: &str,
+    channel_id: Option<&str>,
+    filter: &SearchFilter,
+) -> crate::Result<()> {
+    let query = fts_query(query, filter.limit)?;
+    let channels: Vec<String> = match channel_id {
+        Some(channel_id) => vec![channel::find(server, channel_id)?.id],
+        None => server
+            .visible_channels(&client.get_uuid()?)
+            .into_iter()
+            .map(|c| c.id)
+            .collect(),
+    };
+
+    let hits = server
+        .db
+        .search(&query, &SearchScope::Channels(&channels), filter)?;
+    send(client, hits, filter.limit)
+}
+
+pub fn send(client: &Client, hits: Vec<SearchHit>, limit: usize) -> crate::Result<()> {
+    // A full page means there may be more
+    let next = (hits.len() == limit)
+        .then(|| hits.last().map(|h| h.message.id))
+        .flatten();
+    client.send(ServerMessage::SearchResults { hits, next })
+}
+
+/// Check a search and turn what the user typed into an FTS5 query, every word is quoted so it
+/// can't be read as query syntax
+pub fn fts_query(query: &str, limit: usize) -> crate::Result<String> {
+    if limit == 0 || limit > MAX_PAGE_SIZE {
+        return Err(RequestError::InvalidRequest(format!(
+            "limit must be between 1 and {MAX_PAGE_SIZE}"
+        ))
+        .into());
+    }
+    if query.chars().count() > MAX_QUERY_LEN {
+        return Err(RequestError::InvalidRequest(format!(
+            "Search queries can't be longer than {MAX_QUERY_LEN} characters"
+        ))
+        .into());
+    }
+
+    let terms: Vec<String> = query
+        .split_whitespace()
+        .filter_map(|word| {
+            let term = word.trim_end_matches('*');
+            if term.is_empty() {
+                return None;
+            }
+            let quoted = format!("\"{}\"", term.replace('"', "\"\""));
+            Some(match term.len() < word.len() {
+                true => quoted + "*",
+                false => quoted,
+            })
+        })
+        .collect();
+    if terms.is_empty() {
+        return Err(RequestError::InvalidRequest("Search query is empty".to_string()).into());
+    }
+    Ok(terms.join(" "))
+}
@@ -0,0 +1,35 @@
+use std::sync::Arc;
+
+use crate::{
+    server::Server,
+    types::message::{Event, ServerMessage},
+    utils::client::Client,
+};
+
+crate::logger!(LOGGER "Session");
+
+pub fn resume(
+    server: &Arc<Server>,
+    client: &Client,
+    session_id: &str,
+    last_seq: u64,
+) -> crate::Result<()> {
+    let missed = server.sessions.resume(
+        &client.get_session_id()?,
+        session_id,
+        &client.get_uuid()?,
+        last_seq,
+    )?;
+    LOGGER.info(format!(
+        "Resumed session, replaying {} events",
+        missed.len()
+    ));
+
+    for (seq, message) in &missed {
+        client.send(Event { message, seq: *seq })?;
+    }
+    client.send(ServerMessage::Resumed {
+        replayed: missed.len(),
+    })?;
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use std::sync::Arc;
+
+use crate::{
+    requests::{channel, error::RequestError},
+    server::Server,
+    types::{
+        data::{ChannelKind, Thread},
+        message::ServerMessage,
+    },
+    utils::{client::Client, permissions::Permissions},
+};
+
+crate::logger!(LOGGER "Threads");
+
+/// Get a thread by its id, a missing thread is a `NotFound` error
+pub fn find(server: &Arc<Server>, thread_id: i64) -> crate::Result<Thread> {
+    server
+        .db
+        .get_thread(thread_id)?
+        .ok_or_else(|| RequestError::NotFound("Thread does not exist".to_string()).into())
+}
+
+/// Start a thread at a message of the channel, or at a new message of `contents` in a forum
+pub fn create(
+    server: &Arc<Server>,
+    client: &Client,
+    channel_id: &str,
+    name: &str,
+    message_id: Option<i64>,
+    contents: Option<&str>,
+) -> crate::Result<Thread> {
+    let len = name.trim().chars().count();
+    if len == 0 || len > channel::MAX_NAME_LEN {
+        return Err(RequestError::InvalidRequest(format!(
+            "Thread names must be 1 to {} characters",
+            channel::MAX_NAME_LEN
+        ))
+        .into());
+    }
+
+    let channel = channel::find(server, channel_id)?;
+    let user_id = client.get_uuid()?;
+    let permissions = server.channel_permissions(&user_id, channel_id)?;
+    channel::check_send(&channel, permissions, true)?;
+
+    let root = match (message_id, contents) {
+        (Some(message_id), None) => {
+            let Some(msg) = server.db.get_message_by_id(message_id)? else {
+                return Err(RequestError::NotFound("Message does not exist".to_string()).into());
+            };
+            if msg.channel_id != channel_id || msg.thread_id.is_some() {
+                return Err(RequestError::InvalidRequest(
+                    "Threads can only start at a message of the channel".to_string(),
+                )
+                .into());
+            }
+            if server.db.get_thread(message_id)?.is_some() {
+                return Err(RequestError::InvalidRequest(
+                    "The message already has a thread".to_string(),
+                )
+                .into());
+            }
+            msg
+        }
+        // The first post of a forum thread is the only message outside of threads
+        (None, Some(contents)) if matches!(channel.kind, ChannelKind::Forum) => {
+            if contents.is_empty() {
+                return Err(RequestError::InvalidRequest(
+                    "Invalid message: empty message".to_string(),
+                )
+                .into());
+            }
+            let reserved = channel::reserve_slow_mode(server, &channel, &user_id, permissions)?;
+            let msg = server
+                .db
+                .insert_message(
+                    channel_id,
+                    &user_id,
+                    contents,
+                    chrono::Utc::now().timestamp(),
+                    None,
+                    None,
+                )
+                .inspect_err(|_| {
+                    if let Some(reserved) = reserved {
+                        channel::release_slow_mode(server, channel_id, &user_id, reserved);
+                    }
+                })?;
+            server.broadcast(ServerMessage::MessageCreate(msg.clone()));
+            msg
+        }
+        (None, Some(_)) => {
+            return Err(RequestError::InvalidRequest(
+                "Only forum threads start with a new message".to_string(),
+            )
+            .into());
+        }
+        _ => {
+            return Err(RequestError::InvalidRequest(
+                "Threads start at either a message_id or new contents".to_string(),
+            )
+            .into());
+        }
+    };
+
+    let thread = Thread {
+        id: root.id,
+        channel_id: channel_id.to_string(),
+        name: name.trim().to_string(),
+        from: user_id,
+        timestamp: chrono::Utc::now().timestamp(),
+    };
+    server.db.insert_thread(&thread)?;
+
+    LOGGER.info(format!(
+        "Created thread '{}' ({}) in {channel_id}",
+        thread.name, thread.id
+    ));
+    server.broadcast(ServerMessage::ThreadCreate(thread.clone()));
+    Ok(thread)
+}
+
+pub fn load_chunk(
+    server: &Arc<Server>,
+    client: &Client,
+    thread_id: i64,
+    chunk_id: usize,
+) -> crate::Result<()> {
+    let thread = find(server, thread_id)?;
+    server.require(client, Some(&thread.channel_id), Permissions::VIEW_CHANNELS)?;
+
+    let chunk = server.db.get_thread_chunk(thread_id, chunk_id)?;
+    client.send(ServerMessage::Chunk(chunk))
+}
+
+pub fn list(server: &Arc<Server>, client: &Client, channel_id: &str) -> crate::Result<()> {
+    client.send(ServerMessage::Threads {
+        channel_id: channel_id.to_string(),
+        threads: server.db.get_threads(channel_id)?,
+    })
+}
@@ -0,0 +1,119 @@
+use std::sync::Arc;
+
+use crate::{
+    requests::error::RequestError,
+    server::Server,
+    types::{data::Attachment, message::ServerMessage},
+    utils::{
+        client::Client,
+        encoding::{Encoding, UPLOAD_TAG},
+    },
+};
+
+crate::logger!(LOGGER "Uploads");
+
+pub fn begin(
+    server: &Arc<Server>,
+    client: &Client,
+    name: &str,
+    size: u64,
+    content_type: Option<String>,
+) -> crate::Result<()> {
+    let upload_id = server
+        .uploads
+        .begin(&client.get_session_id()?, name, size, content_type)?;
+    client.send(ServerMessage::UploadStarted { upload_id })
+}
+
+/// Append a chunk, the upload id followed by the data
+pub fn chunk(server: &Arc<Server>, client: &Client, chunk: &[u8]) -> crate::Result<()> {
+    let Some((upload_id, data)) = split_chunk(chunk) else {
+        return Err(RequestError::InvalidRequest("Invalid upload chunk".to_string()).into());
+    };
+    server
+        .uploads
+        .write(&client.get_session_id()?, upload_id, data)
+}
+
+/// On JSON connections binary frames are voice, unless they're tagged with an upload in progress
+pub fn json_chunk<'a>(server: &Arc<Server>, client: &Client, data: &'a [u8]) -> Option<&'a [u8]> {
+    if client.encoding() != Encoding::Json {
+        return None;
+    }
+    let (&UPLOAD_TAG, chunk) = data.split_first()? else {
+        return None;
+    };
+    let (upload_id, _) = split_chunk(chunk)?;
+    let session_id = client.get_session_id().ok()?;
+    server
+        .uploads
+        .is_open(&session_id, upload_id)
+        .then_some(chunk)
+}
+
+pub fn finish(server: &Arc<Server>, client: &Client, upload_id: u32) -> crate::Result<()> {
+    let user_id = client.get_uuid()?;
+    let attachment = server
+        .uploads
+        .finish(&client.get_session_id()?, upload_id, |stored| {
+            Ok(server
+                .db
+                .insert_attachment(stored, &user_id, chrono::Utc::now().timestamp())?)
+        })?;
+
+    LOGGER.info(format!(
+        "{user_id} uploaded '{}' ({} bytes)",
+        attachment.name, attachment.size
+    ));
+    client.send(ServerMessage::UploadFinished {
+        upload_id,
+        attachment,
+    })
+}
+
+pub fn cancel(server: &Arc<Server>, client: &Client, upload_id: u32) -> crate::Result<()> {
+    server.uploads.cancel(&client.get_session_id()?, upload_id)
+}
+
+/// The attachments a user is sending with a message, every one has to be a finished upload of
+/// theirs that wasn't sent yet
+pub fn attachments(
+    server: &Arc<Server>,
+    user_id: &str,
+    ids: &[i64],
+) -> crate::Result<Vec<Attachment>> {
+    let max = server.config.attachments.max_per_message;
+    if ids.len() > max {
+        return Err(RequestError::InvalidRequest(format!(
+            "Messages can't have more than {max} attachments"
+        ))
+        .into());
+    }
+
+    let mut attachments: Vec<Attachment> = Vec::with_capacity(ids.len());
+    for &id in ids {
+        if attachments.iter().any(|a| a.id == id) {
+            continue;
+        }
+        let Some(attachment) = server.db.get_unsent_attachment(id, user_id)? else {
+            return Err(RequestError::NotFound(format!(
+                "Attachment {id} does not exist or was already sent"
+            ))
+            .into());
+        };
+        attachments.push(attachment);
+    }
+    Ok(attachments)
+}
+
+/// Remove the files of deleted attachments that nothing else refers to
+pub fn remove_unused(server: &Arc<Server>, hashes: &[String]) {
+    server
+        .uploads
+        .remove_unused(hashes, |hash| Ok(server.db.is_attachment_used(hash)?));
+}
+
+fn split_chunk(chunk: &[u8]) -> Option<(u32, &[u8])> {
+    let (id, data) = chunk.split_first_chunk::<4>()?;
+    Some((u32::from_le_bytes(*id), data))
+}
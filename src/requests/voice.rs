@@ -1,9 +1,15 @@
-use crate::{server::Server, utils::client::Client};
+use crate::{
+    requests::channel,
+    server::Server,
+    types::data::ChannelKind,
+    utils::{client::Client, permissions::Permissions},
+};
 use std::sync::Arc;
 
 crate::logger!(LOGGER "Voice chat");
 
 pub fn join(server: &Arc<Server>, client: &Client, channel_id: &str) -> crate::Result<()> {
+    channel::find_voice(server, channel_id)?;
     let user_id = client.get_uuid()?;
 
     let voice_id = server
@@ -15,7 +21,7 @@ pub fn join(server: &Arc<Server>, client: &Client, channel_id: &str) -> crate::R
     server.broadcast(crate::types::message::ServerMessage::VoiceJoin {
         user_id,
         channel_id: channel_id.to_string(),
-        voice_id: voice_id,
+        voice_id,
     });
 
     Ok(())
@@ -38,16 +44,35 @@ pub fn leave(server: &Arc<Server>, client: &Client, channel_id: &str) -> crate::
 }
 
 pub fn voice(server: &Arc<Server>, client: &Client, data: &[u8]) -> crate::Result<()> {
-    let v = server.voice.lock().unwrap();
     let user_id = client.get_uuid()?;
-    let Some((channel_id, voice_id)) = v.find_user(&user_id) else {
-        return Ok(());
+
+    // Collect the targets first, broadcasting may need the voice lock to drop dead clients
+    let (channel_id, targets, voice_id) = {
+        let v = server.voice.lock().unwrap();
+        let Some((channel_id, voice_id)) = v.find_user(&user_id) else {
+            return Ok(());
+        };
+
+        let targets: Vec<String> = v
+            .get(channel_id)
+            .into_iter()
+            .filter(|x| **x != user_id)
+            .cloned()
+            .collect();
+        (channel_id.clone(), targets, voice_id)
     };
 
-    let mut targets = v.get(&channel_id);
+    // Dropped silently, answering every frame would flood the client
+    let permissions = server.channel_permissions(&user_id, &channel_id)?;
+    if !permissions.contains(Permissions::SPEAK) {
+        return Ok(());
+    }
 
-    if let Some(pos) = targets.iter().position(|x| *x == &user_id) {
-        targets.remove(pos);
+    // Listeners on a stage aren't heard
+    if channel::get(server, &channel_id).is_some_and(|c| c.kind == ChannelKind::Stage)
+        && !permissions.contains(Permissions::SPEAK_ON_STAGE)
+    {
+        return Ok(());
     }
 
     let prefix = voice_id.to_le_bytes();
@@ -55,7 +80,7 @@ pub fn voice(server: &Arc<Server>, client: &Client, data: &[u8]) -> crate::Resul
     payload.extend_from_slice(&prefix);
     payload.extend_from_slice(data);
 
-    server.broadcast_bin_to(&targets, payload)?;
+    server.broadcast_bin_to(&targets.iter().collect::<Vec<_>>(), payload)?;
 
     Ok(())
 }
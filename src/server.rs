@@ -1,13 +1,23 @@
 use std::{
-    collections::HashSet,
-    net::{TcpListener, TcpStream},
+    collections::{HashMap, HashSet},
     path::{Path, PathBuf},
     sync::{
         Arc, Mutex,
         atomic::{AtomicBool, Ordering},
     },
+    time::{Duration, Instant},
 };
 
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+
+/// How long shutdown waits for clients to receive the shutdown message
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+/// How long a client has to complete the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
 const WELCOME: &str = "\x1b[38;2;169;86;252m
     _          _                 
    / \\   __  _(_) ___  _ __ ___  
@@ -19,21 +29,48 @@ const WELCOME: &str = "\x1b[38;2;169;86;252m
 use crate::{
     cli, logger,
     plugin::{Plugin, loader::PluginLoader, types::LoaderMessage},
-    requests::voice,
+    requests::{error::RequestError, indicator::Indicator, member, voice},
     types::{
         self,
+        data::Overwrite,
         message::{ClientMessage, WsMessage},
     },
-    utils::{self, auth, client::Client, voice::Voice},
+    utils::{
+        self,
+        attachments::{AttachmentConfig, Uploads},
+        auth::{self, AuthConfig, AuthProvider},
+        client::{Client, ConnectionConfig},
+        permissions::Grants,
+        presence::Presence,
+        session::Sessions,
+        tls::{Tls, TlsConfig},
+        voice::Voice,
+    },
 };
 
+logger!(LOGGER "Server");
+
+/// Handles a single client request, returns the id of the message it created if any
+pub type RequestHandler =
+    fn(&Arc<Server>, &WsMessage<ClientMessage>, &Client) -> crate::Result<Option<i64>>;
+
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct ServerConfig {
     pub server_name: String,
     pub server_id: String,
     pub server_key: String,
     pub port: u16,
+    /// Only read when the database is created, channels are managed at runtime after that
+    #[serde(default)]
     pub channels: Vec<types::data::Channel>,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub connection: ConnectionConfig,
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub attachments: AttachmentConfig,
 }
 
 pub struct Server {
@@ -41,21 +78,39 @@ pub struct Server {
     pub config: ServerConfig,
     pub clients: Mutex<HashSet<Client>>,
     pub plugins: Mutex<Vec<Plugin>>,
-    pub db: utils::database::Database,
+    pub db: Arc<utils::database::Database>,
     pub shutting_down: AtomicBool,
     pub indicators: Mutex<Vec<crate::requests::indicator::IndicatorContext>>,
+    pub sessions: Sessions,
     pub voice: Mutex<crate::utils::voice::Voice>,
-    pub call_request: fn(&Arc<Self>, &WsMessage<ClientMessage>, &Client) -> crate::Result<()>,
+    pub presence: Mutex<Presence>,
+    /// Roles and permissions of users by id, cleared whenever a role changes
+    pub permission_cache: Mutex<HashMap<String, Grants>>,
+    /// Every channel ordered by position, kept in sync with the database
+    pub channels: Mutex<Vec<types::data::Channel>>,
+    /// When users last sent a message in a channel with slow mode, by user and channel id
+    pub slow_mode: Mutex<HashMap<(String, String), Instant>>,
+    /// Permission overwrites by channel id
+    pub overwrites: Mutex<HashMap<String, Vec<Overwrite>>>,
+    /// Uploads in progress, by upload id
+    pub uploads: Uploads,
+    pub auth: Box<dyn AuthProvider>,
+    pub tls: Option<Arc<Tls>>,
+    pub call_request: RequestHandler,
 }
 
 impl Default for ServerConfig {
     fn default() -> Self {
         Self {
             port: 7080,
-            server_name: format!("Server Name"),
-            server_id: format!("important"),
-            server_key: format!("important"),
+            server_name: "Server Name".to_string(),
+            server_id: "important".to_string(),
+            server_key: "important".to_string(),
             channels: Vec::new(),
+            auth: AuthConfig::default(),
+            connection: ConnectionConfig::default(),
+            tls: None,
+            attachments: AttachmentConfig::default(),
         }
     }
 }
@@ -65,11 +120,7 @@ impl ServerConfig {
         Server::new_config(root, self)
     }
 
-    pub fn build_req(
-        self,
-        root: &Path,
-        req: fn(&Arc<Server>, &WsMessage<ClientMessage>, &Client) -> crate::Result<()>,
-    ) -> Arc<Server> {
+    pub fn build_req(self, root: &Path, req: RequestHandler) -> Arc<Server> {
         Server::new_req_config(root, req, self)
     }
 
@@ -79,15 +130,42 @@ impl ServerConfig {
 }
 
 impl Server {
-    logger!(LOGGER "Server");
-
     pub fn new_req_config(
         root: &Path,
-        call_request: fn(&Arc<Self>, &WsMessage<ClientMessage>, &Client) -> crate::Result<()>,
+        call_request: RequestHandler,
         config: ServerConfig,
     ) -> Arc<Self> {
+        let db = Arc::new(
+            utils::database::Database::new("main.db", &config).expect("Failed to open main.db"),
+        );
+        let channels = db.get_channels().expect("Failed to load channels");
+        let mut overwrites: HashMap<String, Vec<Overwrite>> = HashMap::new();
+        for (channel_id, overwrite) in db.get_overwrites().expect("Failed to load overwrites") {
+            overwrites.entry(channel_id).or_default().push(overwrite);
+        }
+
+        // Uploads that were never sent are forgotten after a day
+        db.prune_attachments(chrono::Utc::now().timestamp() - 24 * 60 * 60)
+            .expect("Failed to prune attachments");
+        let uploads = Uploads::new(
+            root,
+            config.attachments.clone(),
+            &db.get_attachment_hashes()
+                .expect("Failed to load attachments"),
+        )
+        .expect("Failed to initialize attachments");
+
         Arc::new(Self {
-            db: utils::database::Database::new(&config).unwrap(),
+            auth: config
+                .auth
+                .build(root, &config, &db)
+                .expect("Failed to initialize auth provider"),
+            tls: config
+                .tls
+                .as_ref()
+                .map(|tls| Arc::new(Tls::load(root, tls).expect("Failed to load TLS certificate"))),
+            db,
+            sessions: Sessions::new(&config.connection),
             root: root.to_path_buf(),
             config,
             clients: Mutex::new(HashSet::new()),
@@ -95,116 +173,209 @@ impl Server {
             shutting_down: AtomicBool::new(false),
             indicators: Mutex::new(Vec::new()),
             voice: Mutex::new(Voice::new()),
-            call_request: call_request,
+            presence: Mutex::new(Presence::new()),
+            permission_cache: Mutex::new(HashMap::new()),
+            channels: Mutex::new(channels),
+            overwrites: Mutex::new(overwrites),
+            slow_mode: Mutex::new(HashMap::new()),
+            uploads,
+            call_request,
         })
     }
 
     pub fn new_config(root: &Path, config: ServerConfig) -> Arc<Self> {
-        Arc::new(Self {
-            db: utils::database::Database::new(&config).unwrap(),
-            root: root.to_path_buf(),
-            config,
-            clients: Mutex::new(HashSet::new()),
-            plugins: Mutex::new(Vec::new()),
-            shutting_down: AtomicBool::new(false),
-            indicators: Mutex::new(Vec::new()),
-            voice: Mutex::new(Voice::new()),
-            call_request: Self::call_server_request,
-        })
+        Self::new_req_config(root, Self::call_server_request, config)
     }
 
-    pub fn run(self: &Arc<Self>) -> crate::Result<()> {
+    pub async fn run(self: &Arc<Self>) -> crate::Result<()> {
         // Start plugin loader
         let plugin_loader = PluginLoader::new();
-        Self::LOGGER.info("Starting loader");
-        plugin_loader.start_server();
+        LOGGER.info("Starting loader");
+        plugin_loader.start_server().await?;
 
         // Load plugins
-        plugin_loader.load_all(self);
+        plugin_loader.load_all(self).await;
 
         // Initialize plugins
-        Self::LOGGER.info("Initializing plugins");
-
-        Self::LOGGER.info("Authenticating");
-        auth::test(self)?;
+        LOGGER.info("Initializing plugins");
+
+        LOGGER.info("Authenticating");
+        let srv = self.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || auth::test(&srv)).await? {
+            LOGGER.warn(format!(
+                "Auth provider unreachable, clients may fail to authenticate: {e:#}"
+            ));
+        }
 
         // Initialize indicators
-        Self::LOGGER.info("Initializing indicators");
-        self.spawn_indicator_thread();
+        LOGGER.info("Initializing indicators");
+        self.spawn_indicator_task();
+
+        // Watch the certificate for renewals
+        if let Some(tls) = &self.tls {
+            tls.spawn_reload_task();
+        }
 
         // Initialize CLI
-        Self::LOGGER.info("Initializing CLI");
+        LOGGER.info("Initializing CLI");
         cli::start_cli(self.clone(), plugin_loader);
 
         // Start server
-        let listener = TcpListener::bind(format!("0.0.0.0:{}", self.config.port))?;
-        Self::LOGGER.info(format!("Server listening at 0.0.0.0:{}", self.config.port));
+        let listener = TcpListener::bind(format!("0.0.0.0:{}", self.config.port)).await?;
+        LOGGER.info(format!(
+            "Server listening at {}://0.0.0.0:{}",
+            if self.tls.is_some() { "wss" } else { "ws" },
+            self.config.port
+        ));
 
         println!(
             "{WELCOME}\nversion {}\nType 'help' to see available commands.",
             env!("CARGO_PKG_VERSION")
         );
 
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    std::thread::spawn({
-                        let srv = self.clone();
-
-                        move || {
-                            let Some(client) = Self::LOGGER
-                                .extract(srv.init_client(stream), "Failed to initialize client")
-                            else {
-                                return;
-                            };
-
-                            Self::LOGGER.extract(
-                                srv.wrap_err(&client, srv.handle_client(&client)),
-                                "Client handler failed",
-                            );
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    let srv = self.clone();
+                    tokio::spawn(async move {
+                        LOGGER.info(format!("New connection: {addr}"));
+                        let Some(tls) = &srv.tls else {
+                            return srv.serve(stream).await;
+                        };
+
+                        let accept = tls.acceptor().accept(stream);
+                        match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, accept).await {
+                            Ok(Ok(stream)) => srv.serve(stream).await,
+                            Ok(Err(e)) => LOGGER.warn(format!("TLS handshake failed: {e}")),
+                            Err(_) => LOGGER.warn(format!("TLS handshake timed out: {addr}")),
                         }
                     });
                 }
                 Err(e) => {
-                    Self::LOGGER.error(format!("Connection failed: {e}"));
+                    LOGGER.error(format!("Connection failed: {e}"));
                 }
             }
         }
+    }
 
-        Ok(())
+    /// Run a connection from the WebSocket handshake until it disconnects
+    async fn serve<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+        self: &Arc<Self>,
+        stream: S,
+    ) {
+        let Some(client) = LOGGER
+            .extract(
+                self.init_client(stream).await,
+                "Failed to initialize client",
+            )
+            .flatten()
+        else {
+            return;
+        };
+
+        // Errors were already reported to the client where they happened
+        LOGGER.extract(self.handle_client(&client).await, "Client handler failed");
+        self.disconnect(&client);
     }
 
-    fn init_client(self: &Arc<Self>, stream: TcpStream) -> crate::Result<Client> {
-        Self::LOGGER.info(format!("New connection: {}", stream.peer_addr()?));
+    /// Returns `None` if the connection was a plain HTTP request or closed during the handshake
+    async fn init_client<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+        self: &Arc<Self>,
+        stream: S,
+    ) -> crate::Result<Option<Client>> {
         // Initialize client
-        let mut client = Client::new(stream)?;
-
-        // Initialize handshake
+        let Some(mut client) = Client::new(stream, &self.config.connection, Some(self)).await?
+        else {
+            return Ok(None);
+        };
+
+        // Initialize handshake, who the client is isn't known yet
+        let channels = utils::blocking(|| self.public_channels());
+        let channels = self.wrap_err(&client, channels)?;
         self.wrap_err(
             &client,
             client.send(types::handshake::ServerDetails {
                 name: self.config.server_name.clone(),
                 id: self.config.server_id.clone(),
-                version: format!("0.0.1"),
-                channels: self.config.channels.clone(),
+                version: "0.0.1".to_string(),
+                channels,
             }),
         )?;
 
-        match self.wrap_err(&client, client.read_t::<types::handshake::ClientDetails>())? {
+        let details = client.read_t::<types::handshake::ClientDetails>().await;
+        match self.wrap_err(&client, details)? {
             Some(types::message::WsMessage::Message(types::handshake::ClientDetails {
                 auth_token,
+                login,
                 ..
             })) => {
-                let auth_res = utils::auth::auth(self, &mut client, &auth_token);
-                let uuid = self.wrap_err(&client, auth_res)?;
-                self.wrap_err(
-                    &client,
-                    client.send(types::message::ServerMessage::Authenticated {
-                        uuid,
-                        indicators: self.indicators.lock().unwrap().clone(),
-                        voice_chat: self.voice.lock().unwrap().get_connections(),
-                    }),
-                )?;
+                let (uuid, session_token) = match login {
+                    Some(login) => {
+                        let login_res = utils::auth::login(self, &mut client, &login).await;
+                        let session = self.wrap_auth_err(&client, login_res)?;
+                        (session.user_id, Some(session.token))
+                    }
+                    None => {
+                        let auth_res = utils::auth::auth(self, &mut client, &auth_token).await;
+                        (self.wrap_auth_err(&client, auth_res)?, None)
+                    }
+                };
+                let banned = utils::blocking(|| self.db.is_banned(&uuid));
+                if self.wrap_err(&client, banned)? {
+                    let rejected = auth::Rejected("You are banned from this server".to_string());
+                    self.wrap_auth_err::<()>(&client, Err(rejected.into()))?;
+                }
+                let permissions = utils::blocking(|| self.permissions(&uuid));
+                let permissions = self.wrap_err(&client, permissions)?;
+
+                let channels = utils::blocking(|| self.visible_channels(&uuid));
+                let mut indicators = self.indicators.lock().unwrap().clone();
+                indicators.retain(|i| {
+                    let Indicator::Typing { channel_id, .. } = &i.indicator;
+                    self.can_view(&uuid, channel_id)
+                });
+                let mut voice_chat = self.voice.lock().unwrap().get_connections();
+                voice_chat.retain(|channel_id, _| self.can_view(&uuid, channel_id));
+                let came_online = self.presence.lock().unwrap().connect(&uuid);
+                let presence = self.presence.lock().unwrap().snapshot();
+
+                // Going live can't be split from the sequence number the client is told about
+                let sent = self.sessions.start(&uuid, |session_id, seq| {
+                    client.set_session_id(session_id);
+                    let sent = client.send(types::message::ServerMessage::Authenticated {
+                        uuid: uuid.clone(),
+                        session_token,
+                        session_id: session_id.to_string(),
+                        seq,
+                        indicators,
+                        voice_chat,
+                        presence,
+                        permissions,
+                        channels,
+                    });
+                    if sent.is_ok() {
+                        // Insert to the set of all connected clients
+                        self.clients.lock().unwrap().insert(client.clone());
+                    }
+                    sent
+                });
+                if sent.is_err() {
+                    // Never went live, so there's nothing to announce or resume
+                    self.presence.lock().unwrap().disconnect(&uuid);
+                    if let Ok(session_id) = client.get_session_id() {
+                        self.sessions.detach(&session_id);
+                    }
+                } else {
+                    let joined = utils::blocking(|| member::join(self, &uuid));
+                    LOGGER.extract(joined, "Couldn't add member");
+                    if let Some(presence) = came_online {
+                        self.broadcast(types::message::ServerMessage::PresenceUpdate {
+                            user_id: uuid,
+                            presence,
+                        });
+                    }
+                }
+                self.wrap_err(&client, sent)?;
             }
             Some(v) => {
                 self.wrap_err(
@@ -213,39 +384,98 @@ impl Server {
                         "Invalid handshake: {v:?}"
                     ))),
                 )?;
+                client.close()?;
+                return Ok(None);
             }
-            None => {}
+            None => return Ok(None),
         }
 
-        // Insert to the set of all connected clients
-        self.clients.lock().unwrap().insert(client.clone());
-
-        Ok(client)
+        Ok(Some(client))
     }
 
-    fn handle_client(self: &Arc<Self>, client: &Client) -> crate::Result<()> {
+    async fn handle_client(self: &Arc<Self>, client: &Client) -> crate::Result<()> {
         // The main req/res loop
         while !self.shutting_down.load(Ordering::SeqCst) {
-            let req = client.read()?;
-            if let Some(r) = &req {
-                match r {
-                    WsMessage::Binary(_) => {
-                        // ignore binary
-                    }
-                    _ => {
+            let Some(r) = client.read().await? else {
+                break;
+            };
+            let (r, request_id) = r.into_parts();
+
+            match &r {
+                WsMessage::Binary(_) | WsMessage::Upload(_) | WsMessage::Malformed(_) => {
+                    // ignore binary and what couldn't be decoded
+                }
+                _ => {
+                    self.wrap_err(
+                        client,
                         self.send_plugin_message(&LoaderMessage::Request {
                             user_id: client.get_uuid().unwrap_or_default(),
                             msg: r.clone(),
-                        })?;
-                    }
+                        }),
+                    )?;
                 }
-
-                self.wrap_err(&client, (self.call_request)(self, r, &client))?;
             }
+
+            let sent = match utils::blocking(|| (self.call_request)(self, &r, client)) {
+                Ok(message_id) => match request_id {
+                    Some(request_id) => client.send(types::message::ServerMessage::Ack {
+                        request_id,
+                        message_id,
+                    }),
+                    None => Ok(()),
+                },
+                Err(e) => match e.downcast::<RequestError>() {
+                    // The client made a mistake, tell it and keep going
+                    Ok(e) => Self::send_error(client, request_id, e.into()),
+                    Err(e) => {
+                        self.clients.lock().unwrap().remove(client);
+                        let error = types::message::ResponseError::InternalError(e.to_string());
+                        if Self::send_error(client, request_id, error).is_err() {
+                            self.leave_voice(client);
+                        }
+                        return Err(e);
+                    }
+                },
+            };
+            self.wrap_err(client, sent)?;
         }
         Ok(())
     }
 
+    /// Send an error, attributed to the request that caused it when it has an id
+    fn send_error(
+        client: &Client,
+        request_id: Option<String>,
+        error: types::message::ResponseError,
+    ) -> crate::Result<()> {
+        match request_id {
+            Some(request_id) => {
+                client.send(types::message::ServerMessage::Error { request_id, error })
+            }
+            None => client.send(error),
+        }
+    }
+
+    /// Rejected credentials get `Unauthorized` and a close, anything else goes through [`Self::wrap_err`]
+    fn wrap_auth_err<T>(
+        self: &Arc<Self>,
+        client: &Client,
+        res: crate::Result<T>,
+    ) -> crate::Result<T> {
+        if let Err(e) = &res
+            && let Some(rejected) = e.downcast_ref::<auth::Rejected>()
+        {
+            LOGGER.info(format!("Authentication rejected: {rejected}"));
+            let _ = client.send(types::message::ResponseError::Unauthorized(
+                rejected.to_string(),
+            ));
+            let _ = client.send_close(1008, "Unauthorized");
+            return res;
+        }
+
+        self.wrap_err(client, res)
+    }
+
     /// When there is a error it removes the client
     pub fn wrap_err<T, E: std::fmt::Display>(
         self: &Arc<Self>,
@@ -253,30 +483,57 @@ impl Server {
         res: std::result::Result<T, E>,
     ) -> std::result::Result<T, E> {
         if let Err(e) = &res {
-            self.clients.lock().unwrap().remove(&client);
+            self.clients.lock().unwrap().remove(client);
             if client
                 .send(types::message::ResponseError::InternalError(e.to_string()))
                 .is_err()
             {
-                let Ok(user_id) = client.get_uuid() else {
-                    return res;
-                };
-
-                let channel_id = {
-                    let v = self.voice.lock().unwrap();
-                    let Some((channel_id, _)) = v.find_user(&user_id) else {
-                        return res;
-                    };
-                    channel_id.clone()
-                };
-
-                voice::leave(self, client, &channel_id).unwrap();
+                self.leave_voice(client);
             }
         }
 
         res
     }
 
+    /// Forget a client whose connection ended
+    pub fn disconnect(self: &Arc<Self>, client: &Client) {
+        self.clients.lock().unwrap().remove(client);
+        if let Ok(session_id) = client.get_session_id() {
+            self.sessions.detach(&session_id);
+            self.uploads.cancel_all(&session_id);
+        }
+        self.leave_voice(client);
+        self.leave_presence(client);
+        let _ = client.close();
+    }
+
+    fn leave_presence(self: &Arc<Self>, client: &Client) {
+        let Ok(user_id) = client.get_uuid() else {
+            return;
+        };
+
+        let update = self.presence.lock().unwrap().disconnect(&user_id);
+        if let Some(presence) = update {
+            self.broadcast(types::message::ServerMessage::PresenceUpdate { user_id, presence });
+        }
+    }
+
+    fn leave_voice(self: &Arc<Self>, client: &Client) {
+        let Ok(user_id) = client.get_uuid() else {
+            return;
+        };
+
+        let channel_id = {
+            let v = self.voice.lock().unwrap();
+            let Some((channel_id, _)) = v.find_user(&user_id) else {
+                return;
+            };
+            channel_id.clone()
+        };
+
+        voice::leave(self, client, &channel_id).unwrap();
+    }
+
     pub fn send_plugin_message(self: &Arc<Self>, msg: &LoaderMessage) -> crate::Result<()> {
         for p in self.plugins.lock().unwrap().iter_mut() {
             p.send(msg)?;
@@ -284,30 +541,38 @@ impl Server {
         Ok(())
     }
 
-    pub fn shutdown(self: &Arc<Self>) {
-        Self::LOGGER.info("Server shutting down...");
+    pub async fn shutdown(self: &Arc<Self>) {
+        LOGGER.info("Server shutting down...");
 
         // Signal shutdown
         self.shutting_down.store(true, Ordering::SeqCst);
 
         // Disconnect clients
-        let clients = self.clients.lock().unwrap();
+        let clients: Vec<Client> = self.clients.lock().unwrap().drain().collect();
         for client in clients.iter() {
             let _ = client.send(types::message::ServerMessage::Shutdown {
-                message: format!("Server shutting down... we'll be back shortly"),
+                message: "Server shutting down... we'll be back shortly".to_string(),
             });
             let _ = client.close();
         }
 
+        // Give the writers a moment to flush the goodbye
+        let flushed = async {
+            for client in clients.iter() {
+                client.closed().await;
+            }
+        };
+        let _ = tokio::time::timeout(SHUTDOWN_GRACE, flushed).await;
+
         // Stop plugins
         for plugin in self.plugins.lock().unwrap().iter_mut() {
             if let Err(e) = plugin.stop() {
-                Self::LOGGER.warn(e.context("Couldn't stop plugin"));
+                LOGGER.warn(e.context("Couldn't stop plugin"));
             }
         }
 
-        Self::LOGGER.info("Shutdown complete");
-        Self::LOGGER.info("Exiting process..");
+        LOGGER.info("Shutdown complete");
+        LOGGER.info("Exiting process..");
         std::process::exit(0);
     }
 }
@@ -4,7 +4,7 @@ pub type Author = String;
 pub mod data {
     use serde::{Deserialize, Serialize};
 
-    use crate::types::Author;
+    use crate::{types::Author, utils::permissions::Permissions};
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct Message {
@@ -13,6 +13,53 @@ pub mod data {
         pub from: Author,
         pub contents: String,
         pub timestamp: i64,
+        /// The message this one answers
+        #[serde(default)]
+        pub reply_to: Option<i64>,
+        /// The thread the message was sent in, `None` for the channel itself
+        #[serde(default)]
+        pub thread_id: Option<i64>,
+        /// Filled in for chunks, in the order each emoji was first used
+        #[serde(default)]
+        pub reactions: Vec<Reaction>,
+        #[serde(default)]
+        pub attachments: Vec<Attachment>,
+    }
+
+    /// An uploaded file, downloaded from `/attachments/<hash>` over HTTP
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Attachment {
+        pub id: i64,
+        pub name: String,
+        pub size: u64,
+        pub content_type: Option<String>,
+        /// SHA-256 of the contents in hex, files with the same contents are only stored once
+        pub hash: String,
+    }
+
+    /// How many users reacted to a message with an emoji
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Reaction {
+        pub emoji: String,
+        pub count: u32,
+    }
+
+    /// A message matching a search, `snippet` is the part of it that matched, escaped for HTML,
+    /// with every match between `<mark>` and `</mark>`
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct SearchHit {
+        pub message: Message,
+        pub snippet: String,
+    }
+
+    /// A conversation rooted at a message, the thread's id is the id of that message
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Thread {
+        pub id: i64,
+        pub channel_id: String,
+        pub name: String,
+        pub from: Author,
+        pub timestamp: i64,
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -20,15 +67,240 @@ pub mod data {
         pub id: String,
         pub name: String,
         pub kind: ChannelKind,
+        /// Channels are listed by position, starting at 0
+        #[serde(default)]
+        pub position: u32,
+        #[serde(default)]
+        pub topic: Option<String>,
+        /// The category the channel is listed under
+        #[serde(default)]
+        pub parent: Option<String>,
+        #[serde(default)]
+        pub nsfw: bool,
+        /// How long members have to wait between messages, 0 when off
+        #[serde(default)]
+        pub slow_mode_secs: u32,
     }
 
-    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
     #[serde(rename_all = "snake_case")]
-    #[serde(untagged)]
+    pub enum Status {
+        #[default]
+        Online,
+        Idle,
+        Dnd,
+        /// Connected but appearing offline
+        Offline,
+    }
+
+    #[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct UserPresence {
+        pub status: Status,
+        /// Custom status text
+        pub text: Option<String>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Member {
+        pub user_id: String,
+        /// When the user first connected
+        pub joined: i64,
+        pub presence: UserPresence,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Role {
+        pub id: i64,
+        pub name: String,
+        pub permissions: Permissions,
+    }
+
+    /// Who a channel permission overwrite applies to
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(tag = "type", content = "id", rename_all = "snake_case")]
+    pub enum OverwriteTarget {
+        Role(i64),
+        User(Author),
+    }
+
+    /// Permissions allowed or denied in a single channel, on top of the server wide ones
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Overwrite {
+        pub target: OverwriteTarget,
+        pub allow: Permissions,
+        pub deny: Permissions,
+    }
+
+    /// Serialized as `{ "version": 1, "type": "iframe", "url": <Url> }`, `url` only for iframes
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(into = "ChannelKindRepr", try_from = "ChannelKindFormat")]
     pub enum ChannelKind {
         Text,
         Voice,
         IFrame(String),
+        /// Groups the channels that have it as their `parent`
+        Category,
+        /// Text that only roles with `post_announcements` can send
+        Announcement,
+        /// Only takes messages in threads
+        Forum,
+        /// Voice where only roles with `speak_on_stage` are heard
+        Stage,
+    }
+
+    impl ChannelKind {
+        /// The latest version of the serialized format
+        pub const VERSION: u32 = 1;
+
+        /// The `type` of the kind
+        pub fn name(&self) -> &'static str {
+            match self {
+                Self::Text => "text",
+                Self::Voice => "voice",
+                Self::IFrame(_) => "iframe",
+                Self::Category => "category",
+                Self::Announcement => "announcement",
+                Self::Forum => "forum",
+                Self::Stage => "stage",
+            }
+        }
+
+        pub fn url(&self) -> Option<&str> {
+            match self {
+                Self::IFrame(url) => Some(url),
+                _ => None,
+            }
+        }
+
+        /// The kind with a `type`, iframes need a `url`
+        pub fn from_name(name: &str, url: Option<String>) -> Option<Self> {
+            Some(match name {
+                "text" => Self::Text,
+                "voice" => Self::Voice,
+                "iframe" => Self::IFrame(url?),
+                "category" => Self::Category,
+                "announcement" => Self::Announcement,
+                "forum" => Self::Forum,
+                "stage" => Self::Stage,
+                _ => return None,
+            })
+        }
+
+        /// Text channels that messages can be sent to outside of threads
+        pub fn is_text(&self) -> bool {
+            matches!(self, Self::Text | Self::Announcement)
+        }
+
+        pub fn is_voice(&self) -> bool {
+            matches!(self, Self::Voice | Self::Stage)
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct ChannelKindRepr {
+        version: u32,
+        #[serde(rename = "type")]
+        kind: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        url: Option<String>,
+    }
+
+    /// Every format a channel kind has been written in
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ChannelKindFormat {
+        Tagged(ChannelKindRepr),
+        /// Before the kind was tagged text and voice channels were written as `null`,
+        /// categories as `"category"` and iframes as their url
+        Legacy(Option<String>),
+    }
+
+    impl From<ChannelKind> for ChannelKindRepr {
+        fn from(kind: ChannelKind) -> Self {
+            Self {
+                version: ChannelKind::VERSION,
+                kind: kind.name().to_string(),
+                url: kind.url().map(str::to_string),
+            }
+        }
+    }
+
+    impl TryFrom<ChannelKindFormat> for ChannelKind {
+        type Error = String;
+
+        fn try_from(format: ChannelKindFormat) -> Result<Self, Self::Error> {
+            match format {
+                ChannelKindFormat::Tagged(repr) if repr.version > ChannelKind::VERSION => {
+                    Err(format!("Unsupported channel kind version {}", repr.version))
+                }
+                ChannelKindFormat::Tagged(repr) => ChannelKind::from_name(&repr.kind, repr.url)
+                    .ok_or_else(|| format!("Invalid channel kind '{}'", repr.kind)),
+                // `null` was read back as text, the rest is lost
+                ChannelKindFormat::Legacy(None) => Ok(ChannelKind::Text),
+                // Categories, the rest written by hand, nobody has an iframe at these
+                ChannelKindFormat::Legacy(Some(name))
+                    if matches!(name.as_str(), "text" | "voice" | "category") =>
+                {
+                    Ok(ChannelKind::from_name(&name, None).unwrap_or(ChannelKind::Text))
+                }
+                ChannelKindFormat::Legacy(Some(url)) => Ok(ChannelKind::IFrame(url)),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn read(json: &str) -> Result<ChannelKind, serde_json::Error> {
+            serde_json::from_str(json)
+        }
+
+        #[test]
+        fn tagged_kinds_round_trip() {
+            let kinds = [
+                ChannelKind::Text,
+                ChannelKind::Voice,
+                ChannelKind::IFrame("https://example.com".to_string()),
+                ChannelKind::Category,
+                ChannelKind::Announcement,
+                ChannelKind::Forum,
+                ChannelKind::Stage,
+            ];
+            for kind in kinds {
+                let json = serde_json::to_string(&kind).unwrap();
+                assert_eq!(read(&json).unwrap(), kind);
+            }
+            assert_eq!(
+                serde_json::to_string(&ChannelKind::IFrame("https://example.com".to_string()))
+                    .unwrap(),
+                r#"{"version":1,"type":"iframe","url":"https://example.com"}"#
+            );
+            assert_eq!(
+                serde_json::to_string(&ChannelKind::Voice).unwrap(),
+                r#"{"version":1,"type":"voice"}"#
+            );
+        }
+
+        #[test]
+        fn reads_legacy_kinds() {
+            assert_eq!(read("null").unwrap(), ChannelKind::Text);
+            assert_eq!(read(r#""text""#).unwrap(), ChannelKind::Text);
+            assert_eq!(read(r#""voice""#).unwrap(), ChannelKind::Voice);
+            assert_eq!(read(r#""category""#).unwrap(), ChannelKind::Category);
+            assert_eq!(
+                read(r#""https://example.com""#).unwrap(),
+                ChannelKind::IFrame("https://example.com".to_string())
+            );
+        }
+
+        #[test]
+        fn rejects_unknown_kinds() {
+            assert!(read(r#"{"version":2,"type":"text"}"#).is_err());
+            assert!(read(r#"{"version":1,"type":"hologram"}"#).is_err());
+            // Iframes can't do without their url
+            assert!(read(r#"{"version":1,"type":"iframe"}"#).is_err());
+        }
     }
 }
 
@@ -49,6 +321,15 @@ pub mod handshake {
     pub struct ClientDetails {
         pub version: String,
         pub auth_token: String,
+        /// Log in with a local account instead of a token
+        #[serde(default)]
+        pub login: Option<Login>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Login {
+        pub username: String,
+        pub password: String,
     }
 }
 
@@ -58,11 +339,12 @@ pub mod message {
     use serde::{Deserialize, Serialize};
 
     use crate::{
-        requests::indicator::IndicatorContext,
+        requests::indicator::{Indicator, IndicatorContext},
         types::{
             Author,
             data::{self, Message},
         },
+        utils::permissions::Permissions,
     };
 
     /// Messages sent *from the client* (user’s app) to the server
@@ -73,6 +355,14 @@ pub mod message {
         SendMessage {
             channel_id: String,
             contents: String,
+            #[serde(default)]
+            reply_to: Option<i64>,
+            /// Send to a thread of the channel instead
+            #[serde(default)]
+            thread_id: Option<i64>,
+            /// Ids of finished uploads
+            #[serde(default)]
+            attachments: Vec<i64>,
         },
 
         /// Edit a message (if allowed)
@@ -86,11 +376,98 @@ pub mod message {
             message_id: i64,
         },
 
+        /// 16 messages, `chunk_id` chunks back from the newest. Kept for older clients, chunks
+        /// shift as messages arrive so `LoadMessages` should be used instead
         LoadChunk {
             channel_id: String,
             chunk_id: usize,
         },
 
+        /// A page of messages, the newest ones unless it starts `before`, `after` or `around` a
+        /// message id (only one of them)
+        LoadMessages {
+            channel_id: String,
+            #[serde(default)]
+            before: Option<i64>,
+            #[serde(default)]
+            after: Option<i64>,
+            #[serde(default)]
+            around: Option<i64>,
+            #[serde(default = "default_message_page")]
+            limit: usize,
+        },
+
+        /// Start a thread at `message_id`, or with a new message of `contents` in forum channels
+        CreateThread {
+            channel_id: String,
+            name: String,
+            #[serde(default)]
+            message_id: Option<i64>,
+            #[serde(default)]
+            contents: Option<String>,
+        },
+
+        LoadThreadChunk {
+            thread_id: i64,
+            chunk_id: usize,
+        },
+
+        /// The threads of a channel, newest first
+        ListThreads {
+            channel_id: String,
+        },
+
+        /// Messages containing every word of `query` (a trailing `*` matches any word starting
+        /// with it), newest first
+        Search {
+            query: String,
+            /// Only search this channel
+            #[serde(default)]
+            channel_id: Option<String>,
+            /// Only messages of this user
+            #[serde(default)]
+            from: Option<String>,
+            /// Only messages sent at or after this unix timestamp
+            #[serde(default)]
+            since: Option<i64>,
+            /// Only messages sent before this unix timestamp
+            #[serde(default)]
+            until: Option<i64>,
+            /// Continue before this message id, the `next` of the previous page
+            #[serde(default)]
+            before: Option<i64>,
+            #[serde(default = "default_search_page")]
+            limit: usize,
+        },
+
+        AddReaction {
+            message_id: i64,
+            emoji: String,
+        },
+
+        RemoveReaction {
+            message_id: i64,
+            emoji: String,
+        },
+
+        /// Announce a file of `size` bytes, its chunks are sent as binary frames after
+        /// `upload_started`
+        BeginUpload {
+            name: String,
+            size: u64,
+            #[serde(default)]
+            content_type: Option<String>,
+        },
+
+        /// Every chunk was sent, the file can be attached to a message after `upload_finished`
+        FinishUpload {
+            upload_id: u32,
+        },
+
+        CancelUpload {
+            upload_id: u32,
+        },
+
         Typing {
             channel_id: String,
         },
@@ -102,6 +479,151 @@ pub mod message {
         LeaveVoice {
             channel_id: String,
         },
+
+        /// Set the presence shown to other users
+        SetStatus {
+            status: data::Status,
+            #[serde(default)]
+            text: Option<String>,
+        },
+
+        /// A page of members ordered by user id
+        ListMembers {
+            /// Only list members that are online (`true`) or offline (`false`)
+            #[serde(default)]
+            online: Option<bool>,
+            /// Continue after this user id, the `next` of the previous page
+            #[serde(default)]
+            after: Option<String>,
+            #[serde(default = "default_member_page")]
+            limit: usize,
+        },
+
+        /// Replay the events an earlier session missed since `last_seq`
+        Resume {
+            session_id: String,
+            last_seq: u64,
+        },
+
+        /// Every role, answered with `Roles`
+        ListRoles,
+
+        /// Give a user a role (needs `manage_roles` and every permission of the role)
+        AssignRole {
+            user_id: String,
+            role_id: i64,
+        },
+
+        /// Take a role from a user (same rules as `AssignRole`)
+        UnassignRole {
+            user_id: String,
+            role_id: i64,
+        },
+
+        /// Disconnect a member, they join again when they next connect
+        KickMember {
+            user_id: String,
+        },
+
+        /// Remove a member and keep them from connecting again
+        BanMember {
+            user_id: String,
+        },
+
+        UnbanMember {
+            user_id: String,
+        },
+
+        /// Create a channel at the end of the list
+        CreateChannel {
+            name: String,
+            kind: data::ChannelKind,
+        },
+
+        RenameChannel {
+            channel_id: String,
+            name: String,
+        },
+
+        /// Move a channel to `position`, shifting the ones in between
+        MoveChannel {
+            channel_id: String,
+            position: u32,
+        },
+
+        /// Set or clear (`null`) the topic of a channel
+        SetChannelTopic {
+            channel_id: String,
+            #[serde(default)]
+            topic: Option<String>,
+        },
+
+        /// Put a channel in a category, or take it out of one (`null`)
+        SetChannelParent {
+            channel_id: String,
+            #[serde(default)]
+            parent: Option<String>,
+        },
+
+        SetChannelNsfw {
+            channel_id: String,
+            nsfw: bool,
+        },
+
+        /// Make members wait `seconds` between messages, 0 turns it off
+        SetSlowMode {
+            channel_id: String,
+            seconds: u32,
+        },
+
+        /// Delete a channel and its messages
+        DeleteChannel {
+            channel_id: String,
+        },
+
+        /// The overwrites of a channel, answered with `Overwrites`
+        ListOverwrites {
+            channel_id: String,
+        },
+
+        /// Create or replace the overwrite of a role or user in a channel (needs `manage_channels`
+        /// there and every permission it allows or denies)
+        SetOverwrite {
+            channel_id: String,
+            target: data::OverwriteTarget,
+            #[serde(default)]
+            allow: Permissions,
+            #[serde(default)]
+            deny: Permissions,
+        },
+
+        /// Remove an overwrite (same rules as `SetOverwrite`)
+        DeleteOverwrite {
+            channel_id: String,
+            target: data::OverwriteTarget,
+        },
+    }
+
+    fn default_member_page() -> usize {
+        50
+    }
+
+    fn default_message_page() -> usize {
+        50
+    }
+
+    fn default_search_page() -> usize {
+        25
+    }
+
+    /// A client message, optionally tagged with an id that's echoed back in the response
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Request {
+        #[serde(flatten)]
+        pub message: ClientMessage,
+        /// Echoed in the `Ack` or `Error` answering this request
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub request_id: Option<String>,
     }
 
     /// Messages sent *from the server* to the client
@@ -111,8 +633,20 @@ pub mod message {
         /// Successful authentication
         Authenticated {
             uuid: Author,
+            /// Issued after a password login, use it as `auth_token` when reconnecting
+            session_token: Option<String>,
+            /// Pass to `Resume` after reconnecting to receive the events missed in between
+            session_id: String,
+            /// Every event after this one will be sent to this connection
+            seq: u64,
             indicators: Vec<IndicatorContext>,
             voice_chat: HashMap<String, HashMap<String, u16>>,
+            /// Everyone online, the user themselves included
+            presence: HashMap<Author, data::UserPresence>,
+            /// What this user is allowed to do outside of channel overwrites
+            permissions: Permissions,
+            /// The channels this user can see
+            channels: Vec<data::Channel>,
         },
 
         TempMessage {
@@ -125,18 +659,21 @@ pub mod message {
         /// A message was edited
         MessageUpdate {
             message_id: i64,
+            channel_id: String,
             contents: String,
         },
 
         /// A message was deleted
         MessageDelete {
             message_id: i64,
+            channel_id: String,
         },
 
         /// Presence updates
         PresenceUpdate {
             user_id: Author,
-            status: String,
+            #[serde(flatten)]
+            presence: data::UserPresence,
         },
 
         /// Indicator
@@ -148,6 +685,12 @@ pub mod message {
 
         Chunk(Vec<Message>),
 
+        /// A page of messages of a channel, oldest first
+        Messages {
+            channel_id: String,
+            messages: Vec<Message>,
+        },
+
         VoiceJoin {
             user_id: String,
             channel_id: String,
@@ -159,6 +702,150 @@ pub mod message {
             channel_id: String,
             voice_id: u16,
         },
+
+        /// A page of members, `next` is set when there may be more
+        Members {
+            members: Vec<data::Member>,
+            next: Option<String>,
+        },
+
+        /// A user became a member
+        MemberJoin(data::Member),
+
+        /// A user was removed from the members
+        MemberLeave {
+            user_id: Author,
+        },
+
+        /// Every role, `everyone` first
+        Roles(Vec<data::Role>),
+
+        /// A role was created or its permissions changed
+        RoleUpdate(data::Role),
+
+        RoleDelete {
+            role_id: i64,
+        },
+
+        /// The roles assigned to a user changed
+        MemberRoles {
+            user_id: Author,
+            roles: Vec<i64>,
+        },
+
+        /// A channel was created
+        ChannelCreate(data::Channel),
+
+        /// A channel was renamed, moved or given a new topic
+        ChannelUpdate(data::Channel),
+
+        ChannelDelete {
+            channel_id: String,
+        },
+
+        /// The channels visible to this connection changed
+        Channels(Vec<data::Channel>),
+
+        /// The overwrites of a channel, sent when they change
+        Overwrites {
+            channel_id: String,
+            overwrites: Vec<data::Overwrite>,
+        },
+
+        /// A thread was started
+        ThreadCreate(data::Thread),
+
+        /// The message a thread started at was deleted, and the thread with it
+        ThreadDelete {
+            thread_id: i64,
+            channel_id: String,
+        },
+
+        Threads {
+            channel_id: String,
+            threads: Vec<data::Thread>,
+        },
+
+        /// A page of search results, `next` is set when there may be more
+        SearchResults {
+            hits: Vec<data::SearchHit>,
+            next: Option<i64>,
+        },
+
+        UploadStarted {
+            upload_id: u32,
+        },
+
+        UploadFinished {
+            upload_id: u32,
+            attachment: data::Attachment,
+        },
+
+        ReactionAdd {
+            message_id: i64,
+            channel_id: String,
+            user_id: Author,
+            emoji: String,
+        },
+
+        ReactionRemove {
+            message_id: i64,
+            channel_id: String,
+            user_id: Author,
+            emoji: String,
+        },
+
+        /// Every missed event was sent again
+        Resumed {
+            replayed: usize,
+        },
+
+        /// A request with a `request_id` succeeded
+        Ack {
+            request_id: String,
+            /// The id of the message the request created, if any
+            message_id: Option<i64>,
+        },
+
+        /// A request with a `request_id` failed
+        Error {
+            request_id: String,
+            error: ResponseError,
+        },
+    }
+
+    impl ServerMessage {
+        /// The channel an event happened in, only users who can see it receive it
+        pub fn channel_id(&self) -> Option<&str> {
+            match self {
+                Self::MessageCreate(Message { channel_id, .. })
+                | Self::MessageUpdate { channel_id, .. }
+                | Self::MessageDelete { channel_id, .. }
+                | Self::VoiceJoin { channel_id, .. }
+                | Self::VoiceLeave { channel_id, .. }
+                | Self::Overwrites { channel_id, .. }
+                | Self::ThreadCreate(data::Thread { channel_id, .. })
+                | Self::ThreadDelete { channel_id, .. }
+                | Self::ReactionAdd { channel_id, .. }
+                | Self::ReactionRemove { channel_id, .. }
+                | Self::ChannelDelete { channel_id } => Some(channel_id),
+                Self::ChannelCreate(data::Channel { id, .. })
+                | Self::ChannelUpdate(data::Channel { id, .. }) => Some(id),
+                Self::Indicator(IndicatorContext {
+                    indicator: Indicator::Typing { channel_id, .. },
+                    ..
+                }) => Some(channel_id),
+                _ => None,
+            }
+        }
+    }
+
+    /// A broadcast message, numbered so it can be replayed after a reconnect
+    #[derive(Debug, Clone, Serialize)]
+    pub struct Event<'a> {
+        #[serde(flatten)]
+        pub message: &'a ServerMessage,
+        pub seq: u64,
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -177,6 +864,21 @@ pub mod message {
     pub enum WsMessage<T: Serialize + for<'de> Deserialize<'de>> {
         Message(T),
         Binary(Vec<u8>),
-        String(String),
+        /// A chunk of an upload, the upload id followed by the data
+        Upload(Vec<u8>),
+        /// A frame that couldn't be decoded, and why
+        Malformed(String),
+    }
+
+    impl WsMessage<Request> {
+        /// Split off the request id
+        pub fn into_parts(self) -> (WsMessage<ClientMessage>, Option<String>) {
+            match self {
+                WsMessage::Message(r) => (WsMessage::Message(r.message), r.request_id),
+                WsMessage::Binary(b) => (WsMessage::Binary(b), None),
+                WsMessage::Upload(c) => (WsMessage::Upload(c), None),
+                WsMessage::Malformed(r) => (WsMessage::Malformed(r), None),
+            }
+        }
     }
 }
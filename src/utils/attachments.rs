@@ -0,0 +1,397 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    logger,
+    requests::error::RequestError,
+    server::Server,
+    utils::{self, auth::hex},
+};
+
+logger!(LOGGER "Attachments");
+
+/// Longest file name and content type
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Set through `attachments` in the server config
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AttachmentConfig {
+    /// Largest file that can be uploaded
+    pub max_file_size: u64,
+    /// Uploads a connection can have in progress at once
+    pub max_uploads: usize,
+    /// Most files attached to a single message
+    pub max_per_message: usize,
+}
+
+impl Default for AttachmentConfig {
+    fn default() -> Self {
+        Self {
+            max_file_size: 25 * 1024 * 1024,
+            max_uploads: 4,
+            max_per_message: 10,
+        }
+    }
+}
+
+/// A finished upload, stored under its hash
+pub struct Stored {
+    pub name: String,
+    pub size: u64,
+    pub content_type: Option<String>,
+    pub hash: String,
+}
+
+struct Upload {
+    /// Session of the connection that started it
+    owner: String,
+    name: String,
+    size: u64,
+    content_type: Option<String>,
+    /// Locked for as long as a chunk is written, other uploads go on meanwhile
+    written: Mutex<Written>,
+}
+
+#[derive(Default)]
+struct Written {
+    received: u64,
+    hasher: Sha256,
+    /// Created with the first chunk
+    file: Option<fs::File>,
+}
+
+/// Uploads in progress, written to `tmp` and moved next to the other files once finished
+pub struct Uploads {
+    dir: PathBuf,
+    config: AttachmentConfig,
+    open: Mutex<HashMap<u32, Arc<Upload>>>,
+    /// Held while files are moved into place or removed, so a file that's reused by an upload
+    /// isn't removed as unused
+    files: Mutex<()>,
+}
+
+impl Uploads {
+    /// Files are kept in `attachments` under the server root, the ones not in `in_use` and any
+    /// unfinished upload are removed
+    pub fn new(
+        root: &Path,
+        config: AttachmentConfig,
+        in_use: &HashSet<String>,
+    ) -> io::Result<Self> {
+        let dir = root.join("attachments");
+        let _ = fs::remove_dir_all(dir.join("tmp"));
+        fs::create_dir_all(dir.join("tmp"))?;
+
+        let mut pruned = 0;
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if entry.file_type()?.is_file() && !in_use.contains(&name) {
+                fs::remove_file(entry.path())?;
+                pruned += 1;
+            }
+        }
+        if pruned > 0 {
+            LOGGER.info(format!("Removed {pruned} unused files"));
+        }
+
+        Ok(Self {
+            dir,
+            config,
+            open: Mutex::new(HashMap::new()),
+            files: Mutex::new(()),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Start an upload, returns its id
+    pub fn begin(
+        &self,
+        owner: &str,
+        name: &str,
+        size: u64,
+        content_type: Option<String>,
+    ) -> crate::Result<u32> {
+        // Only the file name is kept, wherever it came from
+        let name = name.rsplit(['/', '\\']).next().unwrap_or_default().trim();
+        if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+            return Err(RequestError::InvalidRequest(format!(
+                "File names must be 1 to {MAX_NAME_LEN} characters"
+            ))
+            .into());
+        }
+        if content_type
+            .as_ref()
+            .is_some_and(|t| t.chars().count() > MAX_NAME_LEN)
+        {
+            return Err(RequestError::InvalidRequest("Invalid content type".to_string()).into());
+        }
+        if size > self.config.max_file_size {
+            return Err(RequestError::InvalidRequest(format!(
+                "Files can't be larger than {} bytes",
+                self.config.max_file_size
+            ))
+            .into());
+        }
+
+        let mut open = self.open.lock().unwrap();
+        if open.values().filter(|u| u.owner == owner).count() >= self.config.max_uploads {
+            return Err(RequestError::InvalidRequest(format!(
+                "Only {} uploads can be in progress at once",
+                self.config.max_uploads
+            ))
+            .into());
+        }
+
+        let mut id = rand::random::<u32>();
+        while open.contains_key(&id) {
+            id = rand::random();
+        }
+        open.insert(
+            id,
+            Arc::new(Upload {
+                owner: owner.to_string(),
+                name: name.to_string(),
+                size,
+                content_type,
+                written: Mutex::default(),
+            }),
+        );
+        Ok(id)
+    }
+
+    /// Whether `owner` has the upload in progress
+    pub fn is_open(&self, owner: &str, id: u32) -> bool {
+        self.open
+            .lock()
+            .unwrap()
+            .get(&id)
+            .is_some_and(|u| u.owner == owner)
+    }
+
+    /// Append a chunk, an upload that grows past its announced size is cancelled
+    pub fn write(&self, owner: &str, id: u32, data: &[u8]) -> crate::Result<()> {
+        let upload = self.get(owner, id)?;
+        let mut written = upload.written.lock().unwrap();
+        if written.received + data.len() as u64 > upload.size {
+            drop(written);
+            self.cancel(owner, id)?;
+            return Err(RequestError::InvalidRequest(
+                "Upload is larger than announced, it was cancelled".to_string(),
+            )
+            .into());
+        }
+
+        let tmp = self.tmp_path(id);
+        utils::blocking(|| {
+            let file = match &mut written.file {
+                Some(file) => file,
+                file => file.insert(fs::File::create(tmp)?),
+            };
+            file.write_all(data)
+        })?;
+        written.hasher.update(data);
+        written.received += data.len() as u64;
+        Ok(())
+    }
+
+    /// Store a complete upload, only once if the same contents were uploaded before.
+    ///
+    /// `store` records the file before anything can remove it again.
+    pub fn finish<T>(
+        &self,
+        owner: &str,
+        id: u32,
+        store: impl FnOnce(&Stored) -> crate::Result<T>,
+    ) -> crate::Result<T> {
+        let upload = self.get(owner, id)?;
+        let mut written = upload.written.lock().unwrap();
+        if written.received != upload.size {
+            return Err(RequestError::InvalidRequest(format!(
+                "Upload is incomplete, {} of {} bytes were received",
+                written.received, upload.size
+            ))
+            .into());
+        }
+        // Cancelled while this was waiting for the chunks to be written
+        if self.open.lock().unwrap().remove(&id).is_none() {
+            return Err(RequestError::NotFound("Upload does not exist".to_string()).into());
+        }
+
+        let hash = hex(&std::mem::take(&mut written.hasher).finalize());
+        let created = written.file.take().is_some();
+        let (tmp, path) = (self.tmp_path(id), self.dir.join(&hash));
+        let _files = self.files.lock().unwrap();
+        utils::blocking(|| match (path.exists(), created) {
+            (true, true) => fs::remove_file(tmp),
+            (true, false) => Ok(()),
+            (false, true) => fs::rename(tmp, path),
+            (false, false) => fs::File::create(path).map(|_| ()),
+        })?;
+
+        store(&Stored {
+            name: upload.name.clone(),
+            size: upload.size,
+            content_type: upload.content_type.clone(),
+            hash,
+        })
+    }
+
+    pub fn cancel(&self, owner: &str, id: u32) -> crate::Result<()> {
+        self.get(owner, id)?;
+        let Some(upload) = self.open.lock().unwrap().remove(&id) else {
+            return Ok(());
+        };
+
+        // Waits for a chunk that's being written
+        let mut written = upload.written.lock().unwrap();
+        if written.file.take().is_some() {
+            utils::blocking(|| fs::remove_file(self.tmp_path(id)))?;
+        }
+        Ok(())
+    }
+
+    /// Drop every upload of a session that went away
+    pub fn cancel_all(&self, owner: &str) {
+        let ids: Vec<u32> = self
+            .open
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, u)| u.owner == owner)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in ids {
+            let _ = self.cancel(owner, id);
+        }
+    }
+
+    /// Remove the files of deleted attachments, unless `in_use` says something else still has them
+    pub fn remove_unused(&self, hashes: &[String], in_use: impl Fn(&str) -> crate::Result<bool>) {
+        let _files = self.files.lock().unwrap();
+        for hash in hashes {
+            match in_use(hash) {
+                Ok(true) => {}
+                Ok(false) => {
+                    if let Err(e) = utils::blocking(|| fs::remove_file(self.dir.join(hash))) {
+                        LOGGER.error(format!("Couldn't remove {hash}: {e}"));
+                    }
+                }
+                Err(e) => LOGGER.error(e.context(format!("Couldn't check if {hash} is in use"))),
+            }
+        }
+    }
+
+    fn get(&self, owner: &str, id: u32) -> crate::Result<Arc<Upload>> {
+        self.open
+            .lock()
+            .unwrap()
+            .get(&id)
+            .filter(|u| u.owner == owner)
+            .cloned()
+            .ok_or_else(|| RequestError::NotFound("Upload does not exist".to_string()).into())
+    }
+
+    fn tmp_path(&self, id: u32) -> PathBuf {
+        self.dir.join("tmp").join(id.to_string())
+    }
+}
+
+/// Answer a plain HTTP `GET /attachments/<hash>`, anything may follow the hash (like the file
+/// name) after another `/`.
+///
+/// The token goes in an `Authorization: Bearer <Token>` header, files are only served to their
+/// uploader and to users who can see a channel they were sent to.
+pub async fn serve<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    server: &Arc<Server>,
+    path: &str,
+    headers: &HashMap<String, String>,
+) -> io::Result<()> {
+    let hash = path
+        .trim_start_matches("/attachments/")
+        .split(['/', '?'])
+        .next()
+        .unwrap_or_default();
+    let valid = hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+
+    let token = headers
+        .get("authorization")
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|t| !t.is_empty());
+    let Some(token) = token else {
+        return unauthorized(stream).await;
+    };
+    let Ok(user_id) = utils::blocking(|| server.auth.authenticate(token)) else {
+        return unauthorized(stream).await;
+    };
+
+    // Files the user may not see are as missing as files that don't exist
+    let file = match valid && can_download(server, &user_id, hash) {
+        true => tokio::fs::File::open(server.uploads.dir().join(hash))
+            .await
+            .ok(),
+        false => None,
+    };
+    let Some(mut file) = file else {
+        let response = "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n";
+        stream.write_all(response.as_bytes()).await?;
+        return stream.flush().await;
+    };
+
+    // Files never change, a hash always points to the same contents, but only for this user
+    let response = format!(
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: application/octet-stream\r\n\
+         Content-Length: {}\r\n\
+         Cache-Control: private, max-age=31536000, immutable\r\n\r\n",
+        file.metadata().await?.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    tokio::io::copy(&mut file, stream).await?;
+    stream.flush().await
+}
+
+async fn unauthorized<S: AsyncWrite + Unpin>(stream: &mut S) -> io::Result<()> {
+    let response = "HTTP/1.1 401 Unauthorized\r\n\
+                    WWW-Authenticate: Bearer\r\n\
+                    Content-Length: 0\r\n\r\n";
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await
+}
+
+/// Whether the user is a member who uploaded the file or can see a channel it was sent to
+fn can_download(server: &Arc<Server>, user_id: &str, hash: &str) -> bool {
+    let allowed = utils::blocking(|| -> crate::Result<bool> {
+        // Whoever couldn't connect can't download either
+        if server.db.is_banned(user_id)? || server.db.get_member(user_id)?.is_none() {
+            return Ok(false);
+        }
+
+        let uses = server.db.get_attachment_uses(hash)?;
+        Ok(uses.iter().any(|(uploader, channel_id)| {
+            uploader == user_id
+                || channel_id
+                    .as_ref()
+                    .is_some_and(|c| server.can_view(user_id, c))
+        }))
+    });
+    allowed.unwrap_or_else(|e| {
+        LOGGER.error(format!("Couldn't look up attachment {hash}: {e}"));
+        false
+    })
+}
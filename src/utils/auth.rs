@@ -1,39 +1,714 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use anyhow::Context;
-use serde::Deserialize;
+use anyhow::{Context, anyhow};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD as Base64};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 use crate::logger;
-use crate::server::Server;
+use crate::server::{Server, ServerConfig};
+use crate::types::handshake::Login;
 use crate::utils::client::Client;
+use crate::utils::database::Database;
 
 logger!(LOGGER "Auth");
 
+const CLOUD_URL: &str = "https://vxchat.netlify.app";
+const PBKDF2_ROUNDS: u32 = 100_000;
+const MAX_RETRY_AFTER_SECS: u64 = 10;
+
+/// Validates the auth tokens clients send in their handshake
+pub trait AuthProvider: Send + Sync {
+    /// Returns the user id the token belongs to
+    fn authenticate(&self, token: &str) -> crate::Result<String>;
+
+    /// Checks that the provider is reachable and accepts this server
+    fn test(&self) -> crate::Result<()>;
+
+    /// Exchanges a username and password for a new session token
+    fn login(&self, _username: &str, _password: &str) -> crate::Result<Session> {
+        Err(Rejected("This server does not support password login".to_string()).into())
+    }
+}
+
+/// A server issued session
+pub struct Session {
+    pub user_id: String,
+    pub token: String,
+}
+
+/// Which auth provider the server uses, `cloud` by default
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "provider", rename_all = "snake_case")]
+pub enum AuthConfig {
+    /// The Axiom Cloud API
+    Cloud {
+        #[serde(flatten)]
+        options: HttpOptions,
+    },
+
+    /// A self hosted service implementing the cloud API
+    Http {
+        base_url: String,
+        #[serde(flatten)]
+        options: HttpOptions,
+    },
+
+    /// A JSON file mapping tokens to user ids
+    Local { users_file: PathBuf },
+
+    /// Accounts stored in the server database, logged in with a username and password
+    Database {
+        #[serde(default = "default_session_days")]
+        session_days: u32,
+    },
+
+    /// Accepts any non-empty token and uses it as the user id, never use in production
+    Test,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig::Cloud {
+            options: HttpOptions::default(),
+        }
+    }
+}
+
+fn default_session_days() -> u32 {
+    30
+}
+
+/// Tuning for the HTTP based providers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpOptions {
+    /// Timeout of a single request
+    pub timeout_secs: u64,
+    /// How many times a failed request is retried, rejected tokens are never retried.
+    /// 408, 429 and 5xx responses wait for their `Retry-After` when it's at most 10 seconds
+    pub retries: u32,
+    /// Delay before the first retry, doubled on every attempt
+    pub backoff_ms: u64,
+    /// How long a validated token is remembered, 0 disables the cache
+    pub cache_ttl_secs: u64,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 5,
+            retries: 3,
+            backoff_ms: 250,
+            cache_ttl_secs: 300,
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn build(
+        &self,
+        root: &Path,
+        config: &ServerConfig,
+        db: &Arc<Database>,
+    ) -> crate::Result<Box<dyn AuthProvider>> {
+        Ok(match self {
+            AuthConfig::Cloud { options } => {
+                CachedAuth::wrap(Box::new(CloudAuth::new(config, options)), options)
+            }
+            AuthConfig::Http { base_url, options } => {
+                CachedAuth::wrap(Box::new(HttpAuth::new(base_url, config, options)), options)
+            }
+            AuthConfig::Local { users_file } => Box::new(LocalAuth::load(&root.join(users_file))?),
+            AuthConfig::Database { session_days } => {
+                // Sessions that ran out while the server was down
+                db.delete_expired_sessions(chrono::Utc::now().timestamp())?;
+                Box::new(DatabaseAuth {
+                    db: db.clone(),
+                    session_secs: *session_days as i64 * 24 * 60 * 60,
+                })
+            }
+            AuthConfig::Test => Box::new(TestAuth),
+        })
+    }
+}
+
+/// The provider refused the credentials, as opposed to failing to check them
+#[derive(Debug)]
+pub struct Rejected(pub String);
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
 #[derive(Debug, Deserialize)]
 struct AuthApiRes {
     user_id: String,
 }
 
-pub fn auth(server: &Arc<Server>, client: &mut Client, token: &str) -> crate::Result<String> {
-    let mut res = ureq::get(format!(
-        "https://vxchat.netlify.app/api/auth?token={token}&key={}",
-        server.config.server_key
-    ))
-    .call()
-    .context("Failed to authenticate")?;
-    let api_res: AuthApiRes = serde_json::from_str(&res.body_mut().read_to_string()?)?;
-    client.set_uuid(&api_res.user_id);
-    LOGGER.info(format!("{} successfully authenticated", api_res.user_id));
-    Ok(api_res.user_id)
+/// Talks to a service implementing the cloud auth API at `base_url`
+pub struct HttpAuth {
+    agent: ureq::Agent,
+    base_url: String,
+    server_key: String,
+    server_id: String,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl HttpAuth {
+    pub fn new(base_url: &str, config: &ServerConfig, options: &HttpOptions) -> Self {
+        let agent_config = ureq::Agent::config_builder()
+            .timeout_global(Some(Duration::from_secs(options.timeout_secs)))
+            .http_status_as_error(false)
+            .build();
+
+        Self {
+            agent: ureq::Agent::new_with_config(agent_config),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            server_key: config.server_key.clone(),
+            server_id: config.server_id.clone(),
+            retries: options.retries,
+            backoff: Duration::from_millis(options.backoff_ms),
+        }
+    }
+
+    /// GET with retries and exponential backoff, 401 and 403 are returned as [`Rejected`]
+    fn get(&self, path: &str, query: &[(&str, &str)]) -> crate::Result<String> {
+        let mut attempt = 0;
+        loop {
+            let mut req = self.agent.get(format!("{}{path}", self.base_url));
+            for (k, v) in query {
+                req = req.query(k, v);
+            }
+
+            let (e, retry_after) = match req.call() {
+                Ok(mut res) if res.status().is_success() => {
+                    return Ok(res.body_mut().read_to_string()?);
+                }
+                Ok(res) => {
+                    let code = res.status().as_u16();
+                    let retry_after = res
+                        .headers()
+                        .get("retry-after")
+                        .and_then(|v| v.to_str().ok());
+                    let e = anyhow!("Auth server answered {code}");
+                    match status_action(code, retry_after) {
+                        StatusAction::Reject => {
+                            return Err(Rejected(format!(
+                                "Auth server rejected the request ({code})"
+                            ))
+                            .into());
+                        }
+                        StatusAction::Retry(delay) => (e, delay),
+                        StatusAction::Fail => return Err(e.context("Failed to authenticate")),
+                    }
+                }
+                Err(e) => (e.into(), None),
+            };
+
+            if attempt >= self.retries {
+                return Err(e.context("Failed to authenticate"));
+            }
+            let delay = retry_after.unwrap_or(self.backoff * 2u32.pow(attempt));
+            LOGGER.warn(format!("Auth request failed, retrying in {delay:?}: {e}"));
+            std::thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+}
+
+/// What to do about an error status from the auth server
+#[derive(Debug, PartialEq)]
+enum StatusAction {
+    /// The credentials were refused
+    Reject,
+    /// Try again, after the server's `Retry-After` if it sent one
+    Retry(Option<Duration>),
+    /// Retrying won't help
+    Fail,
+}
+
+fn status_action(code: u16, retry_after: Option<&str>) -> StatusAction {
+    match code {
+        401 | 403 => StatusAction::Reject,
+        408 | 429 | 500..=599 => match retry_after.map(|v| v.trim().parse::<u64>()) {
+            // Longer than a handshake should wait
+            Some(Ok(secs)) if secs > MAX_RETRY_AFTER_SECS => StatusAction::Fail,
+            Some(Ok(secs)) => StatusAction::Retry(Some(Duration::from_secs(secs))),
+            // HTTP dates fall back to the backoff
+            Some(Err(_)) | None => StatusAction::Retry(None),
+        },
+        _ => StatusAction::Fail,
+    }
+}
+
+impl AuthProvider for HttpAuth {
+    fn authenticate(&self, token: &str) -> crate::Result<String> {
+        let body = self.get("/api/auth", &[("token", token), ("key", &self.server_key)])?;
+        let api_res: AuthApiRes = serde_json::from_str(&body)?;
+        Ok(api_res.user_id)
+    }
+
+    fn test(&self) -> crate::Result<()> {
+        self.get(
+            "/api/auth-test",
+            &[("key", &self.server_key), ("id", &self.server_id)],
+        )?;
+        Ok(())
+    }
+}
+
+/// The Axiom Cloud API
+pub struct CloudAuth(HttpAuth);
+
+impl CloudAuth {
+    pub fn new(config: &ServerConfig, options: &HttpOptions) -> Self {
+        Self(HttpAuth::new(CLOUD_URL, config, options))
+    }
+}
+
+impl AuthProvider for CloudAuth {
+    fn authenticate(&self, token: &str) -> crate::Result<String> {
+        self.0.authenticate(token)
+    }
+
+    fn test(&self) -> crate::Result<()> {
+        self.0.test()
+    }
+}
+
+/// Remembers validated tokens so reconnects don't hit the auth server every time
+pub struct CachedAuth {
+    inner: Box<dyn AuthProvider>,
+    ttl: Duration,
+    // token -> (user id, validated at)
+    cache: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl CachedAuth {
+    const MAX_ENTRIES: usize = 10_000;
+
+    pub fn wrap(inner: Box<dyn AuthProvider>, options: &HttpOptions) -> Box<dyn AuthProvider> {
+        if options.cache_ttl_secs == 0 {
+            return inner;
+        }
+
+        Box::new(Self {
+            inner,
+            ttl: Duration::from_secs(options.cache_ttl_secs),
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+impl AuthProvider for CachedAuth {
+    fn authenticate(&self, token: &str) -> crate::Result<String> {
+        if let Some((user_id, validated)) = self.cache.lock().unwrap().get(token)
+            && validated.elapsed() < self.ttl
+        {
+            return Ok(user_id.clone());
+        }
+
+        let user_id = self.inner.authenticate(token)?;
+
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, (_, validated)| validated.elapsed() < self.ttl);
+        if cache.len() >= Self::MAX_ENTRIES
+            && let Some(oldest) = cache
+                .iter()
+                .min_by_key(|(_, (_, validated))| *validated)
+                .map(|(token, _)| token.clone())
+        {
+            cache.remove(&oldest);
+        }
+        cache.insert(token.to_string(), (user_id.clone(), Instant::now()));
+
+        Ok(user_id)
+    }
+
+    fn test(&self) -> crate::Result<()> {
+        self.inner.test()
+    }
+
+    fn login(&self, username: &str, password: &str) -> crate::Result<Session> {
+        self.inner.login(username, password)
+    }
+}
+
+/// Users from a static JSON file: `{ "<token>": "<user id>" }`
+pub struct LocalAuth {
+    users: HashMap<String, String>,
+}
+
+impl LocalAuth {
+    pub fn load(path: &Path) -> crate::Result<Self> {
+        let read = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read users file {path:?}"))?;
+        Ok(Self {
+            users: serde_json::from_str(&read)?,
+        })
+    }
+}
+
+impl AuthProvider for LocalAuth {
+    fn authenticate(&self, token: &str) -> crate::Result<String> {
+        self.users
+            .get(token)
+            .cloned()
+            .ok_or_else(|| Rejected("Unknown token".to_string()).into())
+    }
+
+    fn test(&self) -> crate::Result<()> {
+        Ok(())
+    }
+}
+
+/// Local accounts, clients log in once and then reuse the session token they get back
+pub struct DatabaseAuth {
+    db: Arc<Database>,
+    session_secs: i64,
+}
+
+impl AuthProvider for DatabaseAuth {
+    fn authenticate(&self, token: &str) -> crate::Result<String> {
+        let now = chrono::Utc::now().timestamp();
+        self.db
+            .get_session_user(&hash_token(token), now)?
+            .ok_or_else(|| Rejected("Invalid or expired session token".to_string()).into())
+    }
+
+    fn test(&self) -> crate::Result<()> {
+        Ok(())
+    }
+
+    fn login(&self, username: &str, password: &str) -> crate::Result<Session> {
+        let user = self
+            .db
+            .get_user_by_name(username)?
+            .filter(|user| verify_password(password, &user.password_hash))
+            .ok_or_else(|| Rejected("Invalid username or password".to_string()))?;
+
+        let now = chrono::Utc::now().timestamp();
+        self.db.delete_expired_sessions(now)?;
+
+        let token = Base64.encode(rand::random::<[u8; 32]>());
+        self.db
+            .insert_session(&hash_token(&token), &user.id, now + self.session_secs)?;
+
+        Ok(Session {
+            user_id: user.id,
+            token,
+        })
+    }
+}
+
+/// Uses the token itself as the user id
+pub struct TestAuth;
+
+impl AuthProvider for TestAuth {
+    fn authenticate(&self, token: &str) -> crate::Result<String> {
+        if token.is_empty() {
+            return Err(Rejected("Empty token".to_string()).into());
+        }
+        Ok(token.to_string())
+    }
+
+    fn test(&self) -> crate::Result<()> {
+        LOGGER.warn("Using the test auth provider, every token is accepted");
+        Ok(())
+    }
+}
+
+/// Validates the token off the async runtime, providers may block on network or hashing
+pub async fn auth(server: &Arc<Server>, client: &mut Client, token: &str) -> crate::Result<String> {
+    let srv = server.clone();
+    let token = token.to_string();
+    let user_id = tokio::task::spawn_blocking(move || srv.auth.authenticate(&token)).await??;
+    client.set_uuid(&user_id);
+    LOGGER.info(format!("{user_id} successfully authenticated"));
+    Ok(user_id)
+}
+
+pub async fn login(
+    server: &Arc<Server>,
+    client: &mut Client,
+    login: &Login,
+) -> crate::Result<Session> {
+    let srv = server.clone();
+    let login = login.clone();
+    let session =
+        tokio::task::spawn_blocking(move || srv.auth.login(&login.username, &login.password))
+            .await??;
+    client.set_uuid(&session.user_id);
+    LOGGER.info(format!("{} successfully logged in", session.user_id));
+    Ok(session)
 }
 
 pub fn test(server: &Arc<Server>) -> crate::Result<()> {
-    ureq::get(format!(
-        "https://vxchat.netlify.app/api/auth-test?key={}&id={}",
-        server.config.server_key, server.config.server_id
-    ))
-    .call()
-    .context("Failed to authenticate")?;
+    server.auth.test()
+}
+
+/// Register a local account, returns its user id
+pub fn create_user(db: &Database, username: &str, password: &str) -> crate::Result<String> {
+    if db.get_user_by_name(username)?.is_some() {
+        return Err(anyhow!("User '{username}' already exists"));
+    }
+
+    let user_id = hex(&rand::random::<[u8; 16]>());
+    db.insert_user(
+        &user_id,
+        username,
+        &hash_password(password),
+        chrono::Utc::now().timestamp(),
+    )?;
+    Ok(user_id)
+}
+
+/// Change the password of a local account and revoke its sessions
+pub fn set_password(db: &Database, username: &str, password: &str) -> crate::Result<()> {
+    let user = db
+        .get_user_by_name(username)?
+        .ok_or_else(|| anyhow!("User '{username}' does not exist"))?;
+    db.set_user_password(&user.id, &hash_password(password))?;
+    db.delete_sessions(&user.id)?;
+    Ok(())
+}
 
+/// Delete a local account and revoke its sessions
+pub fn delete_user(db: &Database, username: &str) -> crate::Result<()> {
+    let user = db
+        .get_user_by_name(username)?
+        .ok_or_else(|| anyhow!("User '{username}' does not exist"))?;
+    db.delete_user(&user.id)?;
     Ok(())
 }
+
+/// `pbkdf2-sha256$<rounds>$<salt>$<hash>`
+fn hash_password(password: &str) -> String {
+    let salt = rand::random::<[u8; 16]>();
+    let hash = pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(password.as_bytes(), &salt, PBKDF2_ROUNDS);
+    format!(
+        "pbkdf2-sha256${PBKDF2_ROUNDS}${}${}",
+        Base64.encode(salt),
+        Base64.encode(hash)
+    )
+}
+
+fn verify_password(password: &str, password_hash: &str) -> bool {
+    let mut parts = password_hash.split('$');
+    let (Some("pbkdf2-sha256"), Some(rounds), Some(salt), Some(hash), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return false;
+    };
+    let (Ok(rounds), Ok(salt), Ok(hash)) = (
+        rounds.parse::<u32>(),
+        Base64.decode(salt),
+        Base64.decode(hash),
+    ) else {
+        return false;
+    };
+
+    let computed = pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(password.as_bytes(), &salt, rounds);
+
+    // Constant time comparison
+    hash.len() == computed.len()
+        && hash
+            .iter()
+            .zip(computed)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Session tokens are only stored hashed
+fn hash_token(token: &str) -> String {
+    hex(&Sha256::digest(token.as_bytes()))
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn database_auth(session_secs: i64) -> DatabaseAuth {
+        let db = Database::new(":memory:", &ServerConfig::default()).unwrap();
+        DatabaseAuth {
+            db: Arc::new(db),
+            session_secs,
+        }
+    }
+
+    /// Accepts every token but `bad` and counts how often it's asked
+    struct Counting(Arc<AtomicUsize>);
+
+    impl AuthProvider for Counting {
+        fn authenticate(&self, token: &str) -> crate::Result<String> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            match token {
+                "bad" => Err(Rejected("Unknown token".to_string()).into()),
+                _ => Ok(format!("user-{token}")),
+            }
+        }
+
+        fn test(&self) -> crate::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn cached_auth(ttl: Duration) -> (CachedAuth, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let auth = CachedAuth {
+            inner: Box::new(Counting(calls.clone())),
+            ttl,
+            cache: Mutex::new(HashMap::new()),
+        };
+        (auth, calls)
+    }
+
+    #[test]
+    fn only_401_and_403_reject() {
+        assert_eq!(status_action(401, None), StatusAction::Reject);
+        assert_eq!(status_action(403, Some("5")), StatusAction::Reject);
+
+        assert_eq!(status_action(400, None), StatusAction::Fail);
+        assert_eq!(status_action(404, None), StatusAction::Fail);
+        assert_eq!(status_action(302, None), StatusAction::Fail);
+    }
+
+    #[test]
+    fn timeouts_rate_limits_and_server_errors_retry() {
+        assert_eq!(status_action(408, None), StatusAction::Retry(None));
+        assert_eq!(status_action(500, None), StatusAction::Retry(None));
+        assert_eq!(
+            status_action(429, Some(" 2 ")),
+            StatusAction::Retry(Some(Duration::from_secs(2)))
+        );
+        assert_eq!(
+            status_action(503, Some("Wed, 21 Oct 2015 07:28:00 GMT")),
+            StatusAction::Retry(None)
+        );
+
+        // Waiting that long would stall the handshake
+        assert_eq!(status_action(429, Some("3600")), StatusAction::Fail);
+    }
+
+    #[test]
+    fn validated_tokens_are_cached_until_the_ttl() {
+        let (auth, calls) = cached_auth(Duration::from_millis(100));
+
+        assert_eq!(auth.authenticate("a").unwrap(), "user-a");
+        assert_eq!(auth.authenticate("a").unwrap(), "user-a");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(auth.authenticate("a").unwrap(), "user-a");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn rejected_tokens_are_not_cached() {
+        let (auth, calls) = cached_auth(Duration::from_secs(60));
+
+        assert!(auth.authenticate("bad").is_err());
+        assert!(auth.authenticate("bad").is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn full_cache_evicts_the_oldest_token() {
+        let (auth, calls) = cached_auth(Duration::from_secs(60));
+
+        for i in 0..CachedAuth::MAX_ENTRIES {
+            auth.authenticate(&i.to_string()).unwrap();
+        }
+        auth.authenticate("new").unwrap();
+        assert_eq!(auth.cache.lock().unwrap().len(), CachedAuth::MAX_ENTRIES);
+        assert_eq!(calls.load(Ordering::SeqCst), CachedAuth::MAX_ENTRIES + 1);
+
+        // The newest stays cached, the first one has to be checked again
+        auth.authenticate("new").unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), CachedAuth::MAX_ENTRIES + 1);
+        auth.authenticate("0").unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), CachedAuth::MAX_ENTRIES + 2);
+    }
+
+    #[test]
+    fn passwords_are_salted_and_verified() {
+        let hash = hash_password("hunter2");
+        assert!(hash.starts_with(&format!("pbkdf2-sha256${PBKDF2_ROUNDS}$")));
+        assert_ne!(hash, hash_password("hunter2"));
+
+        assert!(verify_password("hunter2", &hash));
+        assert!(!verify_password("hunter3", &hash));
+        assert!(!verify_password("hunter2", "hunter2"));
+        assert!(!verify_password("hunter2", &format!("{hash}$extra")));
+    }
+
+    #[test]
+    fn login_issues_tokens_that_authenticate() {
+        let auth = database_auth(60);
+        let user_id = create_user(&auth.db, "alice", "hunter2").unwrap();
+        assert!(create_user(&auth.db, "alice", "other").is_err());
+
+        let session = auth.login("alice", "hunter2").unwrap();
+        assert_eq!(session.user_id, user_id);
+        assert_eq!(auth.authenticate(&session.token).unwrap(), user_id);
+
+        // Only the hash is stored, so the token itself isn't a session
+        assert!(auth.authenticate(&hash_token(&session.token)).is_err());
+        assert!(auth.login("alice", "hunter3").is_err());
+        assert!(auth.login("bob", "hunter2").is_err());
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let auth = database_auth(0);
+        create_user(&auth.db, "alice", "hunter2").unwrap();
+
+        let session = auth.login("alice", "hunter2").unwrap();
+        assert!(auth.authenticate(&session.token).is_err());
+    }
+
+    #[test]
+    fn changing_the_password_revokes_sessions() {
+        let auth = database_auth(60);
+        create_user(&auth.db, "alice", "hunter2").unwrap();
+        let session = auth.login("alice", "hunter2").unwrap();
+
+        set_password(&auth.db, "alice", "hunter3").unwrap();
+        assert!(auth.authenticate(&session.token).is_err());
+        assert!(auth.login("alice", "hunter3").is_ok());
+    }
+
+    #[test]
+    fn expired_sessions_are_removed() {
+        let auth = database_auth(60);
+        auth.db.insert_session("expired", "alice", 100).unwrap();
+        auth.db.insert_session("current", "alice", 300).unwrap();
+
+        auth.db.delete_expired_sessions(200).unwrap();
+        assert_eq!(auth.db.get_session_user("expired", 0).unwrap(), None);
+        assert_eq!(
+            auth.db.get_session_user("current", 0).unwrap().as_deref(),
+            Some("alice")
+        );
+    }
+}
@@ -1,28 +1,111 @@
 use std::{
     hash::{Hash, Hasher},
-    io::{self, Read, Write},
-    net::TcpStream,
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+    sync::{
+        Mutex as AsyncMutex, Notify,
+        mpsc::{self, error::TrySendError},
+    },
+};
+
+use crate::{
+    logger,
+    server::Server,
+    types::message::{Request, WsMessage},
+    utils::{
+        deflate::{Deflater, Inflater},
+        encoding::{Decoded, Encoded, Encoding},
+    },
+};
+
+logger!(LOGGER "Client");
 
-use crate::types::message::{ClientMessage, WsMessage};
+/// How long a read may idle before the client gets pinged
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+/// How long a single write may take before the client is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Per connection limits, set through `connection` in the server config
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConnectionConfig {
+    /// Queued messages a client may stay above for `slow_grace_ms` before it's disconnected for
+    /// being too slow, twice as many disconnect it right away
+    pub max_queued_messages: usize,
+    /// Queued voice frames after which new ones are dropped
+    pub max_queued_voice_frames: usize,
+    /// How long a client may stay over `max_queued_messages`, so a short burst doesn't cost a slow
+    /// network its connection
+    pub slow_grace_ms: u64,
+    /// Largest payload of a single inbound frame
+    pub max_frame_size: usize,
+    /// Largest inbound message after reassembling its fragments
+    pub max_message_size: usize,
+    /// Most frames a single inbound message may be split into
+    pub max_fragments: usize,
+    /// Offer permessage-deflate to clients that support it
+    pub compression: bool,
+    /// Events kept per session for resuming after a reconnect
+    pub resume_buffer: usize,
+    /// How long a disconnected session can be resumed
+    pub resume_timeout_secs: u64,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            max_queued_messages: 1024,
+            max_queued_voice_frames: 32,
+            slow_grace_ms: 5000,
+            max_frame_size: 1024 * 1024,
+            max_message_size: 4 * 1024 * 1024,
+            max_fragments: 64,
+            compression: true,
+            resume_buffer: 256,
+            resume_timeout_secs: 300,
+        }
+    }
+}
 
 pub mod handshake {
     use base64::Engine;
     use base64::engine::general_purpose::STANDARD as Base64;
     use sha1::{Digest, Sha1};
-    use std::collections::HashMap;
-    use std::io::{BufRead, BufReader, Write};
-    use std::net::TcpStream;
+    use std::{collections::HashMap, sync::Arc};
+    use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+
+    use crate::{
+        server::Server,
+        utils::{
+            attachments, client::ConnectionConfig, deflate::DeflateParams, encoding::Encoding,
+        },
+    };
 
     const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
-    pub fn handle_websocket_handshake(stream: &mut TcpStream) -> std::io::Result<()> {
-        let mut reader = BufReader::new(stream.try_clone()?);
+    /// What was agreed on for an upgraded connection
+    pub struct Upgrade {
+        /// Set when permessage-deflate was negotiated
+        pub deflate: Option<DeflateParams>,
+        pub encoding: Encoding,
+    }
+
+    /// Returns `None` when the request was plain HTTP and has already been answered, files in
+    /// `attachments` are served to plain `GET /attachments/<hash>` requests
+    pub async fn handle_websocket_handshake<S: AsyncBufRead + AsyncWrite + Unpin>(
+        stream: &mut S,
+        config: &ConnectionConfig,
+        server: Option<&Arc<Server>>,
+    ) -> std::io::Result<Option<Upgrade>> {
         let mut request_line = String::new();
-        reader.read_line(&mut request_line)?;
+        stream.read_line(&mut request_line).await?;
 
         // Trim CRLF to make sure comparisons are clean
         let request_line = request_line.trim_end();
@@ -30,9 +113,9 @@ pub mod handshake {
         // Allow HEAD (used by Render for health checks)
         if request_line.starts_with("HEAD") {
             let response = "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
-            stream.write_all(response.as_bytes())?;
-            stream.flush()?;
-            return Ok(());
+            stream.write_all(response.as_bytes()).await?;
+            stream.flush().await?;
+            return Ok(None);
         }
 
         // Only proceed if it’s a GET
@@ -48,7 +131,7 @@ pub mod handshake {
         let mut line = String::new();
         loop {
             line.clear();
-            let bytes = reader.read_line(&mut line)?;
+            let bytes = stream.read_line(&mut line).await?;
             if bytes == 0 || line == "\r\n" {
                 break;
             }
@@ -63,13 +146,22 @@ pub mod handshake {
             .map(|v| v.eq_ignore_ascii_case("websocket"))
             .unwrap_or(false);
 
+        let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+        if !is_websocket_upgrade
+            && let Some(server) = server
+            && path.starts_with("/attachments/")
+        {
+            attachments::serve(stream, server, path, &headers).await?;
+            return Ok(None);
+        }
+
         if !is_websocket_upgrade {
             // Not a WebSocket request — probably a normal HTTP GET (e.g. health check)
             let response =
                 "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 2\r\n\r\nOK";
-            stream.write_all(response.as_bytes())?;
-            stream.flush()?;
-            return Ok(());
+            stream.write_all(response.as_bytes()).await?;
+            stream.flush().await?;
+            return Ok(None);
         }
 
         // Validate "Connection: Upgrade"
@@ -90,13 +182,13 @@ pub mod handshake {
         })?;
 
         // Validate version
-        if let Some(ver) = headers.get("sec-websocket-version") {
-            if ver.trim() != "13" {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    format!("Unsupported Sec-WebSocket-Version: {}", ver),
-                ));
-            }
+        if let Some(ver) = headers.get("sec-websocket-version")
+            && ver.trim() != "13"
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Unsupported Sec-WebSocket-Version: {}", ver),
+            ));
         }
 
         // Compute accept key
@@ -106,125 +198,311 @@ pub mod handshake {
         let hash = hasher.finalize();
         let accept_key = Base64.encode(hash);
 
+        // Negotiate compression
+        let deflate = headers
+            .get("sec-websocket-extensions")
+            .filter(|_| config.compression)
+            .and_then(|v| DeflateParams::negotiate(v));
+        let extensions = deflate
+            .map(|d| format!("Sec-WebSocket-Extensions: {}\r\n", d.response()))
+            .unwrap_or_default();
+
+        // Negotiate encoding, clients that don't ask for one get JSON
+        let encoding = headers
+            .get("sec-websocket-protocol")
+            .and_then(|v| Encoding::negotiate(v));
+        let protocol = encoding
+            .map(|e| format!("Sec-WebSocket-Protocol: {}\r\n", e.protocol()))
+            .unwrap_or_default();
+
         // Send response
         let response = format!(
             "HTTP/1.1 101 Switching Protocols\r\n\
              Upgrade: websocket\r\n\
              Connection: Upgrade\r\n\
-             Sec-WebSocket-Accept: {}\r\n\r\n",
-            accept_key
+             Sec-WebSocket-Accept: {}\r\n\
+             {}{}\r\n",
+            accept_key, extensions, protocol
         );
 
-        stream.write_all(response.as_bytes())?;
-        stream.flush()?;
-        Ok(())
+        stream.write_all(response.as_bytes()).await?;
+        stream.flush().await?;
+        Ok(Some(Upgrade {
+            deflate,
+            encoding: encoding.unwrap_or_default(),
+        }))
     }
 }
 
-pub struct Client(TcpStream, Option<String>, u64);
+/// An encoded WebSocket frame, shared so a broadcast only serializes once
+pub type Frame = Arc<[u8]>;
+
+enum Outbound {
+    Frame(Frame),
+    Close,
+}
+
+struct Reader {
+    stream: Box<dyn AsyncRead + Send + Unpin>,
+    /// Set when permessage-deflate was negotiated
+    inflater: Option<Inflater>,
+}
+
+/// A connected WebSocket client.
+///
+/// Writes go through bounded queues drained by a dedicated writer task, so sending never blocks
+/// the caller and frames from different tasks can't interleave. Messages are always written
+/// before voice frames, voice frames are dropped when their queue is full and a client whose
+/// message queue stays over half full for the grace period, or fills up, is disconnected.
+#[derive(Clone)]
+pub struct Client {
+    reader: Arc<AsyncMutex<Reader>>,
+    outbound: mpsc::Sender<Outbound>,
+    voice: mpsc::Sender<Frame>,
+    evict: Arc<Notify>,
+    // When the message queue went over half full, cleared once it drains again
+    behind_since: Arc<Mutex<Option<Instant>>>,
+    config: Arc<ConnectionConfig>,
+    encoding: Encoding,
+    uuid: Option<String>,
+    session_id: Option<String>,
+    id: u64,
+}
 
 impl Client {
-    /// Create a client
-    pub fn new(mut stream: TcpStream) -> crate::Result<Self> {
-        handshake::handle_websocket_handshake(&mut stream)?;
-        stream.set_read_timeout(Some(std::time::Duration::from_secs(10)))?;
-        stream.set_write_timeout(Some(std::time::Duration::from_secs(10)))?;
-        Ok(Client(stream, None, rand::random()))
+    /// Create a client, returns `None` if the connection was a plain HTTP request
+    pub async fn new<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+        stream: S,
+        config: &ConnectionConfig,
+        server: Option<&Arc<Server>>,
+    ) -> crate::Result<Option<Self>> {
+        let mut stream = BufReader::new(stream);
+        let Some(upgrade) =
+            handshake::handle_websocket_handshake(&mut stream, config, server).await?
+        else {
+            return Ok(None);
+        };
+
+        let (reader, writer) = tokio::io::split(stream);
+        let (outbound, outbound_rx) = mpsc::channel(config.max_queued_messages * 2);
+        let (voice, voice_rx) = mpsc::channel(config.max_queued_voice_frames);
+        let evict = Arc::new(Notify::new());
+        tokio::spawn(Self::write_loop(
+            writer,
+            outbound_rx,
+            voice_rx,
+            evict.clone(),
+            upgrade.deflate.as_ref().map(Deflater::new),
+        ));
+
+        Ok(Some(Client {
+            reader: Arc::new(AsyncMutex::new(Reader {
+                stream: Box::new(reader),
+                inflater: upgrade.deflate.as_ref().map(Inflater::new),
+            })),
+            outbound,
+            voice,
+            evict,
+            behind_since: Arc::new(Mutex::new(None)),
+            config: Arc::new(config.clone()),
+            encoding: upgrade.encoding,
+            uuid: None,
+            session_id: None,
+            id: rand::random(),
+        }))
     }
 
-    /// Send a close frame and flush. `code` is a WebSocket close code (e.g., 1000 normal).
-    pub fn send_close(&self, code: u16, reason: &str) -> crate::Result<()> {
-        let mut stream = self.0.try_clone()?;
+    /// Drains the outbound queues into the socket until the client is closed.
+    ///
+    /// Frames are shared between clients, so compression happens here with each connection's
+    /// own context.
+    async fn write_loop<W: AsyncWrite + Unpin>(
+        writer: W,
+        mut outbound: mpsc::Receiver<Outbound>,
+        mut voice: mpsc::Receiver<Frame>,
+        evict: Arc<Notify>,
+        mut deflater: Option<Deflater>,
+    ) {
+        let mut writer = BufWriter::new(writer);
 
-        // control frames must be <= 125 bytes
-        let mut payload = Vec::new();
-        payload.extend_from_slice(&code.to_be_bytes());
-        payload.extend_from_slice(reason.as_bytes());
-        if payload.len() > 125 {
-            return Err(anyhow!("close reason too long").into());
-        }
+        loop {
+            let frame = tokio::select! {
+                biased;
+                _ = evict.notified() => {
+                    // Whatever is still queued is dropped, the close frame goes out first
+                    let close = Self::close_frame(1008, "Too slow to keep up").unwrap();
+                    let write = async {
+                        writer.write_all(&close).await?;
+                        writer.flush().await
+                    };
+                    let _ = tokio::time::timeout(WRITE_TIMEOUT, write).await;
+                    break;
+                }
+                out = outbound.recv() => match out {
+                    Some(Outbound::Frame(frame)) => match &mut deflater {
+                        Some(deflater) => Self::compress_frame(deflater, frame),
+                        None => frame,
+                    },
+                    Some(Outbound::Close) | None => break,
+                },
+                Some(frame) = voice.recv() => frame,
+            };
 
-        let mut frame = Vec::with_capacity(2 + payload.len());
-        frame.push(0x88); // FIN=1, opcode=0x8 (Close)
-        frame.push(payload.len() as u8); // server->client MUST NOT mask
-        frame.extend_from_slice(&payload);
+            let write = async {
+                writer.write_all(&frame).await?;
+                // Batch whatever is queued into as few syscalls as possible
+                if outbound.is_empty() && voice.is_empty() {
+                    writer.flush().await?;
+                }
+                io::Result::Ok(())
+            };
 
-        stream.write_all(&frame)?;
-        stream.flush()?;
-        Ok(())
-    }
+            let written = tokio::select! {
+                res = tokio::time::timeout(WRITE_TIMEOUT, write) => matches!(res, Ok(Ok(()))),
+                // Stuck mid frame, there's no clean way to close so just drop the connection
+                _ = evict.notified() => false,
+            };
+            if !written {
+                return;
+            }
+        }
 
-    /// Send a ping (no payload)
-    fn send_ping(&self) -> crate::Result<()> {
-        let mut stream = self.0.try_clone()?;
-        // FIN + opcode (ping = 0x89), payload length = 0x00
-        stream.write_all(&[0x89, 0x00])?;
-        stream.flush()?;
-        Ok(())
+        let _ = writer.flush().await;
+        let _ = writer.shutdown().await;
     }
 
-    /// Send a pong (no payload)
-    fn send_pong(&self) -> crate::Result<()> {
-        let mut stream = self.0.try_clone()?;
-        // FIN + opcode (pong = 0x8A), payload length = 0x00
-        stream.write_all(&[0x8A, 0x00])?;
-        stream.flush()?;
-        Ok(())
+    /// Re-encode a message frame with its payload compressed, anything else is left as is
+    fn compress_frame(deflater: &mut Deflater, frame: Frame) -> Frame {
+        // Always a single FIN frame, see `encode_frame`. Voice never gets here
+        if frame[0] != 0x81 && frame[0] != 0x82 {
+            return frame;
+        }
+        let offset = match frame[1] {
+            127 => 10,
+            126 => 4,
+            _ => 2,
+        };
+
+        match deflater.compress(&frame[offset..]) {
+            // RSV1 marks the message as compressed
+            Ok(Some(payload)) => Self::encode_raw(frame[0] | 0x40, &payload),
+            Ok(None) => frame,
+            Err(e) => {
+                LOGGER.warn(e.context("Couldn't compress frame"));
+                frame
+            }
+        }
     }
 
-    /// Send a text/binary frame (server->client must NOT mask)
-    pub fn send<T: Serialize>(&self, m: T) -> crate::Result<()> {
-        let mut stream = self.0.try_clone()?;
+    /// Encode a single unmasked (server->client) frame
+    pub fn encode_frame(opcode: u8, payload: &[u8]) -> Frame {
+        // FIN=1
+        Self::encode_raw(0x80 | opcode, payload)
+    }
 
-        let payload = serde_json::to_string(&m)?;
-        let payload_bytes = payload.as_bytes();
-        let len = payload_bytes.len();
+    fn encode_raw(first: u8, payload: &[u8]) -> Frame {
+        let len = payload.len();
+        let mut frame = Vec::with_capacity(10 + len);
 
-        let mut header = Vec::new();
-        header.push(0x81); // FIN=1, opcode=0x1 (text)
+        frame.push(first);
 
         if len < 126 {
-            header.push(len as u8);
-        } else if len <= 65535 {
-            header.push(126);
-            header.extend_from_slice(&(len as u16).to_be_bytes());
+            frame.push(len as u8); // mask bit = 0
+        } else if len <= 0xFFFF {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
         } else {
-            header.push(127);
-            header.extend_from_slice(&(len as u64).to_be_bytes());
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
         }
 
-        stream.write_all(&header)?;
-        stream.write_all(payload_bytes)?;
-        stream.flush()?;
-        Ok(())
+        frame.extend_from_slice(payload);
+        frame.into()
     }
 
-    /// Send a binary WebSocket frame (server -> client)
-    pub fn send_bin(&self, payload: &[u8]) -> crate::Result<()> {
-        let mut stream = self.0.try_clone()?;
+    /// Encode a close frame. `code` is a WebSocket close code (e.g., 1000 normal).
+    fn close_frame(code: u16, reason: &str) -> crate::Result<Frame> {
+        // control frames must be <= 125 bytes
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&code.to_be_bytes());
+        payload.extend_from_slice(reason.as_bytes());
+        if payload.len() > 125 {
+            return Err(anyhow!("close reason too long"));
+        }
 
-        let mut header = Vec::with_capacity(10);
+        Ok(Self::encode_frame(0x8, &payload))
+    }
 
-        // FIN=1, opcode=2 (binary)
-        header.push(0x82);
+    /// Whether the client has been too far behind for longer than the grace period
+    fn stayed_behind(&self) -> bool {
+        let queued = self.outbound.max_capacity() - self.outbound.capacity();
+        let mut behind_since = self.behind_since.lock().unwrap();
+        if queued <= self.outbound.max_capacity() / 2 {
+            *behind_since = None;
+            return false;
+        }
 
-        let len = payload.len();
+        let grace = Duration::from_millis(self.config.slow_grace_ms);
+        behind_since.get_or_insert_with(Instant::now).elapsed() > grace
+    }
 
-        if len < 126 {
-            header.push(len as u8); // mask bit = 0
-        } else if len <= 0xFFFF {
-            header.push(126);
-            header.extend_from_slice(&(len as u16).to_be_bytes());
-        } else {
-            header.push(127);
-            header.extend_from_slice(&(len as u64).to_be_bytes());
+    fn queue(&self, out: Outbound) -> crate::Result<()> {
+        let sent = match self.stayed_behind() {
+            true => Err(TrySendError::Full(out)),
+            false => self.outbound.try_send(out),
+        };
+        match sent {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                LOGGER.warn(format!("Client ({}) is too slow, disconnecting", self.id));
+                self.evict.notify_one();
+                Err(anyhow!("Client ({}) is too slow", self.id))
+            }
+            Err(TrySendError::Closed(_)) => Err(anyhow!("Client ({}) disconnected", self.id)),
         }
+    }
+
+    /// Queue an already encoded frame
+    pub fn send_frame(&self, frame: Frame) -> crate::Result<()> {
+        self.queue(Outbound::Frame(frame))
+    }
 
-        stream.write_all(&header)?;
-        stream.write_all(payload)?;
-        stream.flush()?;
+    /// Queue an encoded voice frame, it's dropped if the client is falling behind
+    pub fn send_voice(&self, frame: Frame) -> crate::Result<()> {
+        match self.voice.try_send(frame) {
+            Ok(()) | Err(TrySendError::Full(_)) => Ok(()),
+            Err(TrySendError::Closed(_)) => Err(anyhow!("Client ({}) disconnected", self.id)),
+        }
+    }
 
-        Ok(())
+    /// Send a close frame and close the connection. `code` is a WebSocket close code (e.g., 1000 normal).
+    pub fn send_close(&self, code: u16, reason: &str) -> crate::Result<()> {
+        self.send_frame(Self::close_frame(code, reason)?)?;
+        self.close()
+    }
+
+    /// Send a ping (no payload)
+    fn send_ping(&self) -> crate::Result<()> {
+        self.send_frame(Self::encode_frame(0x9, &[]))
+    }
+
+    /// Send a pong (no payload)
+    fn send_pong(&self) -> crate::Result<()> {
+        self.send_frame(Self::encode_frame(0xA, &[]))
+    }
+
+    /// Send a message in the encoding of this connection (server->client must NOT mask)
+    pub fn send<T: Serialize>(&self, m: T) -> crate::Result<()> {
+        self.send_frame(self.encoding.encode(&m)?)
+    }
+
+    /// Send a message that's shared with other clients
+    pub fn send_encoded<T: Serialize>(&self, m: &Encoded<T>) -> crate::Result<()> {
+        self.send_frame(m.frame(self.encoding)?)
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
     }
 
     /// Read a full WebSocket message, handling fragmentation and control frames.
@@ -233,57 +511,73 @@ impl Client {
     /// - Ok(Some(WsMessage)) on an application message (text/binary)
     /// - Ok(None) if the connection should be closed (close received / read EOF)
     /// - Err on protocol or IO errors.
-    pub fn read_t<T: Serialize + for<'de> Deserialize<'de>>(
+    pub async fn read_t<T: Serialize + for<'de> Deserialize<'de>>(
         &self,
     ) -> crate::Result<Option<WsMessage<T>>> {
-        let mut stream = self.0.try_clone()?;
+        let mut reader = self.reader.lock().await;
+        let Reader { stream, inflater } = &mut *reader;
 
         let mut message_payload = Vec::new();
         let mut expecting_continuation = false;
         let mut message_type: Option<u8> = None; // 0x1 for text, 0x2 for binary
+        let mut compressed = false;
+        let mut fragments = 0;
 
         loop {
-            // Read 2-byte header
-            let mut header = [0u8; 2];
-            match stream.read_exact(&mut header) {
-                Ok(_) => {}
-                Err(e) => match e.kind() {
-                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
-                        self.send_ping()?;
-                        continue;
-                    }
-                    io::ErrorKind::UnexpectedEof | io::ErrorKind::BrokenPipe => return Ok(None),
-                    _ => return Err(e.into()),
-                },
-            }
+            // Read 2-byte header, pinging the client while it's idle
+            let first = loop {
+                let read = tokio::select! {
+                    read = tokio::time::timeout(PING_INTERVAL, stream.read_u8()) => read,
+                    // The writer is gone (closed, evicted or broken), stop reading too
+                    _ = self.outbound.closed() => return Ok(None),
+                };
+
+                match read {
+                    Ok(Ok(b)) => break b,
+                    Ok(Err(e)) => match e.kind() {
+                        io::ErrorKind::UnexpectedEof | io::ErrorKind::BrokenPipe => {
+                            return Ok(None);
+                        }
+                        _ => return Err(e.into()),
+                    },
+                    Err(_) => self.send_ping()?,
+                }
+            };
+            let header = [first, stream.read_u8().await?];
 
             let fin = header[0] & 0x80 != 0;
+            let rsv1 = header[0] & 0x40 != 0;
             let opcode = header[0] & 0x0F;
             let masked = header[1] & 0x80 != 0;
             let mut payload_len = (header[1] & 0x7F) as u64;
 
             // Extended payload length
             if payload_len == 126 {
-                let mut ext_len = [0u8; 2];
-                stream.read_exact(&mut ext_len)?;
-                payload_len = u16::from_be_bytes(ext_len) as u64;
+                payload_len = stream.read_u16().await? as u64;
             } else if payload_len == 127 {
-                let mut ext_len = [0u8; 8];
-                stream.read_exact(&mut ext_len)?;
-                payload_len = u64::from_be_bytes(ext_len);
+                payload_len = stream.read_u64().await?;
             }
 
             // Mask key
             let mut mask = [0u8; 4];
             if masked {
-                stream.read_exact(&mut mask)?;
+                stream.read_exact(&mut mask).await?;
             } else {
                 let _ = self.send_close(1002, "Client frames must be masked");
                 return Ok(None);
             }
 
+            // Reserved bits, RSV1 only means compressed on the first frame of a data message
+            if header[0] & 0x30 != 0
+                || (rsv1 && (inflater.is_none() || !matches!(opcode, 0x1 | 0x2)))
+            {
+                let _ = self.send_close(1002, "Reserved bits set");
+                return Ok(None);
+            }
+            compressed |= rsv1;
+
             // Control frame checks
-            if matches!(opcode, 0x8 | 0x9 | 0xA) {
+            if matches!(opcode, 0x8..=0xA) {
                 if payload_len > 125 {
                     let _ = self.send_close(1002, "Control frame too large");
                     return Ok(None);
@@ -292,14 +586,30 @@ impl Client {
                     let _ = self.send_close(1002, "Control frames must not be fragmented");
                     return Ok(None);
                 }
+            } else {
+                // Limits are checked before anything gets allocated for the payload
+                fragments += 1;
+                if payload_len > self.config.max_frame_size as u64 {
+                    let _ = self.send_close(1009, "Frame too large");
+                    return Ok(None);
+                }
+                if message_payload.len() as u64 + payload_len > self.config.max_message_size as u64
+                {
+                    let _ = self.send_close(1009, "Message too large");
+                    return Ok(None);
+                }
+                if fragments > self.config.max_fragments {
+                    let _ = self.send_close(1009, "Too many fragments");
+                    return Ok(None);
+                }
             }
 
             // Read payload
             let mut payload = vec![0u8; payload_len as usize];
             if payload_len > 0 {
-                stream.read_exact(&mut payload)?;
-                for i in 0..payload.len() {
-                    payload[i] ^= mask[i % 4];
+                stream.read_exact(&mut payload).await?;
+                for (i, b) in payload.iter_mut().enumerate() {
+                    *b ^= mask[i % 4];
                 }
             }
 
@@ -377,20 +687,33 @@ impl Client {
             }
         }
 
-        // Convert payload into proper message type
-        let message = match message_type {
-            Some(0x1) => {
-                // Text frame → try JSON, otherwise keep text
-                match String::from_utf8(message_payload.clone()) {
-                    Ok(text) => match serde_json::from_str(&text) {
-                        Ok(msg) => WsMessage::Message(msg),
-                        Err(_) => WsMessage::String(text),
-                    },
-                    Err(_) => WsMessage::Binary(message_payload),
+        if compressed && let Some(inflater) = inflater {
+            match inflater.decompress(&message_payload, self.config.max_message_size) {
+                Ok(Some(payload)) => message_payload = payload,
+                Ok(None) => {
+                    let _ = self.send_close(1009, "Message too large");
+                    return Ok(None);
+                }
+                Err(_) => {
+                    let _ = self.send_close(1007, "Invalid compressed data");
+                    return Ok(None);
                 }
             }
-            Some(0x2) => WsMessage::Binary(message_payload),
-            _ => return Ok(None), // Should not happen
+        }
+
+        // Convert payload into proper message type
+        let Some(opcode) = message_type else {
+            return Ok(None); // Should not happen
+        };
+        let message = match self.encoding.decode(opcode, message_payload) {
+            Decoded::Message(msg) => WsMessage::Message(msg),
+            Decoded::Voice(data) => WsMessage::Binary(data),
+            Decoded::Upload(chunk) => WsMessage::Upload(chunk),
+            Decoded::Malformed(reason) => WsMessage::Malformed(reason),
+            Decoded::Invalid(reason) => {
+                let _ = self.send_close(1007, reason);
+                return Ok(None);
+            }
         };
 
         Ok(Some(message))
@@ -402,40 +725,46 @@ impl Client {
     /// - Ok(Some(WsMessage)) on an application message (text/binary)
     /// - Ok(None) if the connection should be closed (close received / read EOF)
     /// - Err on protocol or IO errors.
-    pub fn read(&self) -> crate::Result<Option<WsMessage<ClientMessage>>> {
-        self.read_t()
+    pub async fn read(&self) -> crate::Result<Option<WsMessage<Request>>> {
+        self.read_t().await
     }
 
     pub fn get_uuid(&self) -> crate::Result<String> {
-        match &self.1 {
+        match &self.uuid {
             Some(v) => Ok(v.clone()),
-            None => Err(anyhow!("Client ({}) UUID not set", self.2).into()),
+            None => Err(anyhow!("Client ({}) UUID not set", self.id)),
         }
     }
 
     pub fn set_uuid(&mut self, uuid: &str) {
-        self.1 = Some(uuid.to_string())
+        self.uuid = Some(uuid.to_string())
     }
 
+    pub fn get_session_id(&self) -> crate::Result<String> {
+        match &self.session_id {
+            Some(v) => Ok(v.clone()),
+            None => Err(anyhow!("Client ({}) session not started", self.id)),
+        }
+    }
+
+    pub fn set_session_id(&mut self, session_id: &str) {
+        self.session_id = Some(session_id.to_string())
+    }
+
+    /// Flush whatever is queued and close the connection
     pub fn close(&self) -> crate::Result<()> {
-        self.0.shutdown(std::net::Shutdown::Both)?;
-        Ok(())
+        self.queue(Outbound::Close)
     }
-}
 
-impl Clone for Client {
-    fn clone(&self) -> Self {
-        Client(
-            self.0.try_clone().expect("failed to clone TcpStream"),
-            self.1.clone(),
-            self.2.clone(),
-        )
+    /// Wait until the writer has flushed and closed the connection
+    pub async fn closed(&self) {
+        self.outbound.closed().await
     }
 }
 
 impl PartialEq for Client {
     fn eq(&self, other: &Self) -> bool {
-        self.2 == other.2
+        self.id == other.id
     }
 }
 
@@ -443,6 +772,401 @@ impl Eq for Client {}
 
 impl Hash for Client {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.2.hash(state);
+        self.id.hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::*;
+    use crate::{types::message::ClientMessage, utils::deflate::DeflateParams};
+
+    /// Connect a client over an in-memory pipe, returns the server side client and the peer
+    async fn pair(config: ConnectionConfig) -> (Client, BufReader<DuplexStream>) {
+        pair_with(config, "").await
+    }
+
+    /// Like `pair`, with extra request headers in the handshake
+    async fn pair_with(
+        config: ConnectionConfig,
+        headers: &str,
+    ) -> (Client, BufReader<DuplexStream>) {
+        let (server, peer) = tokio::io::duplex(64 * 1024);
+        let mut peer = BufReader::new(peer);
+        let request = format!(
+            "GET / HTTP/1.1\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\n\
+             {headers}\r\n"
+        );
+        peer.write_all(request.as_bytes()).await.unwrap();
+
+        let client = Client::new(server, &config, None).await.unwrap().unwrap();
+
+        // Skip the 101 response
+        let mut line = String::new();
+        while line != "\r\n" {
+            line.clear();
+            tokio::io::AsyncBufReadExt::read_line(&mut peer, &mut line)
+                .await
+                .unwrap();
+        }
+
+        (client, peer)
+    }
+
+    /// A masked client->server frame
+    fn client_frame(fin: bool, opcode: u8, len: u64, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![if fin { 0x80 } else { 0x00 } | opcode];
+        if len < 126 {
+            frame.push(0x80 | len as u8);
+        } else if len <= 0xFFFF {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&len.to_be_bytes());
+        }
+
+        let mask = [1, 2, 3, 4];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    /// Read a server->client frame, returns its first byte (FIN, RSV and opcode) and payload
+    async fn read_frame(peer: &mut BufReader<DuplexStream>) -> (u8, Vec<u8>) {
+        let header = [peer.read_u8().await.unwrap(), peer.read_u8().await.unwrap()];
+        let len = match header[1] & 0x7F {
+            126 => peer.read_u16().await.unwrap() as usize,
+            127 => peer.read_u64().await.unwrap() as usize,
+            len => len as usize,
+        };
+        let mut payload = vec![0u8; len];
+        peer.read_exact(&mut payload).await.unwrap();
+        (header[0], payload)
+    }
+
+    /// Read frames until the close frame and return its code
+    async fn read_close_code(peer: &mut BufReader<DuplexStream>) -> u16 {
+        loop {
+            let (first, payload) = read_frame(peer).await;
+            if first & 0x0F == 0x8 {
+                return u16::from_be_bytes([payload[0], payload[1]]);
+            }
+        }
+    }
+
+    fn limited() -> ConnectionConfig {
+        ConnectionConfig {
+            max_frame_size: 1024,
+            max_message_size: 4096,
+            max_fragments: 8,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn reassembles_fragments_within_limits() {
+        let (client, mut peer) = pair(limited()).await;
+        let json = br#"{"type":"typing","params":{"channel_id":"general"}}"#;
+        let (a, b) = json.split_at(20);
+        peer.write_all(&client_frame(false, 0x1, a.len() as u64, a))
+            .await
+            .unwrap();
+        peer.write_all(&client_frame(true, 0x0, b.len() as u64, b))
+            .await
+            .unwrap();
+
+        let msg = client.read().await.unwrap();
+        assert!(matches!(
+            msg,
+            Some(WsMessage::Message(Request {
+                message: ClientMessage::Typing { channel_id },
+                request_id: None,
+            })) if channel_id == "general"
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_huge_frame_without_allocating() {
+        let (client, mut peer) = pair(limited()).await;
+        // Claims a terabyte of payload, none of it is ever sent
+        peer.write_all(&client_frame(true, 0x2, 1 << 40, &[]))
+            .await
+            .unwrap();
+
+        assert!(client.read().await.unwrap().is_none());
+        assert_eq!(read_close_code(&mut peer).await, 1009);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_message() {
+        let (client, mut peer) = pair(limited()).await;
+        let chunk = [b'a'; 1000];
+        peer.write_all(&client_frame(false, 0x1, 1000, &chunk))
+            .await
+            .unwrap();
+        for _ in 0..4 {
+            peer.write_all(&client_frame(false, 0x0, 1000, &chunk))
+                .await
+                .unwrap();
+        }
+
+        assert!(client.read().await.unwrap().is_none());
+        assert_eq!(read_close_code(&mut peer).await, 1009);
+    }
+
+    #[tokio::test]
+    async fn rejects_too_many_fragments() {
+        let (client, mut peer) = pair(limited()).await;
+        peer.write_all(&client_frame(false, 0x1, 1, b"a"))
+            .await
+            .unwrap();
+        for _ in 0..8 {
+            peer.write_all(&client_frame(false, 0x0, 1, b"a"))
+                .await
+                .unwrap();
+        }
+
+        assert!(client.read().await.unwrap().is_none());
+        assert_eq!(read_close_code(&mut peer).await, 1009);
+    }
+
+    #[tokio::test]
+    async fn slow_consumer_is_evicted() {
+        let config = ConnectionConfig {
+            max_queued_messages: 4,
+            max_queued_voice_frames: 2,
+            ..Default::default()
+        };
+        // The peer never reads, so the pipe fills up and the writer stalls
+        let (client, _peer) = pair(config).await;
+        let frame = Client::encode_frame(0x1, &[b'a'; 32 * 1024]);
+
+        let mut sent = 0;
+        while client.send_frame(frame.clone()).is_ok() {
+            sent += 1;
+            assert!(sent < 100, "queue never filled up");
+            tokio::task::yield_now().await;
+        }
+
+        tokio::time::timeout(Duration::from_secs(1), client.closed())
+            .await
+            .expect("slow client wasn't disconnected");
+        assert!(client.send_frame(frame).is_err());
+    }
+
+    #[tokio::test]
+    async fn full_message_queue_closes_with_1008() {
+        let config = ConnectionConfig {
+            max_queued_messages: 4,
+            ..Default::default()
+        };
+        let (client, mut peer) = pair(config).await;
+        let frame = Client::encode_frame(0x1, b"{}");
+
+        // The writer doesn't get to run in between, so the ninth frame doesn't fit
+        for _ in 0..8 {
+            client.send_frame(frame.clone()).unwrap();
+        }
+        assert!(client.send_frame(frame).is_err());
+
+        // The close frame goes out before anything that was still queued
+        assert_eq!(read_close_code(&mut peer).await, 1008);
+    }
+
+    #[tokio::test]
+    async fn short_burst_is_survived() {
+        let config = ConnectionConfig {
+            max_queued_messages: 4,
+            slow_grace_ms: 100,
+            ..Default::default()
+        };
+        let (client, mut peer) = pair(config).await;
+        let frame = Client::encode_frame(0x1, b"{}");
+
+        // Over the limit, but the writer catches up before the grace period ends
+        for _ in 0..6 {
+            client.send_frame(frame.clone()).unwrap();
+        }
+        for _ in 0..6 {
+            read_frame(&mut peer).await;
+        }
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        for _ in 0..6 {
+            client.send_frame(frame.clone()).unwrap();
+        }
+        for _ in 0..6 {
+            assert_eq!(read_frame(&mut peer).await.0 & 0x0F, 0x1);
+        }
+    }
+
+    #[tokio::test]
+    async fn staying_behind_past_the_grace_period_is_evicted() {
+        let config = ConnectionConfig {
+            max_queued_messages: 8,
+            slow_grace_ms: 50,
+            ..Default::default()
+        };
+        // The peer never reads, the pipe only takes two of these
+        let (client, _peer) = pair(config).await;
+        let frame = Client::encode_frame(0x1, &[b'a'; 32 * 1024]);
+
+        for _ in 0..12 {
+            client.send_frame(frame.clone()).unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Still short of the limit, but behind for too long
+        assert!(client.send_frame(frame.clone()).is_err());
+        tokio::time::timeout(Duration::from_secs(1), client.closed())
+            .await
+            .expect("slow client wasn't disconnected");
+    }
+
+    #[tokio::test]
+    async fn voice_frames_are_dropped_under_pressure() {
+        let config = ConnectionConfig {
+            max_queued_messages: 4,
+            max_queued_voice_frames: 2,
+            ..Default::default()
+        };
+        let (client, mut peer) = pair(config).await;
+        let frame = Client::encode_frame(0x2, &[0u8; 32 * 1024]);
+
+        for _ in 0..100 {
+            client.send_voice(frame.clone()).unwrap();
+        }
+        assert_eq!(client.voice.max_capacity() - client.voice.capacity(), 2);
+
+        // Still connected, messages still go through
+        client
+            .send(ClientMessage::Typing {
+                channel_id: "general".to_string(),
+            })
+            .unwrap();
+
+        let mut voice = 0;
+        let mut messages = Vec::new();
+        while let Ok((first, payload)) =
+            tokio::time::timeout(Duration::from_millis(200), read_frame(&mut peer)).await
+        {
+            match first & 0x0F {
+                0x1 => messages.push(String::from_utf8(payload).unwrap()),
+                0x2 => voice += 1,
+                _ => {}
+            }
+        }
+        assert_eq!(voice, 2);
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].contains(r#""type":"typing""#));
+    }
+
+    const DEFLATE: &str = "Sec-WebSocket-Extensions: permessage-deflate\r\n";
+
+    #[tokio::test]
+    async fn compressed_messages_round_trip() {
+        let (client, mut peer) = pair_with(ConnectionConfig::default(), DEFLATE).await;
+        let params = DeflateParams::default();
+
+        // Inbound, RSV1 marks the message as compressed
+        let channel_id = "a".repeat(200);
+        let json = format!(r#"{{"type":"typing","params":{{"channel_id":"{channel_id}"}}}}"#);
+        let compressed = Deflater::new(&params)
+            .compress(json.as_bytes())
+            .unwrap()
+            .unwrap();
+        peer.write_all(&client_frame(
+            true,
+            0x40 | 0x1,
+            compressed.len() as u64,
+            &compressed,
+        ))
+        .await
+        .unwrap();
+        assert!(matches!(
+            client.read().await.unwrap(),
+            Some(WsMessage::Message(Request {
+                message: ClientMessage::Typing { channel_id: id },
+                ..
+            })) if id == channel_id
+        ));
+
+        // Outbound, the server keeps its context between messages
+        let mut inflater = Inflater::new(&params);
+        for _ in 0..2 {
+            client
+                .send(ClientMessage::Typing {
+                    channel_id: channel_id.clone(),
+                })
+                .unwrap();
+            let (first, payload) = read_frame(&mut peer).await;
+            assert_eq!(first, 0x80 | 0x40 | 0x1);
+            let inflated = inflater.decompress(&payload, 1 << 20).unwrap().unwrap();
+            assert!(String::from_utf8(inflated).unwrap().contains(&channel_id));
+        }
+    }
+
+    #[tokio::test]
+    async fn corrupt_compressed_data_closes_with_1007() {
+        let (client, mut peer) = pair_with(ConnectionConfig::default(), DEFLATE).await;
+        peer.write_all(&client_frame(true, 0x40 | 0x1, 16, &[0xff; 16]))
+            .await
+            .unwrap();
+
+        assert!(client.read().await.unwrap().is_none());
+        assert_eq!(read_close_code(&mut peer).await, 1007);
+    }
+
+    /// Fan-out throughput of the per-client queues, run with
+    /// `cargo test --release fanout_throughput -- --ignored --nocapture`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn fanout_throughput() {
+        const CLIENTS: usize = 100;
+        const MESSAGES: usize = 2_000;
+
+        let mut clients = Vec::new();
+        let mut drains = Vec::new();
+        let frame = Client::encode_frame(0x2, &[0u8; 160]); // ~20ms of opus
+        let config = ConnectionConfig {
+            max_queued_messages: MESSAGES,
+            ..Default::default()
+        };
+        for _ in 0..CLIENTS {
+            let (client, mut peer) = pair(config.clone()).await;
+            let expected = frame.len() * MESSAGES;
+            drains.push(tokio::spawn(async move {
+                let mut buf = vec![0u8; 64 * 1024];
+                let mut read = 0;
+                while read < expected {
+                    read += peer.read(&mut buf).await.unwrap();
+                }
+            }));
+            clients.push(client);
+        }
+
+        let start = Instant::now();
+        for _ in 0..MESSAGES {
+            for c in &clients {
+                c.send_frame(frame.clone()).unwrap();
+            }
+        }
+        for d in drains {
+            d.await.unwrap();
+        }
+        let elapsed = start.elapsed();
+
+        let delivered = CLIENTS * MESSAGES;
+        println!(
+            "fan-out: {delivered} frames to {CLIENTS} clients in {elapsed:?} ({:.0} frames/s)",
+            delivered as f64 / elapsed.as_secs_f64()
+        );
     }
 }
@@ -1,12 +1,32 @@
-use crate::{ServerConfig, types::data::Message};
-use rusqlite::{Connection, Result, params};
+use std::collections::HashSet;
 
-pub struct Database(Connection);
+use crate::{
+    ServerConfig,
+    types::data::{
+        Attachment, Channel, ChannelKind, Message, Overwrite, OverwriteTarget, Reaction, Role,
+        SearchHit, Thread,
+    },
+    utils::{attachments::Stored, permissions::Permissions},
+};
+use rusqlite::{Connection, OptionalExtension, Result, Row, ToSql, params, params_from_iter};
+use std::{
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+/// The role every user has without it being assigned
+pub const EVERYONE_ROLE: i64 = 0;
+/// Messages in a chunk, chunks are counted back from the newest message
+pub const CHUNK_SIZE: usize = 16;
+
+/// One connection shared by every thread, queries take turns on it
+pub struct Database(Mutex<Connection>);
 
 // General use case
 impl Database {
-    pub fn new(_config: &ServerConfig) -> Option<Self> {
-        let conn = Connection::open("main.db").ok()?;
+    /// Opens or creates the database at `path`, `:memory:` for one that isn't saved
+    pub fn new(path: impl AsRef<Path>, config: &ServerConfig) -> Result<Self> {
+        let conn = Connection::open(path)?;
 
         conn.execute(
             "CREATE TABLE IF NOT EXISTS chat (
@@ -17,10 +37,750 @@ impl Database {
                   timestamp   INTEGER NOT NULL
                 )",
             [],
+        )?;
+        add_column(&conn, "chat", "reply_to", "INTEGER")?;
+        add_column(&conn, "chat", "thread_id", "INTEGER")?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS chat_channel ON chat (channel_id, id)",
+            [],
+        )?;
+
+        // The search index only points at `chat`, the triggers keep it in sync
+        let indexed = conn
+            .prepare("SELECT 1 FROM sqlite_master WHERE name = 'chat_search'")
+            .and_then(|mut stmt| stmt.exists([]))?;
+        conn.execute_batch(
+            "CREATE VIRTUAL TABLE IF NOT EXISTS chat_search USING fts5(
+                  contents,
+                  content = 'chat',
+                  content_rowid = 'id',
+                  tokenize = 'unicode61 remove_diacritics 2'
+                );
+            CREATE TRIGGER IF NOT EXISTS chat_search_insert AFTER INSERT ON chat BEGIN
+                INSERT INTO chat_search (rowid, contents) VALUES (new.id, new.contents);
+            END;
+            CREATE TRIGGER IF NOT EXISTS chat_search_delete AFTER DELETE ON chat BEGIN
+                INSERT INTO chat_search (chat_search, rowid, contents)
+                VALUES ('delete', old.id, old.contents);
+            END;
+            CREATE TRIGGER IF NOT EXISTS chat_search_update AFTER UPDATE OF contents ON chat BEGIN
+                INSERT INTO chat_search (chat_search, rowid, contents)
+                VALUES ('delete', old.id, old.contents);
+                INSERT INTO chat_search (rowid, contents) VALUES (new.id, new.contents);
+            END;",
+        )?;
+        if !indexed {
+            // Messages from before the index existed
+            conn.execute(
+                "INSERT INTO chat_search (chat_search) VALUES ('rebuild')",
+                [],
+            )?;
+        }
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS attachments (
+                  id            INTEGER PRIMARY KEY AUTOINCREMENT,
+                  hash          TEXT NOT NULL,
+                  name          TEXT NOT NULL,
+                  size          INTEGER NOT NULL,
+                  content_type  TEXT,
+                  user_id       TEXT NOT NULL,
+                  message_id    INTEGER,
+                  timestamp     INTEGER NOT NULL
+                )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS reactions (
+                  message_id  INTEGER NOT NULL,
+                  emoji       TEXT NOT NULL,
+                  user_id     TEXT NOT NULL,
+                  PRIMARY KEY (message_id, emoji, user_id)
+                )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS threads (
+                  id          INTEGER PRIMARY KEY,
+                  channel_id  TEXT NOT NULL,
+                  name        TEXT NOT NULL,
+                  user_id     TEXT NOT NULL,
+                  timestamp   INTEGER NOT NULL
+                )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS users (
+                  id             TEXT PRIMARY KEY,
+                  username       TEXT NOT NULL UNIQUE,
+                  password_hash  TEXT NOT NULL,
+                  created        INTEGER NOT NULL
+                )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                  token_hash  TEXT PRIMARY KEY,
+                  user_id     TEXT NOT NULL,
+                  expires     INTEGER NOT NULL
+                )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS members (
+                  user_id  TEXT PRIMARY KEY,
+                  joined   INTEGER NOT NULL
+                )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS roles (
+                  id           INTEGER PRIMARY KEY AUTOINCREMENT,
+                  name         TEXT NOT NULL UNIQUE,
+                  permissions  INTEGER NOT NULL
+                )",
+            [],
+        )?;
+
+        conn.execute(
+            "INSERT OR IGNORE INTO roles (id, name, permissions)
+            VALUES (?1, 'everyone', ?2)",
+            params![EVERYONE_ROLE, Permissions::DEFAULT.0 as i64],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS user_roles (
+                  user_id  TEXT NOT NULL,
+                  role_id  INTEGER NOT NULL,
+                  PRIMARY KEY (user_id, role_id)
+                )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS overwrites (
+                  channel_id  TEXT NOT NULL,
+                  kind        TEXT NOT NULL,
+                  target      TEXT NOT NULL,
+                  allow       INTEGER NOT NULL,
+                  deny        INTEGER NOT NULL,
+                  PRIMARY KEY (channel_id, kind, target)
+                )",
+            [],
+        )?;
+
+        let imported = conn
+            .prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'channels'")
+            .and_then(|mut stmt| stmt.exists([]))?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS channels (
+                  id        TEXT PRIMARY KEY,
+                  name      TEXT NOT NULL,
+                  kind      TEXT NOT NULL,
+                  url       TEXT,
+                  position  INTEGER NOT NULL,
+                  topic     TEXT
+                )",
+            [],
+        )?;
+        add_column(&conn, "channels", "parent", "TEXT")?;
+        add_column(&conn, "channels", "nsfw", "INTEGER NOT NULL DEFAULT 0")?;
+        add_column(
+            &conn,
+            "channels",
+            "slow_mode_secs",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS bans (
+                  user_id  TEXT PRIMARY KEY,
+                  banned   INTEGER NOT NULL
+                )",
+            [],
+        )?;
+
+        let db = Database(Mutex::new(conn));
+
+        // Channels used to come from config.json, they're taken over once
+        if !imported {
+            for (position, channel) in config.channels.iter().enumerate() {
+                let channel = Channel {
+                    position: position as u32,
+                    ..channel.clone()
+                };
+                db.insert_channel(&channel)?;
+            }
+        }
+
+        Ok(db)
+    }
+
+    /// Statements and transactions have to finish before the guard is dropped
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.0.lock().unwrap()
+    }
+}
+
+// For channels
+impl Database {
+    pub fn insert_channel(&self, channel: &Channel) -> Result<()> {
+        let conn = self.conn();
+        conn.execute(
+            "INSERT INTO channels (id, name, kind, url, position, topic, parent, nsfw, slow_mode_secs)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                channel.id,
+                channel.name,
+                channel.kind.name(),
+                channel.kind.url(),
+                channel.position,
+                channel.topic,
+                channel.parent,
+                channel.nsfw,
+                channel.slow_mode_secs
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Replace everything but the id of a channel
+    pub fn update_channel(&self, channel: &Channel) -> Result<()> {
+        let conn = self.conn();
+        conn.execute(
+            "UPDATE channels
+                SET name = ?2, kind = ?3, url = ?4, position = ?5, topic = ?6, parent = ?7,
+                    nsfw = ?8, slow_mode_secs = ?9
+                WHERE id = ?1;
+                ",
+            params![
+                channel.id,
+                channel.name,
+                channel.kind.name(),
+                channel.kind.url(),
+                channel.position,
+                channel.topic,
+                channel.parent,
+                channel.nsfw,
+                channel.slow_mode_secs
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Save the position of every channel in `channels` at once
+    pub fn set_channel_positions(&self, channels: &[Channel]) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        for channel in channels {
+            tx.execute(
+                "UPDATE channels SET position = ?2 WHERE id = ?1;",
+                params![channel.id, channel.position],
+            )?;
+        }
+
+        tx.commit()
+    }
+
+    /// Delete a channel with its messages, their reactions and attachments, threads and overwrites,
+    /// returns the hashes of the attachments
+    pub fn delete_channel(&self, channel_id: &str) -> Result<Vec<String>> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let hashes = attachment_hashes(
+            &tx,
+            "message_id IN (SELECT id FROM chat WHERE channel_id = ?1)",
+            channel_id,
+        )?;
+        for table in ["reactions", "attachments"] {
+            tx.execute(
+                &format!(
+                    "DELETE FROM {table}
+                    WHERE message_id IN (SELECT id FROM chat WHERE channel_id = ?1);"
+                ),
+                params![channel_id],
+            )?;
+        }
+        tx.execute(
+            "DELETE FROM chat WHERE channel_id = ?1;",
+            params![channel_id],
+        )?;
+        tx.execute(
+            "DELETE FROM overwrites WHERE channel_id = ?1;",
+            params![channel_id],
+        )?;
+        tx.execute(
+            "DELETE FROM threads WHERE channel_id = ?1;",
+            params![channel_id],
+        )?;
+        tx.execute("DELETE FROM channels WHERE id = ?1;", params![channel_id])?;
+        tx.commit()?;
+
+        Ok(hashes)
+    }
+
+    /// Every channel ordered by position
+    pub fn get_channels(&self) -> Result<Vec<Channel>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id, name, kind, url, position, topic, parent, nsfw, slow_mode_secs
+            FROM channels
+            ORDER BY position, id",
+        )?;
+
+        let rows = stmt.query_map([], |row| {
+            let kind =
+                ChannelKind::from_name(&row.get::<_, String>(2)?, row.get::<_, Option<String>>(3)?)
+                    .unwrap_or(ChannelKind::Text);
+            Ok(Channel {
+                id: row.get::<_, String>(0)?,
+                name: row.get::<_, String>(1)?,
+                kind,
+                position: row.get::<_, u32>(4)?,
+                topic: row.get::<_, Option<String>>(5)?,
+                parent: row.get::<_, Option<String>>(6)?,
+                nsfw: row.get::<_, bool>(7)?,
+                slow_mode_secs: row.get::<_, u32>(8)?,
+            })
+        })?;
+
+        rows.collect()
+    }
+}
+
+// For roles
+impl Database {
+    /// Create a role, returns its id
+    pub fn insert_role(&self, name: &str, permissions: Permissions) -> Result<i64> {
+        let conn = self.conn();
+        conn.query_row(
+            "INSERT INTO roles (name, permissions)
+            VALUES (?1, ?2)
+            RETURNING id",
+            params![name, permissions.0 as i64],
+            |row| row.get(0),
         )
-        .ok()?;
+    }
 
-        Some(Database(conn))
+    /// Delete a role and unassign it from everyone, returns `false` if it didn't exist
+    pub fn delete_role(&self, role_id: i64) -> Result<bool> {
+        let conn = self.conn();
+        conn.execute(
+            "DELETE FROM user_roles WHERE role_id = ?1;",
+            params![role_id],
+        )?;
+        Self::delete_overwrites_of(&conn, &OverwriteTarget::Role(role_id))?;
+        let deleted = conn.execute("DELETE FROM roles WHERE id = ?1;", params![role_id])?;
+
+        Ok(deleted > 0)
+    }
+
+    /// Replace the permissions of a role, returns `false` if it doesn't exist
+    pub fn set_role_permissions(&self, role_id: i64, permissions: Permissions) -> Result<bool> {
+        let conn = self.conn();
+        let updated = conn.execute(
+            "UPDATE roles
+                SET permissions = ?2
+                WHERE id = ?1;
+                ",
+            params![role_id, permissions.0 as i64],
+        )?;
+
+        Ok(updated > 0)
+    }
+
+    /// Every role ordered by id, `everyone` first
+    pub fn get_roles(&self) -> Result<Vec<Role>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id, name, permissions
+            FROM roles
+            ORDER BY id",
+        )?;
+
+        let rows = stmt.query_map([], Self::role_from_row)?;
+
+        rows.collect()
+    }
+
+    pub fn get_role(&self, role_id: i64) -> Result<Option<Role>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id, name, permissions
+            FROM roles
+            WHERE id = ?1",
+        )?;
+
+        let mut rows = stmt.query_map(params![role_id], Self::role_from_row)?;
+
+        rows.next().transpose()
+    }
+
+    pub fn get_role_by_name(&self, name: &str) -> Result<Option<Role>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id, name, permissions
+            FROM roles
+            WHERE name = ?1",
+        )?;
+
+        let mut rows = stmt.query_map(params![name], Self::role_from_row)?;
+
+        rows.next().transpose()
+    }
+
+    fn role_from_row(row: &rusqlite::Row) -> Result<Role> {
+        Ok(Role {
+            id: row.get::<_, i64>(0)?,
+            name: row.get::<_, String>(1)?,
+            permissions: Permissions(row.get::<_, i64>(2)? as u64),
+        })
+    }
+
+    /// Give a user a role, returns `false` if they already had it
+    pub fn assign_role(&self, user_id: &str, role_id: i64) -> Result<bool> {
+        let conn = self.conn();
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO user_roles (user_id, role_id)
+            VALUES (?1, ?2)",
+            params![user_id, role_id],
+        )?;
+
+        Ok(inserted > 0)
+    }
+
+    /// Take a role from a user, returns `false` if they didn't have it
+    pub fn unassign_role(&self, user_id: &str, role_id: i64) -> Result<bool> {
+        let conn = self.conn();
+        let deleted = conn.execute(
+            "DELETE FROM user_roles WHERE user_id = ?1 AND role_id = ?2;",
+            params![user_id, role_id],
+        )?;
+
+        Ok(deleted > 0)
+    }
+
+    /// The ids of the roles assigned to a user, without `everyone`
+    pub fn get_user_roles(&self, user_id: &str) -> Result<Vec<i64>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT role_id
+            FROM user_roles
+            WHERE user_id = ?1
+            ORDER BY role_id",
+        )?;
+
+        let rows = stmt.query_map(params![user_id], |row| row.get::<_, i64>(0))?;
+
+        rows.collect()
+    }
+
+    /// The permissions of `everyone` combined with those of the user's roles
+    pub fn get_user_permissions(&self, user_id: &str) -> Result<Permissions> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT permissions
+            FROM roles
+            WHERE id = ?2
+               OR id IN (SELECT role_id FROM user_roles WHERE user_id = ?1)",
+        )?;
+
+        let rows = stmt.query_map(params![user_id, EVERYONE_ROLE], |row| row.get::<_, i64>(0))?;
+
+        let mut permissions = Permissions::NONE;
+        for row in rows {
+            permissions = permissions | Permissions(row? as u64);
+        }
+
+        Ok(permissions)
+    }
+}
+
+// For channel overwrites
+impl Database {
+    /// Create or replace the overwrite of a role or user in a channel
+    pub fn set_overwrite(&self, channel_id: &str, overwrite: &Overwrite) -> Result<()> {
+        let conn = self.conn();
+        let (kind, target) = Self::overwrite_target(&overwrite.target);
+        conn.execute(
+            "INSERT OR REPLACE INTO overwrites (channel_id, kind, target, allow, deny)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                channel_id,
+                kind,
+                target,
+                overwrite.allow.0 as i64,
+                overwrite.deny.0 as i64
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Remove an overwrite, returns `false` if there was none
+    pub fn delete_overwrite(&self, channel_id: &str, target: &OverwriteTarget) -> Result<bool> {
+        let conn = self.conn();
+        let (kind, target) = Self::overwrite_target(target);
+        let deleted = conn.execute(
+            "DELETE FROM overwrites WHERE channel_id = ?1 AND kind = ?2 AND target = ?3;",
+            params![channel_id, kind, target],
+        )?;
+
+        Ok(deleted > 0)
+    }
+
+    /// Remove the overwrites of a role or user in every channel
+    fn delete_overwrites_of(conn: &Connection, target: &OverwriteTarget) -> Result<()> {
+        let (kind, target) = Self::overwrite_target(target);
+        conn.execute(
+            "DELETE FROM overwrites WHERE kind = ?1 AND target = ?2;",
+            params![kind, target],
+        )?;
+
+        Ok(())
+    }
+
+    /// Every overwrite with the channel it's in
+    pub fn get_overwrites(&self) -> Result<Vec<(String, Overwrite)>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT channel_id, kind, target, allow, deny
+            FROM overwrites",
+        )?;
+
+        let rows = stmt.query_map([], |row| {
+            let target = row.get::<_, String>(2)?;
+            let target = match row.get::<_, String>(1)?.as_str() {
+                "role" => OverwriteTarget::Role(target.parse().unwrap_or_default()),
+                _ => OverwriteTarget::User(target),
+            };
+            Ok((
+                row.get::<_, String>(0)?,
+                Overwrite {
+                    target,
+                    allow: Permissions(row.get::<_, i64>(3)? as u64),
+                    deny: Permissions(row.get::<_, i64>(4)? as u64),
+                },
+            ))
+        })?;
+
+        rows.collect()
+    }
+
+    fn overwrite_target(target: &OverwriteTarget) -> (&'static str, String) {
+        match target {
+            OverwriteTarget::Role(id) => ("role", id.to_string()),
+            OverwriteTarget::User(id) => ("user", id.clone()),
+        }
+    }
+}
+
+// For bans
+impl Database {
+    /// Ban a user, returns `false` if they already were
+    pub fn insert_ban(&self, user_id: &str, banned: i64) -> Result<bool> {
+        let conn = self.conn();
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO bans (user_id, banned)
+            VALUES (?1, ?2)",
+            params![user_id, banned],
+        )?;
+
+        Ok(inserted > 0)
+    }
+
+    /// Lift a ban, returns `false` if the user wasn't banned
+    pub fn delete_ban(&self, user_id: &str) -> Result<bool> {
+        let conn = self.conn();
+        let deleted = conn.execute("DELETE FROM bans WHERE user_id = ?1;", params![user_id])?;
+
+        Ok(deleted > 0)
+    }
+
+    pub fn is_banned(&self, user_id: &str) -> Result<bool> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT 1
+            FROM bans
+            WHERE user_id = ?1",
+        )?;
+
+        stmt.exists(params![user_id])
+    }
+}
+
+// For members
+impl Database {
+    /// Add a member, returns `false` if they already were one
+    pub fn insert_member(&self, user_id: &str, joined: i64) -> Result<bool> {
+        let conn = self.conn();
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO members (user_id, joined)
+            VALUES (?1, ?2)",
+            params![user_id, joined],
+        )?;
+
+        Ok(inserted > 0)
+    }
+
+    /// Remove a member, returns `false` if they weren't one
+    pub fn delete_member(&self, user_id: &str) -> Result<bool> {
+        let conn = self.conn();
+        let deleted = conn.execute("DELETE FROM members WHERE user_id = ?1;", params![user_id])?;
+
+        Ok(deleted > 0)
+    }
+
+    /// Members ordered by user id, starting after `after`
+    pub fn get_members(&self, after: &str, limit: usize) -> Result<Vec<(String, i64)>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT user_id, joined
+            FROM members
+            WHERE user_id > ?1
+            ORDER BY user_id
+            LIMIT ?2",
+        )?;
+
+        let rows = stmt.query_map(params![after, limit], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+        })?;
+
+        rows.collect()
+    }
+
+    /// Get when a user became a member
+    pub fn get_member(&self, user_id: &str) -> Result<Option<i64>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT joined
+            FROM members
+            WHERE user_id = ?1",
+        )?;
+
+        let mut rows = stmt.query_map(params![user_id], |row| row.get::<_, i64>(0))?;
+
+        rows.next().transpose()
+    }
+}
+
+/// A locally registered account
+pub struct LocalUser {
+    pub id: String,
+    pub password_hash: String,
+}
+
+// For local accounts
+impl Database {
+    /// Register a new local account
+    pub fn insert_user(
+        &self,
+        id: &str,
+        username: &str,
+        password_hash: &str,
+        created: i64,
+    ) -> Result<()> {
+        let conn = self.conn();
+        conn.execute(
+            "INSERT INTO users (id, username, password_hash, created)
+            VALUES (?1, ?2, ?3, ?4)",
+            params![id, username, password_hash, created],
+        )?;
+
+        Ok(())
+    }
+
+    /// Get a local account by its username
+    pub fn get_user_by_name(&self, username: &str) -> Result<Option<LocalUser>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id, password_hash
+            FROM users
+            WHERE username = ?1",
+        )?;
+
+        let mut rows = stmt.query_map(params![username], |row| {
+            Ok(LocalUser {
+                id: row.get::<_, String>(0)?,
+                password_hash: row.get::<_, String>(1)?,
+            })
+        })?;
+
+        rows.next().transpose()
+    }
+
+    /// Replace the password hash of a local account
+    pub fn set_user_password(&self, user_id: &str, password_hash: &str) -> Result<()> {
+        let conn = self.conn();
+        conn.execute(
+            "UPDATE users
+                SET password_hash = ?2
+                WHERE id = ?1;
+                ",
+            params![user_id, password_hash],
+        )?;
+
+        Ok(())
+    }
+
+    /// Delete a local account and all of its sessions
+    pub fn delete_user(&self, user_id: &str) -> Result<()> {
+        let conn = self.conn();
+        conn.execute("DELETE FROM sessions WHERE user_id = ?1;", params![user_id])?;
+        conn.execute("DELETE FROM users WHERE id = ?1;", params![user_id])?;
+
+        Ok(())
+    }
+
+    /// Store a server issued session token
+    pub fn insert_session(&self, token_hash: &str, user_id: &str, expires: i64) -> Result<()> {
+        let conn = self.conn();
+        conn.execute(
+            "INSERT INTO sessions (token_hash, user_id, expires)
+            VALUES (?1, ?2, ?3)",
+            params![token_hash, user_id, expires],
+        )?;
+
+        Ok(())
+    }
+
+    /// Get the user a session token belongs to, if it hasn't expired by `now`
+    pub fn get_session_user(&self, token_hash: &str, now: i64) -> Result<Option<String>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT user_id
+            FROM sessions
+            WHERE token_hash = ?1 AND expires > ?2",
+        )?;
+
+        let mut rows = stmt.query_map(params![token_hash, now], |row| row.get::<_, String>(0))?;
+
+        rows.next().transpose()
+    }
+
+    /// Forget the sessions that expired by `now`
+    pub fn delete_expired_sessions(&self, now: i64) -> Result<()> {
+        let conn = self.conn();
+        conn.execute("DELETE FROM sessions WHERE expires <= ?1;", params![now])?;
+
+        Ok(())
+    }
+
+    /// Revoke every session of a user
+    pub fn delete_sessions(&self, user_id: &str) -> Result<()> {
+        let conn = self.conn();
+        conn.execute("DELETE FROM sessions WHERE user_id = ?1;", params![user_id])?;
+
+        Ok(())
     }
 }
 
@@ -33,35 +793,55 @@ impl Database {
         user_id: &str,
         contents: &str,
         timestamp: i64,
+        reply_to: Option<i64>,
+        thread_id: Option<i64>,
     ) -> Result<Message> {
-        self.0.execute(
-            "INSERT INTO chat (channel_id, user_id, contents, timestamp)
-            VALUES (?1, ?2, ?3, ?4)",
-            params![channel_id, user_id, contents, timestamp],
+        let conn = self.conn();
+        let id = conn.query_row(
+            "INSERT INTO chat (channel_id, user_id, contents, timestamp, reply_to, thread_id)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            RETURNING id",
+            params![
+                channel_id, user_id, contents, timestamp, reply_to, thread_id
+            ],
+            |row| row.get(0),
         )?;
 
-        let id = self.0.last_insert_rowid();
-
         Ok(Message {
             id,
             channel_id: channel_id.to_string(),
             from: user_id.to_string(),
             contents: contents.to_string(),
             timestamp,
+            reply_to,
+            thread_id,
+            reactions: Vec::new(),
+            attachments: Vec::new(),
         })
     }
 
-    /// Delete a message from the DB
-    pub fn delete_message(&self, message_id: i64) -> Result<()> {
-        self.0
-            .execute("DELETE FROM chat WHERE id = ?1;", params![message_id])?;
+    /// Delete a message from the DB along with its reactions and attachments, returns the hashes of
+    /// the attachments
+    pub fn delete_message(&self, message_id: i64) -> Result<Vec<String>> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let hashes = attachment_hashes(&tx, "message_id = ?1", message_id)?;
+        for table in ["reactions", "attachments"] {
+            tx.execute(
+                &format!("DELETE FROM {table} WHERE message_id = ?1;"),
+                params![message_id],
+            )?;
+        }
+        tx.execute("DELETE FROM chat WHERE id = ?1;", params![message_id])?;
+        tx.commit()?;
 
-        Ok(())
+        Ok(hashes)
     }
 
     /// Delete a message from the DB
     pub fn edit_message(&self, message_id: i64, contents: &str) -> Result<()> {
-        self.0.execute(
+        let conn = self.conn();
+        conn.execute(
             "UPDATE chat
                 SET contents = ?2
                 WHERE id = ?1;
@@ -74,97 +854,741 @@ impl Database {
 
     /// Get a message by its ID
     pub fn get_message_by_id(&self, message_id: i64) -> Result<Option<Message>> {
-        let mut stmt = self.0.prepare(
-            "SELECT id, channel_id, user_id, contents, timestamp
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id, channel_id, user_id, contents, timestamp, reply_to, thread_id
          FROM chat
          WHERE id = ?1",
         )?;
 
-        let mut rows = stmt.query_map(params![message_id], |row| {
-            Ok((
-                row.get::<_, i64>(0)?,    // id
-                row.get::<_, String>(1)?, // channel_id
-                row.get::<_, String>(2)?, // user_id
-                row.get::<_, String>(3)?, // contents
-                row.get::<_, i64>(4)?,    // timestamp
-            ))
-        })?;
-
-        if let Some(row) = rows.next() {
-            let (id, channel_id, user_id, contents, timestamp) = row?;
-            return Ok(Some(Message {
-                id,
-                channel_id,
-                from: user_id.to_string(),
-                contents,
-                timestamp,
-            }));
-        }
-        Ok(None)
+        let mut rows = stmt.query_map(params![message_id], message_from_row)?;
+        rows.next().transpose()
     }
+}
 
-    pub fn get_chunk(&self, channel_id: &str, chunk_id: usize) -> Result<Vec<Message>> {
-        let mut stmt = self.0.prepare(
-            "SELECT id, channel_id, user_id, contents, timestamp
-            FROM chat
-            WHERE channel_id = ?1
-            ORDER BY id DESC
-            LIMIT 16 OFFSET (?2 * 16)",
-        )?;
+/// Where a page of messages starts
+#[derive(Debug, Clone, Copy)]
+pub enum Page {
+    Latest,
+    Before(i64),
+    After(i64),
+    /// The message itself and the ones around it, half of them older
+    Around(i64),
+}
 
-        let rows = stmt.query_map(params![channel_id, chunk_id], |row| {
-            Ok(Message {
-                id: row.get::<_, i64>(0)?,
-                channel_id: row.get::<_, String>(1)?,
-                from: row.get::<_, String>(2)?,
-                contents: row.get::<_, String>(3)?,
-                timestamp: row.get::<_, i64>(4)?,
-            })
-        })?;
+// For message history
+impl Database {
+    /// A page of the messages in a channel outside of its threads, oldest first
+    pub fn get_messages(&self, channel_id: &str, page: Page, limit: usize) -> Result<Vec<Message>> {
+        self.page_messages(
+            "channel_id = ?1 AND thread_id IS NULL",
+            &[&channel_id],
+            page,
+            limit,
+        )
+    }
 
-        let mut messages = Vec::new();
-        for row in rows {
-            messages.push(row?);
-        }
+    /// A page of the direct messages between two users, oldest first
+    pub fn get_messages_node(
+        &self,
+        author: &str,
+        channel_id: &str,
+        page: Page,
+        limit: usize,
+    ) -> Result<Vec<Message>> {
+        self.page_messages(
+            "((channel_id = ?1 AND user_id = ?2) OR (channel_id = ?2 AND user_id = ?1))",
+            &[&channel_id, &author],
+            page,
+            limit,
+        )
+    }
 
-        Ok(messages)
+    /// A chunk of the messages in a channel outside of its threads, `chunk_id` chunks back from the
+    /// newest, oldest first
+    pub fn get_chunk(&self, channel_id: &str, chunk_id: usize) -> Result<Vec<Message>> {
+        self.chunk_messages(
+            "channel_id = ?1 AND thread_id IS NULL",
+            &[&channel_id],
+            chunk_id,
+        )
     }
 
+    /// A chunk of the messages in a thread, oldest first
+    pub fn get_thread_chunk(&self, thread_id: i64, chunk_id: usize) -> Result<Vec<Message>> {
+        // A thread's id is the id of the message it started at, the oldest chunk starts with it
+        self.chunk_messages("(thread_id = ?1 OR id = ?1)", &[&thread_id], chunk_id)
+    }
+
+    /// A chunk of the direct messages between two users, oldest first
     pub fn get_chunk_node(
         &self,
         author: &str,
         channel_id: &str,
         chunk_id: usize,
     ) -> Result<Vec<Message>> {
-        let mut stmt = self.0.prepare(
-            "SELECT id, channel_id, user_id, contents, timestamp
-            FROM chat
-            WHERE (
-                (channel_id = ?1 AND user_id = ?3)
-             OR (channel_id = ?3 AND user_id = ?1)
+        self.chunk_messages(
+            "((channel_id = ?1 AND user_id = ?2) OR (channel_id = ?2 AND user_id = ?1))",
+            &[&channel_id, &author],
+            chunk_id,
+        )
+    }
+
+    /// Chunks are counted back from the newest message so they shift as messages arrive, only the
+    /// newest message of the chunk is found by skipping over the index, the chunk is then loaded
+    /// as a page
+    fn chunk_messages(
+        &self,
+        scope: &str,
+        values: &[&dyn ToSql],
+        chunk_id: usize,
+    ) -> Result<Vec<Message>> {
+        if chunk_id == 0 {
+            return self.page_messages(scope, values, Page::Latest, CHUNK_SIZE);
+        }
+
+        let skipped = i64::try_from(chunk_id.saturating_mul(CHUNK_SIZE)).unwrap_or(i64::MAX);
+        let newest: Option<i64> = self
+            .conn()
+            .query_row(
+                &format!(
+                    "SELECT id FROM chat WHERE {scope} ORDER BY id DESC LIMIT 1 OFFSET {skipped}"
+                ),
+                params_from_iter(values.iter().copied()),
+                |row| row.get(0),
             )
-            ORDER BY id DESC
-            LIMIT 16 OFFSET (?2 * 16)",
+            .optional()?;
+        match newest {
+            // Ids are integers, so this is the newest message and the ones before it
+            Some(id) => self.page_messages(scope, values, Page::Before(id + 1), CHUNK_SIZE),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn page_messages(
+        &self,
+        scope: &str,
+        values: &[&dyn ToSql],
+        page: Page,
+        limit: usize,
+    ) -> Result<Vec<Message>> {
+        let conn = self.conn();
+        let cursor = format!("?{}", values.len() + 1);
+        let select = |condition: String, order: &str, id: Option<i64>, limit: usize| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT id, channel_id, user_id, contents, timestamp, reply_to, thread_id
+                FROM chat
+                WHERE {scope} {condition}
+                ORDER BY id {order}
+                LIMIT {limit}"
+            ))?;
+            let values = values
+                .iter()
+                .copied()
+                .chain(id.as_ref().map(|id| id as &dyn ToSql));
+            let rows = stmt.query_map(params_from_iter(values), message_from_row)?;
+            rows.collect::<Result<Vec<_>>>()
+        };
+
+        let messages = match page {
+            Page::Latest => select(String::new(), "DESC", None, limit)?
+                .into_iter()
+                .rev()
+                .collect(),
+            Page::Before(id) => select(format!("AND id < {cursor}"), "DESC", Some(id), limit)?
+                .into_iter()
+                .rev()
+                .collect(),
+            Page::After(id) => select(format!("AND id > {cursor}"), "ASC", Some(id), limit)?,
+            Page::Around(id) => {
+                let older = select(
+                    format!("AND id <= {cursor}"),
+                    "DESC",
+                    Some(id),
+                    limit - limit / 2,
+                )?;
+                let newer = select(format!("AND id > {cursor}"), "ASC", Some(id), limit / 2)?;
+                older.into_iter().rev().chain(newer).collect()
+            }
+        };
+
+        Self::with_details(&conn, messages)
+    }
+}
+
+// For reactions
+impl Database {
+    /// React to a message, `false` if the user already reacted with the emoji
+    pub fn insert_reaction(&self, message_id: i64, emoji: &str, user_id: &str) -> Result<bool> {
+        let conn = self.conn();
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO reactions (message_id, emoji, user_id)
+            VALUES (?1, ?2, ?3)",
+            params![message_id, emoji, user_id],
+        )?;
+
+        Ok(inserted > 0)
+    }
+
+    pub fn delete_reaction(&self, message_id: i64, emoji: &str, user_id: &str) -> Result<bool> {
+        let conn = self.conn();
+        let deleted = conn.execute(
+            "DELETE FROM reactions WHERE message_id = ?1 AND emoji = ?2 AND user_id = ?3;",
+            params![message_id, emoji, user_id],
         )?;
 
-        let rows = stmt.query_map(params![channel_id, chunk_id, author], |row| {
-            Ok(Message {
-                id: row.get::<_, i64>(0)?,
-                channel_id: row.get::<_, String>(1)?,
-                from: row.get::<_, String>(2)?,
-                contents: row.get::<_, String>(3)?,
-                timestamp: row.get::<_, i64>(4)?,
+        Ok(deleted > 0)
+    }
+
+    /// How many users reacted with each emoji, in the order the emoji were first used
+    pub fn get_reactions(&self, message_id: i64) -> Result<Vec<Reaction>> {
+        Self::reactions_of(&self.conn(), message_id)
+    }
+
+    fn reactions_of(conn: &Connection, message_id: i64) -> Result<Vec<Reaction>> {
+        let mut stmt = conn.prepare_cached(
+            "SELECT emoji, COUNT(*)
+            FROM reactions
+            WHERE message_id = ?1
+            GROUP BY emoji
+            ORDER BY MIN(rowid)",
+        )?;
+
+        let rows = stmt.query_map(params![message_id], |row| {
+            Ok(Reaction {
+                emoji: row.get::<_, String>(0)?,
+                count: row.get::<_, u32>(1)?,
             })
         })?;
+        rows.collect()
+    }
 
-        let mut messages = Vec::new();
-        for row in rows {
-            messages.push(row?);
+    /// Fill in the reactions and attachments of messages
+    fn with_details(conn: &Connection, mut messages: Vec<Message>) -> Result<Vec<Message>> {
+        for msg in &mut messages {
+            msg.reactions = Self::reactions_of(conn, msg.id)?;
+            msg.attachments = Self::attachments_of(conn, msg.id)?;
         }
 
         Ok(messages)
     }
 }
 
-unsafe impl Send for Database {}
-unsafe impl Sync for Database {}
+// For attachments
+impl Database {
+    pub fn insert_attachment(
+        &self,
+        stored: &Stored,
+        user_id: &str,
+        timestamp: i64,
+    ) -> Result<Attachment> {
+        let conn = self.conn();
+        let id = conn.query_row(
+            "INSERT INTO attachments (hash, name, size, content_type, user_id, timestamp)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            RETURNING id",
+            params![
+                stored.hash,
+                stored.name,
+                stored.size,
+                stored.content_type,
+                user_id,
+                timestamp
+            ],
+            |row| row.get(0),
+        )?;
+
+        Ok(Attachment {
+            id,
+            name: stored.name.clone(),
+            size: stored.size,
+            content_type: stored.content_type.clone(),
+            hash: stored.hash.clone(),
+        })
+    }
+
+    /// An attachment the user uploaded that isn't part of a message yet
+    pub fn get_unsent_attachment(
+        &self,
+        attachment_id: i64,
+        user_id: &str,
+    ) -> Result<Option<Attachment>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id, name, size, content_type, hash
+            FROM attachments
+            WHERE id = ?1 AND user_id = ?2 AND message_id IS NULL",
+        )?;
+
+        let mut rows = stmt.query_map(params![attachment_id, user_id], attachment_from_row)?;
+        rows.next().transpose()
+    }
+
+    pub fn attach(&self, message_id: i64, attachment_id: i64) -> Result<()> {
+        let conn = self.conn();
+        conn.execute(
+            "UPDATE attachments SET message_id = ?1 WHERE id = ?2;",
+            params![message_id, attachment_id],
+        )?;
+
+        Ok(())
+    }
+
+    fn attachments_of(conn: &Connection, message_id: i64) -> Result<Vec<Attachment>> {
+        let mut stmt = conn.prepare_cached(
+            "SELECT id, name, size, content_type, hash
+            FROM attachments
+            WHERE message_id = ?1
+            ORDER BY id",
+        )?;
+
+        let rows = stmt.query_map(params![message_id], attachment_from_row)?;
+        rows.collect()
+    }
+
+    /// Forget uploads from before `before` that never made it into a message
+    pub fn prune_attachments(&self, before: i64) -> Result<()> {
+        let conn = self.conn();
+        conn.execute(
+            "DELETE FROM attachments WHERE message_id IS NULL AND timestamp < ?1;",
+            params![before],
+        )?;
+
+        Ok(())
+    }
+
+    /// Who uploaded each attachment with the hash and the channel it was sent to, if it was
+    pub fn get_attachment_uses(&self, hash: &str) -> Result<Vec<(String, Option<String>)>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT a.user_id, c.channel_id
+            FROM attachments a
+            LEFT JOIN chat c ON c.id = a.message_id
+            WHERE a.hash = ?1",
+        )?;
+        let rows = stmt.query_map(params![hash], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect()
+    }
+
+    /// Whether any attachment, sent or not, has the file with the hash
+    pub fn is_attachment_used(&self, hash: &str) -> Result<bool> {
+        let conn = self.conn();
+        conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM attachments WHERE hash = ?1)",
+            params![hash],
+            |row| row.get(0),
+        )
+    }
+
+    /// The hashes of every stored file that's still needed
+    pub fn get_attachment_hashes(&self) -> Result<HashSet<String>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT DISTINCT hash FROM attachments")?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        rows.collect()
+    }
+}
+
+// For threads
+impl Database {
+    pub fn insert_thread(&self, thread: &Thread) -> Result<()> {
+        let conn = self.conn();
+        conn.execute(
+            "INSERT INTO threads (id, channel_id, name, user_id, timestamp)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                thread.id,
+                thread.channel_id,
+                thread.name,
+                thread.from,
+                thread.timestamp
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn get_thread(&self, thread_id: i64) -> Result<Option<Thread>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id, channel_id, name, user_id, timestamp
+            FROM threads
+            WHERE id = ?1",
+        )?;
+
+        let mut rows = stmt.query_map(params![thread_id], thread_from_row)?;
+        rows.next().transpose()
+    }
+
+    /// The threads of a channel, newest first
+    pub fn get_threads(&self, channel_id: &str) -> Result<Vec<Thread>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id, channel_id, name, user_id, timestamp
+            FROM threads
+            WHERE channel_id = ?1
+            ORDER BY id DESC",
+        )?;
+
+        let rows = stmt.query_map(params![channel_id], thread_from_row)?;
+        rows.collect()
+    }
+
+    /// Delete a thread with its messages, `None` if there was no thread
+    pub fn delete_thread(&self, thread_id: i64) -> Result<Option<DeletedThread>> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let messages = tx
+            .prepare("SELECT id FROM chat WHERE thread_id = ?1 ORDER BY id")?
+            .query_map(params![thread_id], |row| row.get(0))?
+            .collect::<Result<Vec<i64>>>()?;
+        let hashes = attachment_hashes(
+            &tx,
+            "message_id IN (SELECT id FROM chat WHERE thread_id = ?1)",
+            thread_id,
+        )?;
+        for table in ["reactions", "attachments"] {
+            tx.execute(
+                &format!(
+                    "DELETE FROM {table}
+                    WHERE message_id IN (SELECT id FROM chat WHERE thread_id = ?1);"
+                ),
+                params![thread_id],
+            )?;
+        }
+        tx.execute("DELETE FROM chat WHERE thread_id = ?1;", params![thread_id])?;
+        let deleted = tx.execute("DELETE FROM threads WHERE id = ?1;", params![thread_id])?;
+        tx.commit()?;
+
+        Ok((deleted > 0).then_some(DeletedThread { messages, hashes }))
+    }
+}
+
+/// What went along with a deleted thread
+pub struct DeletedThread {
+    /// The messages sent to the thread
+    pub messages: Vec<i64>,
+    /// The hashes of their attachments
+    pub hashes: Vec<String>,
+}
+
+/// Which messages a search looks through
+pub enum SearchScope<'a> {
+    /// Messages in any of these channels
+    Channels(&'a [String]),
+    /// Direct messages of a user, only the ones with `with` if it's set
+    Direct {
+        user_id: &'a str,
+        with: Option<&'a str>,
+    },
+}
+
+/// Narrows down a search, every filter that's set has to match
+pub struct SearchFilter<'a> {
+    pub from: Option<&'a str>,
+    /// Sent at or after this timestamp
+    pub since: Option<i64>,
+    /// Sent before this timestamp
+    pub until: Option<i64>,
+    /// Only messages older than this one
+    pub before: Option<i64>,
+    pub limit: usize,
+}
+
+// For search
+impl Database {
+    /// Messages matching an FTS5 `query`, newest first, with the matches marked in a snippet
+    pub fn search(
+        &self,
+        query: &str,
+        scope: &SearchScope,
+        filter: &SearchFilter,
+    ) -> Result<Vec<SearchHit>> {
+        let mut values: Vec<&dyn ToSql> = vec![
+            &query,
+            &filter.from,
+            &filter.since,
+            &filter.until,
+            &filter.before,
+            &filter.limit,
+        ];
+        let scope = match scope {
+            SearchScope::Channels(channels) => {
+                let first = values.len() + 1;
+                let placeholders: Vec<String> = (first..first + channels.len())
+                    .map(|i| format!("?{i}"))
+                    .collect();
+                values.extend(channels.iter().map(|id| id as &dyn ToSql));
+                format!("c.channel_id IN ({})", placeholders.join(", "))
+            }
+            SearchScope::Direct { user_id, with } => {
+                values.push(user_id);
+                values.push(with);
+                "((c.channel_id = ?7 AND (?8 IS NULL OR c.user_id = ?8))
+                 OR (c.user_id = ?7 AND (?8 IS NULL OR c.channel_id = ?8)))"
+                    .to_string()
+            }
+        };
+
+        let conn = self.conn();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT c.id, c.channel_id, c.user_id, c.contents, c.timestamp, c.reply_to,
+                c.thread_id, snippet(chat_search, 0, char(1), char(2), '…', 16)
+            FROM chat_search
+            JOIN chat c ON c.id = chat_search.rowid
+            WHERE chat_search MATCH ?1
+              AND {scope}
+              AND (?2 IS NULL OR c.user_id = ?2)
+              AND (?3 IS NULL OR c.timestamp >= ?3)
+              AND (?4 IS NULL OR c.timestamp < ?4)
+              AND (?5 IS NULL OR c.id < ?5)
+            ORDER BY c.id DESC
+            LIMIT ?6"
+        ))?;
+
+        let rows = stmt.query_map(params_from_iter(values), |row| {
+            Ok((message_from_row(row)?, row.get::<_, String>(7)?))
+        })?;
+        let (messages, snippets): (Vec<Message>, Vec<String>) =
+            rows.collect::<Result<Vec<_>>>()?.into_iter().unzip();
+
+        Ok(Self::with_details(&conn, messages)?
+            .into_iter()
+            .zip(snippets)
+            .map(|(message, snippet)| SearchHit {
+                message,
+                snippet: mark_snippet(&snippet),
+            })
+            .collect())
+    }
+}
+
+/// Escape a snippet for HTML and turn the markers around its matches into `<mark>` tags, so user
+/// text is never taken for markup
+fn mark_snippet(snippet: &str) -> String {
+    let mut marked = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            '\u{1}' => marked.push_str("<mark>"),
+            '\u{2}' => marked.push_str("</mark>"),
+            '&' => marked.push_str("&amp;"),
+            '<' => marked.push_str("&lt;"),
+            '>' => marked.push_str("&gt;"),
+            '"' => marked.push_str("&quot;"),
+            '\'' => marked.push_str("&#39;"),
+            c => marked.push(c),
+        }
+    }
+    marked
+}
+
+/// Read a message selected as `id, channel_id, user_id, contents, timestamp, reply_to, thread_id`
+fn message_from_row(row: &Row) -> Result<Message> {
+    Ok(Message {
+        id: row.get::<_, i64>(0)?,
+        channel_id: row.get::<_, String>(1)?,
+        from: row.get::<_, String>(2)?,
+        contents: row.get::<_, String>(3)?,
+        timestamp: row.get::<_, i64>(4)?,
+        reply_to: row.get::<_, Option<i64>>(5)?,
+        thread_id: row.get::<_, Option<i64>>(6)?,
+        reactions: Vec::new(),
+        attachments: Vec::new(),
+    })
+}
+
+fn attachment_from_row(row: &Row) -> Result<Attachment> {
+    Ok(Attachment {
+        id: row.get::<_, i64>(0)?,
+        name: row.get::<_, String>(1)?,
+        size: row.get::<_, u64>(2)?,
+        content_type: row.get::<_, Option<String>>(3)?,
+        hash: row.get::<_, String>(4)?,
+    })
+}
+
+fn thread_from_row(row: &Row) -> Result<Thread> {
+    Ok(Thread {
+        id: row.get::<_, i64>(0)?,
+        channel_id: row.get::<_, String>(1)?,
+        name: row.get::<_, String>(2)?,
+        from: row.get::<_, String>(3)?,
+        timestamp: row.get::<_, i64>(4)?,
+    })
+}
+
+/// The distinct hashes of the attachments matching `condition`, which takes `value` as `?1`
+fn attachment_hashes(conn: &Connection, condition: &str, value: impl ToSql) -> Result<Vec<String>> {
+    conn.prepare(&format!(
+        "SELECT DISTINCT hash FROM attachments WHERE {condition}"
+    ))?
+    .query_map(params![value], |row| row.get(0))?
+    .collect()
+}
+
+/// Add a column to a table created by an older version
+fn add_column(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    let exists = conn
+        .prepare("SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2")?
+        .exists(params![table, column])?;
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"),
+            [],
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database() -> Database {
+        Database::new(":memory:", &ServerConfig::default()).unwrap()
+    }
+
+    fn search(db: &Database, query: &str) -> Vec<SearchHit> {
+        let channels = ["general".to_string()];
+        let filter = SearchFilter {
+            from: None,
+            since: None,
+            until: None,
+            before: None,
+            limit: 25,
+        };
+        db.search(query, &SearchScope::Channels(&channels), &filter)
+            .unwrap()
+    }
+
+    fn send(db: &Database, contents: &str) -> Message {
+        db.insert_message("general", "alice", contents, 0, None, None)
+            .unwrap()
+    }
+
+    #[test]
+    fn sent_messages_are_indexed() {
+        let db = database();
+        let msg = send(&db, "hello world");
+        send(&db, "something else");
+
+        let hits = search(&db, "hello");
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].message.id, msg.id);
+        assert_eq!(hits[0].snippet, "<mark>hello</mark> world");
+    }
+
+    #[test]
+    fn edited_messages_are_reindexed() {
+        let db = database();
+        let msg = send(&db, "hello world");
+        db.edit_message(msg.id, "goodbye world").unwrap();
+
+        assert!(search(&db, "hello").is_empty());
+        assert_eq!(search(&db, "goodbye").len(), 1);
+    }
+
+    #[test]
+    fn deleted_messages_leave_the_index() {
+        let db = database();
+        let msg = send(&db, "hello world");
+        db.delete_message(msg.id).unwrap();
+
+        assert!(search(&db, "hello").is_empty());
+    }
+
+    #[test]
+    fn snippets_escape_user_text() {
+        let db = database();
+        send(&db, "<b>hello</b> & \"world\"");
+
+        let hits = search(&db, "hello");
+        assert_eq!(
+            hits[0].snippet,
+            "&lt;b&gt;<mark>hello</mark>&lt;/b&gt; &amp; &quot;world&quot;"
+        );
+    }
+
+    fn ids(messages: &[Message]) -> Vec<i64> {
+        messages.iter().map(|m| m.id).collect()
+    }
+
+    #[test]
+    fn pages_before_after_and_around_a_message() {
+        let db = database();
+        let sent: Vec<i64> = (0..10).map(|i| send(&db, &format!("{i}")).id).collect();
+        let page = |page| ids(&db.get_messages("general", page, 4).unwrap());
+
+        assert_eq!(page(Page::Latest), sent[6..]);
+        assert_eq!(page(Page::Before(sent[5])), sent[1..5]);
+        assert_eq!(page(Page::After(sent[5])), sent[6..]);
+        assert_eq!(page(Page::Around(sent[5])), sent[4..8]);
+    }
+
+    #[test]
+    fn empty_channels_have_empty_pages() {
+        let db = database();
+        for page in [
+            Page::Latest,
+            Page::Before(1),
+            Page::After(1),
+            Page::Around(1),
+        ] {
+            assert!(db.get_messages("general", page, 4).unwrap().is_empty());
+        }
+        assert!(db.get_chunk("general", 0).unwrap().is_empty());
+        assert!(db.get_chunk("general", 1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn pages_around_a_deleted_message_go_on_from_where_it_was() {
+        let db = database();
+        let sent: Vec<i64> = (0..10).map(|i| send(&db, &format!("{i}")).id).collect();
+        db.delete_message(sent[5]).unwrap();
+        let page = |page| ids(&db.get_messages("general", page, 4).unwrap());
+
+        assert_eq!(page(Page::Before(sent[5])), sent[1..5]);
+        assert_eq!(page(Page::After(sent[5])), sent[6..]);
+        assert_eq!(
+            page(Page::Around(sent[5])),
+            [sent[3], sent[4], sent[6], sent[7]]
+        );
+    }
+
+    #[test]
+    fn chunks_count_back_from_the_newest_message() {
+        let db = database();
+        let sent: Vec<i64> = (0..40).map(|i| send(&db, &format!("{i}")).id).collect();
+        let chunk = |chunk_id| ids(&db.get_chunk("general", chunk_id).unwrap());
+
+        assert_eq!(chunk(0), sent[24..]);
+        assert_eq!(chunk(1), sent[8..24]);
+        assert_eq!(chunk(2), sent[..8]);
+        assert!(chunk(3).is_empty());
+        assert!(chunk(usize::MAX).is_empty());
+    }
+
+    #[test]
+    fn thread_chunks_start_with_the_message_the_thread_started_at() {
+        let db = database();
+        let root = send(&db, "root");
+        db.insert_thread(&Thread {
+            id: root.id,
+            channel_id: "general".to_string(),
+            name: "thread".to_string(),
+            from: "alice".to_string(),
+            timestamp: 0,
+        })
+        .unwrap();
+        let reply = db
+            .insert_message("general", "bob", "reply", 0, None, Some(root.id))
+            .unwrap();
+
+        assert_eq!(
+            ids(&db.get_thread_chunk(root.id, 0).unwrap()),
+            [root.id, reply.id]
+        );
+        assert_eq!(ids(&db.get_chunk("general", 0).unwrap()), [root.id]);
+    }
+}
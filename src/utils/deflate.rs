@@ -0,0 +1,316 @@
+//! RFC 7692 permessage-deflate
+
+use anyhow::anyhow;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+/// Every compressed message ends with an empty stored block whose trailer is left off the wire
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+
+/// Messages smaller than this are sent uncompressed, deflate only makes them bigger
+const MIN_COMPRESSED_SIZE: usize = 128;
+
+/// Parameters agreed on during the handshake
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeflateParams {
+    /// The server resets its compressor after every message
+    pub server_no_context_takeover: bool,
+    /// The client resets its compressor after every message
+    pub client_no_context_takeover: bool,
+}
+
+impl DeflateParams {
+    /// Pick the first acceptable offer from a `Sec-WebSocket-Extensions` header
+    pub fn negotiate(header: &str) -> Option<Self> {
+        header.split(',').find_map(Self::accept)
+    }
+
+    fn accept(offer: &str) -> Option<Self> {
+        let mut params = offer.split(';').map(str::trim);
+        if params.next()? != "permessage-deflate" {
+            return None;
+        }
+
+        let mut accepted = Self::default();
+        let mut seen = Vec::new();
+        for param in params {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+            // Offers repeating a parameter must be declined
+            if seen.contains(&name) {
+                return None;
+            }
+            seen.push(name);
+
+            match (name, value) {
+                ("server_no_context_takeover", None) => accepted.server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => accepted.client_no_context_takeover = true,
+                // We always inflate with the full window, whatever the client picks works
+                ("client_max_window_bits", None) => {}
+                ("client_max_window_bits", Some(bits)) => {
+                    window_bits(bits)?;
+                }
+                // The compressor can't shrink its window, so only the default is acceptable
+                ("server_max_window_bits", Some(bits)) if window_bits(bits)? == 15 => {}
+                _ => return None,
+            }
+        }
+        Some(accepted)
+    }
+
+    /// The value for the `Sec-WebSocket-Extensions` response header
+    pub fn response(&self) -> String {
+        let mut response = "permessage-deflate".to_string();
+        if self.server_no_context_takeover {
+            response.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            response.push_str("; client_no_context_takeover");
+        }
+        response
+    }
+}
+
+fn window_bits(value: &str) -> Option<u8> {
+    // No leading zeros or signs, RFC 7692 section 7.1.2.1
+    if value.starts_with('0') || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok().filter(|bits| (8..=15).contains(bits))
+}
+
+/// Compresses outbound messages of a single connection
+pub struct Deflater {
+    compress: Compress,
+    no_context_takeover: bool,
+}
+
+impl Deflater {
+    pub fn new(params: &DeflateParams) -> Self {
+        Self {
+            compress: Compress::new(Compression::default(), false),
+            no_context_takeover: params.server_no_context_takeover,
+        }
+    }
+
+    /// Returns `None` when the payload is too small to be worth compressing
+    pub fn compress(&mut self, payload: &[u8]) -> crate::Result<Option<Vec<u8>>> {
+        if payload.len() < MIN_COMPRESSED_SIZE {
+            return Ok(None);
+        }
+
+        let start = self.compress.total_in();
+        let mut out = Vec::with_capacity(payload.len() / 2 + 64);
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            if out.len() == out.capacity() {
+                out.reserve(1024);
+            }
+            self.compress
+                .compress_vec(&payload[consumed..], &mut out, FlushCompress::Sync)?;
+
+            // A sync flush is done once all input is used and there was room left to finish
+            if self.compress.total_in() - start == payload.len() as u64
+                && out.len() < out.capacity()
+            {
+                break;
+            }
+        }
+
+        if out.ends_with(&TRAILER) {
+            out.truncate(out.len() - TRAILER.len());
+        }
+        if self.no_context_takeover {
+            self.compress.reset();
+        }
+        Ok(Some(out))
+    }
+}
+
+/// Decompresses inbound messages of a single connection
+pub struct Inflater {
+    decompress: Decompress,
+    no_context_takeover: bool,
+}
+
+impl Inflater {
+    pub fn new(params: &DeflateParams) -> Self {
+        Self {
+            decompress: Decompress::new(false),
+            no_context_takeover: params.client_no_context_takeover,
+        }
+    }
+
+    /// Returns `None` when the message inflates to more than `limit` bytes
+    pub fn decompress(&mut self, payload: &[u8], limit: usize) -> crate::Result<Option<Vec<u8>>> {
+        let mut input = Vec::with_capacity(payload.len() + TRAILER.len());
+        input.extend_from_slice(payload);
+        input.extend_from_slice(&TRAILER);
+
+        let start = self.decompress.total_in();
+        let mut out = Vec::with_capacity((payload.len() * 4).min(limit + 1));
+        loop {
+            let consumed = (self.decompress.total_in() - start) as usize;
+            if out.len() == out.capacity() {
+                out.reserve((out.len()).clamp(1024, 64 * 1024));
+            }
+            let before = out.len();
+            let status = self.decompress.decompress_vec(
+                &input[consumed..],
+                &mut out,
+                FlushDecompress::Sync,
+            )?;
+
+            // Checked as it grows so a tiny message can't expand into gigabytes
+            if out.len() > limit {
+                return Ok(None);
+            }
+
+            let done = self.decompress.total_in() - start == input.len() as u64
+                && out.len() < out.capacity();
+            let stuck = status == Status::BufError && out.len() == before;
+            if done || status == Status::StreamEnd {
+                break;
+            }
+            if stuck {
+                return Err(anyhow!("Corrupt compressed message"));
+            }
+        }
+
+        if self.no_context_takeover {
+            self.decompress.reset(false);
+        }
+        Ok(Some(out))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message() -> Vec<u8> {
+        br#"{"type":"message_create","params":{"id":1,"channel_id":"general","contents":"hello"}}"#
+            .repeat(8)
+    }
+
+    #[test]
+    fn round_trip() {
+        let params = DeflateParams::default();
+        let mut deflater = Deflater::new(&params);
+        let mut inflater = Inflater::new(&params);
+
+        let compressed = deflater.compress(&message()).unwrap().unwrap();
+        assert!(compressed.len() < message().len());
+        let inflated = inflater.decompress(&compressed, 1 << 20).unwrap().unwrap();
+        assert_eq!(inflated, message());
+    }
+
+    #[test]
+    fn small_messages_are_not_compressed() {
+        let mut deflater = Deflater::new(&DeflateParams::default());
+        assert!(deflater.compress(b"{}").unwrap().is_none());
+    }
+
+    #[test]
+    fn trailer_is_left_off_and_added_back() {
+        let mut deflater = Deflater::new(&DeflateParams::default());
+        let compressed = deflater.compress(&message()).unwrap().unwrap();
+        assert!(!compressed.ends_with(&TRAILER));
+
+        // "Hello" from RFC 7692 section 7.2.3.1, sent without the trailer
+        let mut inflater = Inflater::new(&DeflateParams::default());
+        let hello = [0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00];
+        assert_eq!(
+            inflater.decompress(&hello, 1024).unwrap().unwrap(),
+            b"Hello"
+        );
+    }
+
+    #[test]
+    fn context_is_taken_over_between_messages() {
+        let params = DeflateParams::default();
+        let mut deflater = Deflater::new(&params);
+        let mut inflater = Inflater::new(&params);
+
+        let first = deflater.compress(&message()).unwrap().unwrap();
+        let second = deflater.compress(&message()).unwrap().unwrap();
+        // The second message refers back to the first instead of repeating it
+        assert!(second.len() < first.len());
+        assert_eq!(
+            inflater.decompress(&first, 1 << 20).unwrap().unwrap(),
+            message()
+        );
+        assert_eq!(
+            inflater.decompress(&second, 1 << 20).unwrap().unwrap(),
+            message()
+        );
+
+        // Without the first message the second can't be read
+        let mut fresh = Inflater::new(&params);
+        assert!(
+            fresh
+                .decompress(&second, 1 << 20)
+                .map_or(true, |out| out.as_deref() != Some(message().as_slice()))
+        );
+    }
+
+    #[test]
+    fn no_context_takeover_resets_after_every_message() {
+        let params = DeflateParams {
+            server_no_context_takeover: true,
+            client_no_context_takeover: true,
+        };
+        let mut deflater = Deflater::new(&params);
+
+        let first = deflater.compress(&message()).unwrap().unwrap();
+        let second = deflater.compress(&message()).unwrap().unwrap();
+        assert_eq!(first, second);
+
+        // Every message can be read on its own
+        let mut inflater = Inflater::new(&params);
+        for compressed in [&first, &second] {
+            assert_eq!(
+                inflater.decompress(compressed, 1 << 20).unwrap().unwrap(),
+                message()
+            );
+        }
+    }
+
+    #[test]
+    fn inflating_past_the_limit_stops() {
+        let mut deflater = Deflater::new(&DeflateParams::default());
+        let compressed = deflater.compress(&[b'a'; 64 * 1024]).unwrap().unwrap();
+
+        let mut inflater = Inflater::new(&DeflateParams::default());
+        assert!(inflater.decompress(&compressed, 1024).unwrap().is_none());
+    }
+
+    #[test]
+    fn corrupt_data_is_an_error() {
+        let mut inflater = Inflater::new(&DeflateParams::default());
+        assert!(inflater.decompress(&[0xff; 16], 1024).is_err());
+    }
+
+    #[test]
+    fn negotiates_supported_offers() {
+        assert_eq!(
+            DeflateParams::negotiate("permessage-deflate; client_max_window_bits"),
+            Some(DeflateParams::default())
+        );
+        assert_eq!(
+            DeflateParams::negotiate(
+                "permessage-deflate; server_max_window_bits=10, \
+                 permessage-deflate; server_no_context_takeover"
+            ),
+            Some(DeflateParams {
+                server_no_context_takeover: true,
+                client_no_context_takeover: false,
+            })
+        );
+        assert_eq!(
+            DeflateParams::negotiate("permessage-deflate; client_max_window_bits=08"),
+            None
+        );
+    }
+}
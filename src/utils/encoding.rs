@@ -0,0 +1,242 @@
+use std::cell::OnceCell;
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::client::{Client, Frame};
+
+/// Leads binary frames carrying voice on connections that don't use JSON
+const VOICE_TAG: u8 = 0x00;
+/// Leads binary frames carrying a message on connections that don't use JSON
+const MESSAGE_TAG: u8 = 0x01;
+/// Leads binary frames carrying a chunk of an upload
+pub const UPLOAD_TAG: u8 = 0x02;
+
+/// Wire format of the messages on a connection, negotiated through `Sec-WebSocket-Protocol`.
+///
+/// JSON messages are text frames and binary frames are voice, or upload chunks when they start
+/// with [`UPLOAD_TAG`] and the id of an upload in progress. MessagePack messages are binary frames
+/// too, so on those connections every binary frame starts with a tag byte telling messages, voice
+/// and upload chunks apart.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+/// How a data frame should be handled once it's been decoded
+pub enum Decoded<T> {
+    Message(T),
+    Voice(Vec<u8>),
+    /// An upload chunk without its tag
+    Upload(Vec<u8>),
+    /// A frame that isn't a valid message, answered with an error
+    Malformed(String),
+    /// Text that isn't UTF-8, closes the connection
+    Invalid(&'static str),
+}
+
+impl Encoding {
+    pub const ALL: [Self; 2] = [Self::Json, Self::MessagePack];
+
+    pub fn protocol(self) -> &'static str {
+        match self {
+            Self::Json => "axiom.json",
+            Self::MessagePack => "axiom.msgpack",
+        }
+    }
+
+    /// Pick the first supported protocol from a `Sec-WebSocket-Protocol` header
+    pub fn negotiate(header: &str) -> Option<Self> {
+        header
+            .split(',')
+            .map(str::trim)
+            .find_map(|p| Self::ALL.into_iter().find(|e| e.protocol() == p))
+    }
+
+    /// Encode a message into a frame
+    pub fn encode<T: Serialize>(self, m: &T) -> crate::Result<Frame> {
+        Ok(match self {
+            Self::Json => Client::encode_frame(0x1, serde_json::to_string(m)?.as_bytes()),
+            Self::MessagePack => {
+                let mut payload = vec![MESSAGE_TAG];
+                rmp_serde::encode::write_named(&mut payload, m)?;
+                Client::encode_frame(0x2, &payload)
+            }
+        })
+    }
+
+    /// Encode voice data into a frame
+    pub fn encode_voice(self, data: &[u8]) -> Frame {
+        match self {
+            Self::Json => Client::encode_frame(0x2, data),
+            Self::MessagePack => {
+                let mut payload = Vec::with_capacity(data.len() + 1);
+                payload.push(VOICE_TAG);
+                payload.extend_from_slice(data);
+                Client::encode_frame(0x2, &payload)
+            }
+        }
+    }
+
+    /// Decode the reassembled payload of a text (0x1) or binary (0x2) message
+    pub fn decode<T: for<'de> Deserialize<'de>>(self, opcode: u8, payload: Vec<u8>) -> Decoded<T> {
+        match (self, opcode) {
+            (_, 0x1) => match String::from_utf8(payload) {
+                Ok(text) => match serde_json::from_str(&text) {
+                    Ok(msg) => Decoded::Message(msg),
+                    Err(e) => Decoded::Malformed(format!("Invalid JSON message: {e}")),
+                },
+                Err(_) => Decoded::Invalid("Invalid UTF-8"),
+            },
+            (Self::Json, _) => Decoded::Voice(payload),
+            (Self::MessagePack, _) => match payload.split_first() {
+                Some((&MESSAGE_TAG, m)) => match rmp_serde::from_slice(m) {
+                    Ok(msg) => Decoded::Message(msg),
+                    Err(e) => Decoded::Malformed(format!("Invalid MessagePack message: {e}")),
+                },
+                Some((&VOICE_TAG, _)) => {
+                    let mut payload = payload;
+                    payload.remove(0);
+                    Decoded::Voice(payload)
+                }
+                Some((&UPLOAD_TAG, chunk)) => Decoded::Upload(chunk.to_vec()),
+                _ => Decoded::Malformed("Unknown binary frame".to_string()),
+            },
+        }
+    }
+}
+
+/// A message encoded at most once per encoding, for sending the same message to many clients
+pub struct Encoded<'a, T: Serialize> {
+    message: &'a T,
+    frames: [OnceCell<Frame>; Encoding::ALL.len()],
+}
+
+impl<'a, T: Serialize> Encoded<'a, T> {
+    pub fn new(message: &'a T) -> Self {
+        Self {
+            message,
+            frames: Default::default(),
+        }
+    }
+
+    pub fn frame(&self, encoding: Encoding) -> crate::Result<Frame> {
+        let cell = &self.frames[encoding as usize];
+        if let Some(frame) = cell.get() {
+            return Ok(frame.clone());
+        }
+        let frame = encoding.encode(self.message)?;
+        Ok(cell.get_or_init(|| frame).clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::message::{ClientMessage, Request};
+
+    fn typing() -> Request {
+        Request {
+            message: ClientMessage::Typing {
+                channel_id: "general".to_string(),
+            },
+            request_id: Some("r".to_string()),
+        }
+    }
+
+    /// Split a short unmasked frame into its opcode and payload
+    fn split(frame: &[u8]) -> (u8, Vec<u8>) {
+        assert!(frame[1] < 126);
+        (frame[0] & 0x0F, frame[2..].to_vec())
+    }
+
+    fn is_typing(decoded: Decoded<Request>) -> bool {
+        matches!(
+            decoded,
+            Decoded::Message(Request {
+                message: ClientMessage::Typing { channel_id },
+                request_id: Some(request_id),
+            }) if channel_id == "general" && request_id == "r"
+        )
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let (opcode, payload) = split(&Encoding::Json.encode(&typing()).unwrap());
+        assert_eq!(opcode, 0x1);
+        assert!(is_typing(Encoding::Json.decode(opcode, payload)));
+
+        let (opcode, payload) = split(&Encoding::MessagePack.encode(&typing()).unwrap());
+        assert_eq!(opcode, 0x2);
+        assert_eq!(payload[0], MESSAGE_TAG);
+        assert!(is_typing(Encoding::MessagePack.decode(opcode, payload)));
+    }
+
+    #[test]
+    fn binary_frames_are_told_apart_by_tag() {
+        let (opcode, payload) = split(&Encoding::Json.encode_voice(b"opus"));
+        assert!(matches!(
+            Encoding::Json.decode::<Request>(opcode, payload),
+            Decoded::Voice(data) if data == b"opus"
+        ));
+
+        let (opcode, payload) = split(&Encoding::MessagePack.encode_voice(b"opus"));
+        assert_eq!(payload[0], VOICE_TAG);
+        assert!(matches!(
+            Encoding::MessagePack.decode::<Request>(opcode, payload),
+            Decoded::Voice(data) if data == b"opus"
+        ));
+
+        assert!(matches!(
+            Encoding::MessagePack.decode::<Request>(0x2, vec![UPLOAD_TAG, 7, 8]),
+            Decoded::Upload(chunk) if chunk == [7, 8]
+        ));
+        assert!(matches!(
+            Encoding::MessagePack.decode::<Request>(0x2, vec![0x7F]),
+            Decoded::Malformed(_)
+        ));
+        assert!(matches!(
+            Encoding::MessagePack.decode::<Request>(0x2, vec![]),
+            Decoded::Malformed(_)
+        ));
+    }
+
+    #[test]
+    fn invalid_messages() {
+        assert!(matches!(
+            Encoding::Json.decode::<Request>(0x1, b"{not json".to_vec()),
+            Decoded::Malformed(_)
+        ));
+        assert!(matches!(
+            Encoding::MessagePack.decode::<Request>(0x2, vec![MESSAGE_TAG, 0xC1]),
+            Decoded::Malformed(_)
+        ));
+        assert!(matches!(
+            Encoding::Json.decode::<Request>(0x1, vec![0xFF, 0xFE]),
+            Decoded::Invalid(_)
+        ));
+    }
+
+    #[test]
+    fn negotiates_first_supported_protocol() {
+        assert_eq!(
+            Encoding::negotiate("chat, axiom.msgpack, axiom.json"),
+            Some(Encoding::MessagePack)
+        );
+        assert_eq!(Encoding::negotiate("chat"), None);
+    }
+
+    #[test]
+    fn encoded_frames_are_cached_per_encoding() {
+        let request = typing();
+        let encoded = Encoded::new(&request);
+        let json = encoded.frame(Encoding::Json).unwrap();
+        let msgpack = encoded.frame(Encoding::MessagePack).unwrap();
+        assert!(std::sync::Arc::ptr_eq(
+            &json,
+            &encoded.frame(Encoding::Json).unwrap()
+        ));
+        assert_ne!(json, msgpack);
+    }
+}
@@ -1,6 +1,24 @@
+pub mod attachments;
 pub mod auth;
 pub mod client;
 pub mod database;
+pub mod deflate;
+pub mod encoding;
 pub mod logger;
+pub mod permissions;
+pub mod presence;
+pub mod session;
+pub mod tls;
 pub mod vfs;
 pub mod voice;
+
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+/// Runs blocking work like database queries without holding up the other tasks on this worker.
+/// Outside of a multi threaded runtime it just runs in place
+pub fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    match Handle::try_current().map(|h| h.runtime_flavor()) {
+        Ok(RuntimeFlavor::MultiThread) => tokio::task::block_in_place(f),
+        _ => f(),
+    }
+}
@@ -0,0 +1,285 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    types::data::{Overwrite, OverwriteTarget},
+    utils::database::EVERYONE_ROLE,
+};
+
+/// What a user is allowed to do, the union of the permissions of their roles
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Permissions(pub u64);
+
+impl Permissions {
+    pub const NONE: Self = Self(0);
+    pub const VIEW_CHANNELS: Self = Self(1 << 0);
+    pub const SEND_MESSAGES: Self = Self(1 << 1);
+    /// Delete messages of other users
+    pub const MANAGE_MESSAGES: Self = Self(1 << 2);
+    pub const MANAGE_CHANNELS: Self = Self(1 << 3);
+    pub const JOIN_VOICE: Self = Self(1 << 4);
+    pub const SPEAK: Self = Self(1 << 5);
+    pub const KICK_MEMBERS: Self = Self(1 << 6);
+    pub const BAN_MEMBERS: Self = Self(1 << 7);
+    /// Assign and unassign roles with no more permissions than one's own
+    pub const MANAGE_ROLES: Self = Self(1 << 8);
+    /// Every permission, including the ones added later
+    pub const ADMINISTRATOR: Self = Self(1 << 9);
+    /// Send messages in announcement channels
+    pub const POST_ANNOUNCEMENTS: Self = Self(1 << 10);
+    /// Be heard in stage channels, everyone else only listens
+    pub const SPEAK_ON_STAGE: Self = Self(1 << 11);
+
+    /// What the `everyone` role starts out with
+    pub const DEFAULT: Self =
+        Self(Self::VIEW_CHANNELS.0 | Self::SEND_MESSAGES.0 | Self::JOIN_VOICE.0 | Self::SPEAK.0);
+
+    pub const NAMES: [(&str, Self); 12] = [
+        ("view_channels", Self::VIEW_CHANNELS),
+        ("send_messages", Self::SEND_MESSAGES),
+        ("manage_messages", Self::MANAGE_MESSAGES),
+        ("manage_channels", Self::MANAGE_CHANNELS),
+        ("join_voice", Self::JOIN_VOICE),
+        ("speak", Self::SPEAK),
+        ("kick_members", Self::KICK_MEMBERS),
+        ("ban_members", Self::BAN_MEMBERS),
+        ("manage_roles", Self::MANAGE_ROLES),
+        ("administrator", Self::ADMINISTRATOR),
+        ("post_announcements", Self::POST_ANNOUNCEMENTS),
+        ("speak_on_stage", Self::SPEAK_ON_STAGE),
+    ];
+
+    /// Whether every permission in `other` is granted, administrators have them all
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & Self::ADMINISTRATOR.0 != 0 || self.0 & other.0 == other.0
+    }
+
+    /// Parse a comma separated list of permission names, `none` for no permissions
+    pub fn parse(s: &str) -> Option<Self> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty() && *name != "none")
+            .try_fold(Self::NONE, |all, name| {
+                let (_, p) = Self::NAMES.iter().find(|(n, _)| *n == name)?;
+                Some(all | *p)
+            })
+    }
+}
+
+/// The roles of a user and the server wide permissions they add up to
+#[derive(Debug, Clone)]
+pub struct Grants {
+    pub roles: Vec<i64>,
+    pub permissions: Permissions,
+}
+
+impl Grants {
+    /// Apply the overwrites of a channel, the `everyone` one first, then the ones of the user's
+    /// roles together and the user's own last.
+    ///
+    /// Nothing is granted in a channel the user can't see.
+    pub fn in_channel(&self, user_id: &str, overwrites: &[Overwrite]) -> Permissions {
+        if self.permissions.contains(Permissions::ADMINISTRATOR) {
+            return self.permissions;
+        }
+
+        let apply = |p: u64, allow: Permissions, deny: Permissions| (p & !deny.0) | allow.0;
+        let mut p = self.permissions.0;
+
+        let everyone = OverwriteTarget::Role(EVERYONE_ROLE);
+        if let Some(o) = overwrites.iter().find(|o| o.target == everyone) {
+            p = apply(p, o.allow, o.deny);
+        }
+
+        let (allow, deny) = overwrites
+            .iter()
+            .filter(|o| matches!(o.target, OverwriteTarget::Role(id) if self.roles.contains(&id)))
+            .fold(
+                (Permissions::NONE, Permissions::NONE),
+                |(allow, deny), o| (allow | o.allow, deny | o.deny),
+            );
+        p = apply(p, allow, deny);
+
+        if let Some(o) = overwrites
+            .iter()
+            .find(|o| matches!(&o.target, OverwriteTarget::User(id) if id == user_id))
+        {
+            p = apply(p, o.allow, o.deny);
+        }
+
+        match Permissions(p).contains(Permissions::VIEW_CHANNELS) {
+            true => Permissions(p),
+            false => Permissions::NONE,
+        }
+    }
+}
+
+impl std::ops::BitOr for Permissions {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl std::fmt::Display for Permissions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<&str> = Self::NAMES
+            .iter()
+            .filter(|(_, p)| self.0 & p.0 == p.0)
+            .map(|(n, _)| *n)
+            .collect();
+        if names.is_empty() {
+            return f.write_str("none");
+        }
+        f.write_str(&names.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODERATOR: i64 = 2;
+    const MUTED: i64 = 3;
+
+    fn overwrite(target: OverwriteTarget, allow: Permissions, deny: Permissions) -> Overwrite {
+        Overwrite {
+            target,
+            allow,
+            deny,
+        }
+    }
+
+    fn member(roles: &[i64]) -> Grants {
+        Grants {
+            roles: roles.to_vec(),
+            permissions: Permissions::DEFAULT,
+        }
+    }
+
+    #[test]
+    fn no_overwrites_keeps_server_permissions() {
+        assert_eq!(member(&[]).in_channel("alice", &[]), Permissions::DEFAULT);
+    }
+
+    #[test]
+    fn roles_override_everyone_and_the_user_overrides_roles() {
+        let overwrites = [
+            // Listed out of order, the order of resolution doesn't depend on it
+            overwrite(
+                OverwriteTarget::User("alice".to_string()),
+                Permissions::SEND_MESSAGES,
+                Permissions::NONE,
+            ),
+            overwrite(
+                OverwriteTarget::Role(MODERATOR),
+                Permissions::SEND_MESSAGES | Permissions::MANAGE_MESSAGES,
+                Permissions::NONE,
+            ),
+            overwrite(
+                OverwriteTarget::Role(EVERYONE_ROLE),
+                Permissions::NONE,
+                Permissions::SEND_MESSAGES,
+            ),
+        ];
+
+        let everyone = member(&[]).in_channel("bob", &overwrites);
+        assert!(!everyone.contains(Permissions::SEND_MESSAGES));
+
+        let moderator = member(&[MODERATOR]).in_channel("bob", &overwrites);
+        assert!(moderator.contains(Permissions::SEND_MESSAGES | Permissions::MANAGE_MESSAGES));
+
+        let alice = member(&[]).in_channel("alice", &overwrites);
+        assert!(alice.contains(Permissions::SEND_MESSAGES));
+        assert!(!alice.contains(Permissions::MANAGE_MESSAGES));
+    }
+
+    #[test]
+    fn role_overwrites_are_applied_together() {
+        // An allow from one role wins over a deny from another
+        let overwrites = [
+            overwrite(
+                OverwriteTarget::Role(MUTED),
+                Permissions::NONE,
+                Permissions::SPEAK,
+            ),
+            overwrite(
+                OverwriteTarget::Role(MODERATOR),
+                Permissions::SPEAK,
+                Permissions::NONE,
+            ),
+        ];
+        assert!(
+            member(&[MUTED, MODERATOR])
+                .in_channel("bob", &overwrites)
+                .contains(Permissions::SPEAK)
+        );
+        assert!(
+            !member(&[MUTED])
+                .in_channel("bob", &overwrites)
+                .contains(Permissions::SPEAK)
+        );
+    }
+
+    #[test]
+    fn user_deny_wins_over_role_allow() {
+        let overwrites = [
+            overwrite(
+                OverwriteTarget::Role(MODERATOR),
+                Permissions::MANAGE_MESSAGES,
+                Permissions::NONE,
+            ),
+            overwrite(
+                OverwriteTarget::User("bob".to_string()),
+                Permissions::NONE,
+                Permissions::MANAGE_MESSAGES,
+            ),
+        ];
+        assert!(
+            !member(&[MODERATOR])
+                .in_channel("bob", &overwrites)
+                .contains(Permissions::MANAGE_MESSAGES)
+        );
+    }
+
+    #[test]
+    fn hidden_channels_grant_nothing() {
+        let overwrites = [overwrite(
+            OverwriteTarget::Role(EVERYONE_ROLE),
+            Permissions::NONE,
+            Permissions::VIEW_CHANNELS,
+        )];
+        assert_eq!(
+            member(&[]).in_channel("bob", &overwrites),
+            Permissions::NONE
+        );
+    }
+
+    #[test]
+    fn administrators_ignore_overwrites() {
+        let admin = Grants {
+            roles: vec![],
+            permissions: Permissions::ADMINISTRATOR,
+        };
+        let overwrites = [overwrite(
+            OverwriteTarget::User("root".to_string()),
+            Permissions::NONE,
+            Permissions::VIEW_CHANNELS | Permissions::SEND_MESSAGES,
+        )];
+        assert!(
+            admin
+                .in_channel("root", &overwrites)
+                .contains(Permissions::SEND_MESSAGES)
+        );
+    }
+
+    #[test]
+    fn parses_and_displays_names() {
+        let p = Permissions::parse("speak, view_channels").unwrap();
+        assert_eq!(p, Permissions::VIEW_CHANNELS | Permissions::SPEAK);
+        assert_eq!(p.to_string(), "view_channels,speak");
+        assert_eq!(Permissions::parse("none"), Some(Permissions::NONE));
+        assert_eq!(Permissions::parse("fly"), None);
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+
+use crate::types::data::{Status, UserPresence};
+
+/// Longest custom status text
+pub const MAX_TEXT_LEN: usize = 128;
+
+pub struct Presence {
+    // user_id -> (open connections, chosen presence)
+    users: HashMap<String, (usize, UserPresence)>,
+}
+
+impl Presence {
+    pub fn new() -> Self {
+        Self {
+            users: HashMap::new(),
+        }
+    }
+
+    /// Count a new connection of a user
+    /// Returns the presence to broadcast if the user just came online
+    pub fn connect(&mut self, user_id: &str) -> Option<UserPresence> {
+        let (connections, presence) = self
+            .users
+            .entry(user_id.to_string())
+            .or_insert_with(|| (0, UserPresence::default()));
+        *connections += 1;
+
+        (*connections == 1).then(|| presence.clone())
+    }
+
+    /// Count a closed connection of a user
+    /// Returns the presence to broadcast if it was their last one
+    pub fn disconnect(&mut self, user_id: &str) -> Option<UserPresence> {
+        let (connections, _) = self.users.get_mut(user_id)?;
+        *connections -= 1;
+        if *connections > 0 {
+            return None;
+        }
+
+        self.users.remove(user_id);
+        Some(UserPresence {
+            status: Status::Offline,
+            text: None,
+        })
+    }
+
+    /// Change the presence a user chose, shared by all their connections
+    /// Returns the presence to broadcast if it changed
+    pub fn set(&mut self, user_id: &str, presence: UserPresence) -> Option<UserPresence> {
+        let (_, current) = self.users.get_mut(user_id)?;
+        if *current == presence {
+            return None;
+        }
+
+        *current = presence.clone();
+        Some(presence)
+    }
+
+    /// Presence of every user that doesn't appear offline
+    pub fn snapshot(&self) -> HashMap<String, UserPresence> {
+        self.users
+            .iter()
+            .filter(|(_, (_, p))| p.status != Status::Offline)
+            .map(|(user_id, (_, p))| (user_id.clone(), p.clone()))
+            .collect()
+    }
+}
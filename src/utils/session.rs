@@ -0,0 +1,288 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD as Base64};
+
+use crate::{
+    requests::error::RequestError, types::message::ServerMessage, utils::client::ConnectionConfig,
+};
+
+/// Every event a connection was sent, so a client that lost its connection can pick up where
+/// it left off.
+///
+/// Events are numbered by a single server wide sequence, a connection sees every event with a
+/// sequence number above the `seq` it was authenticated with.
+pub struct Sessions {
+    state: Mutex<State>,
+    buffer: usize,
+    timeout: Duration,
+}
+
+struct State {
+    seq: u64,
+    sessions: HashMap<String, Session>,
+}
+
+struct Session {
+    user_id: String,
+    /// The sequence number the session was started at
+    started: u64,
+    events: VecDeque<(u64, Arc<ServerMessage>)>,
+    /// The newest event that isn't in the buffer
+    lost: u64,
+    /// When the connection ended, `None` while it's still connected
+    detached: Option<Instant>,
+}
+
+impl Sessions {
+    pub fn new(config: &ConnectionConfig) -> Self {
+        Self {
+            state: Mutex::new(State {
+                seq: 0,
+                sessions: HashMap::new(),
+            }),
+            buffer: config.resume_buffer,
+            timeout: Duration::from_secs(config.resume_timeout_secs),
+        }
+    }
+
+    /// Start a session for a newly authenticated connection.
+    ///
+    /// `attach` gets the session id and the current sequence number and has to make the
+    /// connection live, it runs under the lock so no event can be numbered in between.
+    pub fn start<T>(&self, user_id: &str, attach: impl FnOnce(&str, u64) -> T) -> T {
+        let mut state = self.state.lock().unwrap();
+        let timeout = self.timeout;
+        state
+            .sessions
+            .retain(|_, s| s.detached.is_none_or(|d| d.elapsed() < timeout));
+
+        let session_id = Base64.encode(rand::random::<[u8; 16]>());
+        let started = state.seq;
+        state.sessions.insert(
+            session_id.clone(),
+            Session {
+                user_id: user_id.to_string(),
+                started,
+                events: VecDeque::new(),
+                // Nothing before the start was recorded
+                lost: started,
+                detached: None,
+            },
+        );
+        attach(&session_id, started)
+    }
+
+    /// Number an event and remember it for the sessions of the users `to` selects.
+    ///
+    /// `to` is asked once per user and outside the lock, `collect` gathers the live recipients
+    /// under the same lock as [`Self::start`].
+    pub fn record<T>(
+        &self,
+        message: &Arc<ServerMessage>,
+        to: impl Fn(&str) -> bool,
+        collect: impl FnOnce() -> T,
+    ) -> (u64, T) {
+        let users: HashSet<String> = self
+            .state
+            .lock()
+            .unwrap()
+            .sessions
+            .values()
+            .map(|s| s.user_id.clone())
+            .collect();
+        let selected: HashSet<&String> = users.iter().filter(|u| to(u)).collect();
+
+        let mut state = self.state.lock().unwrap();
+        state.seq += 1;
+        let seq = state.seq;
+
+        for session in state.sessions.values_mut() {
+            let selected = match users.contains(&session.user_id) {
+                true => selected.contains(&session.user_id),
+                // Started since the users were checked
+                false => to(&session.user_id),
+            };
+            if !selected {
+                continue;
+            }
+            if self.buffer == 0 {
+                session.lost = seq;
+                continue;
+            }
+            if session.events.len() == self.buffer
+                && let Some((lost, _)) = session.events.pop_front()
+            {
+                session.lost = lost;
+            }
+            session.events.push_back((seq, message.clone()));
+        }
+
+        (seq, collect())
+    }
+
+    /// The connection of a session ended, it can be resumed until it times out
+    pub fn detach(&self, session_id: &str) {
+        if let Some(session) = self.state.lock().unwrap().sessions.get_mut(session_id) {
+            session.detached = Some(Instant::now());
+        }
+    }
+
+    /// Take over an earlier session, returns the events it missed before `current` started
+    pub fn resume(
+        &self,
+        current: &str,
+        session_id: &str,
+        user_id: &str,
+        last_seq: u64,
+    ) -> crate::Result<Vec<(u64, Arc<ServerMessage>)>> {
+        let mut state = self.state.lock().unwrap();
+        let started = state
+            .sessions
+            .get(current)
+            .map(|s| s.started)
+            .unwrap_or(state.seq);
+
+        let resumable = state.sessions.get(session_id).is_some_and(|s| {
+            session_id != current
+                && s.user_id == user_id
+                && s.detached.is_none_or(|d| d.elapsed() < self.timeout)
+        });
+        if !resumable {
+            return Err(RequestError::NotFound("Session can't be resumed".to_string()).into());
+        }
+
+        let session = &state.sessions[session_id];
+        if last_seq < session.lost || last_seq > started {
+            return Err(RequestError::InvalidRequest(
+                "Missed events are no longer available".to_string(),
+            )
+            .into());
+        }
+
+        // Events after `started` were sent to the new connection already
+        let missed = session
+            .events
+            .iter()
+            .filter(|(seq, _)| *seq > last_seq && *seq <= started)
+            .cloned()
+            .collect();
+        state.sessions.remove(session_id);
+        Ok(missed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sessions(resume_buffer: usize, resume_timeout_secs: u64) -> Sessions {
+        Sessions::new(&ConnectionConfig {
+            resume_buffer,
+            resume_timeout_secs,
+            ..Default::default()
+        })
+    }
+
+    fn start(sessions: &Sessions, user_id: &str) -> String {
+        sessions.start(user_id, |session_id, _| session_id.to_string())
+    }
+
+    fn record(sessions: &Sessions, to: &str) -> u64 {
+        let message = Arc::new(ServerMessage::Resumed { replayed: 0 });
+        sessions.record(&message, |user_id| user_id == to, || ()).0
+    }
+
+    fn is_not_found(res: crate::Result<Vec<(u64, Arc<ServerMessage>)>>) -> bool {
+        matches!(
+            res.unwrap_err().downcast_ref::<RequestError>(),
+            Some(RequestError::NotFound(_))
+        )
+    }
+
+    fn is_unavailable(res: crate::Result<Vec<(u64, Arc<ServerMessage>)>>) -> bool {
+        matches!(
+            res.unwrap_err().downcast_ref::<RequestError>(),
+            Some(RequestError::InvalidRequest(_))
+        )
+    }
+
+    #[test]
+    fn resume_replays_what_the_user_missed() {
+        let sessions = sessions(8, 60);
+        let old = start(&sessions, "alice");
+        record(&sessions, "alice");
+        record(&sessions, "bob");
+        let missed = record(&sessions, "alice");
+        sessions.detach(&old);
+
+        let new = start(&sessions, "alice");
+        record(&sessions, "alice");
+
+        // Only alice's events, and only up to where the new connection took over
+        let replayed = sessions.resume(&new, &old, "alice", 1).unwrap();
+        let seqs: Vec<u64> = replayed.iter().map(|(seq, _)| *seq).collect();
+        assert_eq!(seqs, [missed]);
+
+        // A session is only resumed once
+        assert!(is_not_found(sessions.resume(&new, &old, "alice", 1)));
+    }
+
+    #[test]
+    fn resume_rejects_last_seq_out_of_range() {
+        let sessions = sessions(8, 60);
+        let old = start(&sessions, "alice");
+        record(&sessions, "alice");
+        sessions.detach(&old);
+        let new = start(&sessions, "alice");
+
+        // Past the start of the new connection
+        assert!(is_unavailable(sessions.resume(&new, &old, "alice", 2)));
+        assert!(is_unavailable(sessions.resume(
+            &new,
+            &old,
+            "alice",
+            u64::MAX
+        )));
+        assert!(sessions.resume(&new, &old, "alice", 1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn resume_rejects_events_that_fell_out_of_the_buffer() {
+        let sessions = sessions(2, 60);
+        let old = start(&sessions, "alice");
+        for _ in 0..3 {
+            record(&sessions, "alice");
+        }
+        sessions.detach(&old);
+        let new = start(&sessions, "alice");
+
+        assert!(is_unavailable(sessions.resume(&new, &old, "alice", 0)));
+        assert_eq!(sessions.resume(&new, &old, "alice", 1).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn resume_rejects_timed_out_sessions() {
+        let sessions = sessions(8, 0);
+        let old = start(&sessions, "alice");
+        sessions.detach(&old);
+        let new = start(&sessions, "alice");
+
+        assert!(is_not_found(sessions.resume(&new, &old, "alice", 0)));
+    }
+
+    #[test]
+    fn resume_rejects_sessions_of_other_users() {
+        let sessions = sessions(8, 60);
+        let old = start(&sessions, "alice");
+        sessions.detach(&old);
+        let new = start(&sessions, "bob");
+
+        assert!(is_not_found(sessions.resume(&new, &old, "bob", 0)));
+        // Nor can a connection resume itself
+        assert!(is_not_found(sessions.resume(&new, &new, "bob", 0)));
+    }
+}
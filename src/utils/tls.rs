@@ -0,0 +1,122 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        self,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    },
+};
+
+use crate::logger;
+
+logger!(LOGGER "TLS");
+
+/// Set through `tls` in the server config to serve `wss://` directly
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// PEM certificate chain, relative to the server root
+    pub cert: PathBuf,
+    /// PEM private key, relative to the server root
+    pub key: PathBuf,
+    /// How often the files are checked for changes, 0 turns automatic reloading off
+    #[serde(default = "default_reload_secs")]
+    pub reload_secs: u64,
+}
+
+fn default_reload_secs() -> u64 {
+    60
+}
+
+/// Terminates TLS for incoming connections, the certificate can be swapped while running
+pub struct Tls {
+    cert: PathBuf,
+    key: PathBuf,
+    reload_interval: Duration,
+    acceptor: RwLock<TlsAcceptor>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl Tls {
+    pub fn load(root: &Path, config: &TlsConfig) -> crate::Result<Self> {
+        let cert = root.join(&config.cert);
+        let key = root.join(&config.key);
+        let acceptor = Self::acceptor_from(&cert, &key)?;
+
+        Ok(Self {
+            modified: Mutex::new(modified(&cert, &key)),
+            cert,
+            key,
+            reload_interval: Duration::from_secs(config.reload_secs),
+            acceptor: RwLock::new(acceptor),
+        })
+    }
+
+    fn acceptor_from(cert: &Path, key: &Path) -> crate::Result<TlsAcceptor> {
+        let certs = CertificateDer::pem_file_iter(cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("Couldn't read certificate {cert:?}"))?;
+        let key = PrivateKeyDer::from_pem_file(key)
+            .with_context(|| format!("Couldn't read private key {key:?}"))?;
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    /// The acceptor for the certificate currently loaded
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+
+    /// Read the certificate and key again, connections that are already open are unaffected
+    pub fn reload(&self) -> crate::Result<()> {
+        let acceptor = Self::acceptor_from(&self.cert, &self.key)?;
+        *self.acceptor.write().unwrap() = acceptor;
+        *self.modified.lock().unwrap() = modified(&self.cert, &self.key);
+        Ok(())
+    }
+
+    /// Reload whenever the certificate or key changes on disk, e.g. after a renewal
+    pub fn spawn_reload_task(self: &Arc<Self>) {
+        if self.reload_interval.is_zero() {
+            return;
+        }
+
+        let tls = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(tls.reload_interval);
+            loop {
+                interval.tick().await;
+                let changed = modified(&tls.cert, &tls.key) != *tls.modified.lock().unwrap();
+                if !changed {
+                    continue;
+                }
+
+                // A renewal may still be writing the files, the next tick retries
+                match tls.reload() {
+                    Ok(()) => LOGGER.info("Reloaded certificate"),
+                    Err(e) => LOGGER.warn(e.context("Couldn't reload certificate")),
+                }
+            }
+        });
+    }
+}
+
+/// The latest modification time of the certificate and key
+fn modified(cert: &Path, key: &Path) -> Option<SystemTime> {
+    let cert = fs::metadata(cert).and_then(|m| m.modified()).ok()?;
+    let key = fs::metadata(key).and_then(|m| m.modified()).ok()?;
+    Some(cert.max(key))
+}
@@ -18,7 +18,7 @@ impl Voice {
 
         self.connections
             .entry(channel_id)
-            .or_insert_with(HashMap::new)
+            .or_default()
             .insert(user_id, voice_id);
 
         voice_id